@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::error::SynthError;
 use crate::track::{MelodyTrack, LoopPoint};
-use crate::effects::{ReverbParams, DelayParams, DistortionParams, FilterParams, FilterType};
+use crate::instrument::{Instrument, Note, SampleData, SequenceElement};
+use crate::effects::{EffectsChain, ReverbParams, DelayParams, DistortionParams, FilterParams, FilterType, ChorusParams, CompressorParams};
 
 #[derive(Debug, Clone, Default)]
 pub struct TrackOverrides {
@@ -13,6 +14,53 @@ pub struct TrackOverrides {
     pub delay: Option<DelayParams>,
     pub distortion: Option<DistortionParams>,
     pub filter: Option<FilterParams>,
+    pub chorus: Option<ChorusParams>,
+}
+
+impl TrackOverrides {
+    // The track's own chain with any overridden effects swapped in
+    pub fn effects_over(&self, base: &EffectsChain) -> EffectsChain {
+        let mut chain = base.clone();
+        if let Some(r) = &self.reverb     { chain.reverb     = Some(r.clone()); }
+        if let Some(d) = &self.delay      { chain.delay      = Some(d.clone()); }
+        if let Some(x) = &self.distortion { chain.distortion = Some(x.clone()); }
+        if let Some(f) = &self.filter     { chain.filter     = Some(f.clone()); }
+        if let Some(c) = &self.chorus     { chain.chorus     = Some(c.clone()); }
+        chain
+    }
+}
+
+// Shape of a 0..1 ramp (fades, automation)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CurveShape {
+    #[default]
+    Linear,
+    Cosine, // Eases in and out
+    Exponential, // Slow start, fast finish
+}
+
+impl CurveShape {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            CurveShape::Linear => t,
+            CurveShape::Cosine => 0.5 - 0.5 * (t * std::f32::consts::PI).cos(),
+            CurveShape::Exponential => {
+                const K: f32 = 4.0;
+                ((K * t).exp() - 1.0) / (K.exp() - 1.0)
+            }
+        }
+    }
+}
+
+// Note order for Arrangement::arpeggio
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ArpMode {
+    #[default]
+    Up,
+    Down,
+    UpDown, // Bounces without repeating the top and bottom notes
+    Random, // A fresh random order each time the arpeggio is generated
 }
 
 #[derive(Debug, Clone)]
@@ -24,10 +72,208 @@ pub struct Arrangement {
     pub master_tempo: Option<f32>,
     pub fade_in: Option<f32>,
     pub fade_out: Option<f32>,
+    pub fade_curve: CurveShape,
+    pub seed: Option<u64>, // Render seed (noise, ...), overrides the engine's
+    pub compressor: Option<CompressorParams>, // Master bus, after the track mix and ahead of the fades
+    pub lyrics: Vec<(f32, String)>, // Timed text lines (seconds), in time order
 }
 
 impl Arrangement {
+    // Plays `other` after this one with `gap` seconds of silence in between. The result fades in like
+    // self and out like other, and keeps self's loop region (or other's, shifted, if self has none).
+    pub fn concat(&self, other: &Arrangement, gap: f32) -> Arrangement {
+        let offset = self.total_length + gap.max(0.0);
+        let mut tracks = self.tracks.clone();
+        tracks.extend(other.tracks.iter()
+            .map(|(track, start, overrides)| (track.clone(), start + offset, overrides.clone())));
+
+        let loop_point = self.loop_point.clone().or_else(|| other.loop_point.as_ref()
+            .map(|lp| LoopPoint { start: lp.start + offset, end: lp.end + offset }));
+
+        let mut arrangement = Arrangement {
+            name: format!("{} + {}", self.name, other.name),
+            tracks,
+            total_length: offset + other.total_length,
+            loop_point,
+            master_tempo: self.master_tempo,
+            fade_in: self.fade_in,
+            fade_out: other.fade_out,
+            fade_curve: self.fade_curve,
+            seed: self.seed,
+            compressor: self.compressor.clone(),
+            lyrics: self.lyrics.iter().cloned()
+                .chain(other.lyrics.iter().map(|(t, line)| (t + offset, line.clone())))
+                .collect(),
+        };
+        arrangement.uniquify_track_names();
+        arrangement
+    }
+
+    // Seconds until the last release has faded, each track timed at the master (else overridden) tempo.
+    // Unlike total_length this includes release tails past the last note.
+    pub fn duration_seconds(&self) -> f32 {
+        self.tracks.iter()
+            .map(|(track, start, overrides)| self.track_end(track, *start, overrides))
+            .fold(0.0, f32::max)
+    }
+
+    // Same, plus each track's reverb/delay decay estimate (EffectsChain::tail_seconds): how long a
+    // render has to run for everything to ring out
+    pub fn ring_out_seconds(&self) -> f32 {
+        self.tracks.iter()
+            .map(|(track, start, overrides)| {
+                self.track_end(track, *start, overrides) + overrides.effects_over(&track.instrument.effects).tail_seconds()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    fn track_end(&self, track: &MelodyTrack, start: f32, overrides: &TrackOverrides) -> f32 {
+        let seconds = match self.master_tempo.or(overrides.tempo) {
+            Some(tempo) if tempo != track.tempo => {
+                let mut retimed = track.clone();
+                retimed.tempo = tempo;
+                retimed.duration_seconds()
+            }
+            _ => track.duration_seconds(),
+        };
+        start + seconds + track.release_tail()
+    }
+
+    // Dynamic parameters (volume, enable, solo, ...) are keyed by track name, so a name used
+    // more than once gets "#2", "#3", ... appended to later uses to keep them independent
+    pub fn uniquify_track_names(&mut self) {
+        let mut seen: HashSet<String> = HashSet::new();
+        for (track, _, _) in &mut self.tracks {
+            if !seen.contains(&track.name) {
+                seen.insert(track.name.clone());
+                continue;
+            }
+            let mut n = 2;
+            while seen.contains(&format!("{}#{}", track.name, n)) {
+                n += 1;
+            }
+            track.name = format!("{}#{}", track.name, n);
+            seen.insert(track.name.clone());
+        }
+    }
+
+    // A looping single track arpeggio over `pitches`, `rate_hz` notes per second. `seed` picks the
+    // Random order and becomes the arrangement's render seed, so the same seed plays the same arp.
+    pub fn arpeggio(pitches: &[f32], mode: ArpMode, rate_hz: f32, instrument: Instrument, seed: u64) -> Arrangement {
+        let mut order: Vec<f32> = pitches.to_vec();
+        match mode {
+            ArpMode::Up => {}
+            ArpMode::Down => order.reverse(),
+            ArpMode::UpDown => {
+                let down: Vec<f32> = order.iter().rev().skip(1).take(order.len().saturating_sub(2)).copied().collect();
+                order.extend(down);
+            }
+            ArpMode::Random => {
+                // Several passes so the loop doesn't audibly repeat every few notes
+                let mut rng = fastrand::Rng::with_seed(seed);
+                order = (0..pitches.len() * 4).map(|_| pitches[rng.usize(..pitches.len())]).collect();
+            }
+        }
+
+        let step = 1.0 / rate_hz.max(0.01);
+        let sequence: Vec<SequenceElement> = order.iter().map(|&pitch| SequenceElement::Note(Note {
+            pitch,
+            duration: step,
+            in_seconds: true,
+            velocity: 1.0,
+            pan: None,
+            slide_to: None,
+            release: None,
+            shift: 0.0,
+            wave: None,
+            overlap: false,
+        })).collect();
+
+        let mut track = MelodyTrack {
+            name: "arp".to_string(),
+            instrument,
+            sequence,
+            tempo: 120.0,
+            length: 0.0,
+            loop_point: None,
+            time_signature: (4, 4),
+            swing: 0.0,
+            gate: None,
+        };
+        track.length = track.total_beats();
+        let cycle = order.len() as f32 * step;
+
+        Arrangement {
+            name: "arp".to_string(),
+            tracks: vec![(track, 0.0, TrackOverrides::default())],
+            total_length: cycle,
+            loop_point: (cycle > 0.0).then_some(LoopPoint { start: 0.0, end: cycle }),
+            master_tempo: None,
+            fade_in: None,
+            fade_out: None,
+            fade_curve: CurveShape::default(),
+            seed: Some(seed),
+            compressor: None,
+            lyrics: Vec::new(),
+        }
+    }
+
+    // Every element of every track with its absolute start time in seconds, ordered by time
+    pub fn iter_timed(&self) -> impl Iterator<Item = (f32, &MelodyTrack, &SequenceElement)> {
+        let mut timeline: Vec<_> = self.tracks.iter()
+            .flat_map(|(track, start_time, _)| {
+                track.iter_timed().map(move |(t, element)| (start_time + t, track, element))
+            })
+            .collect();
+        timeline.sort_by(|a, b| a.0.total_cmp(&b.0));
+        timeline.into_iter()
+    }
+
+    // The lyric line showing at `time`: the last one that has started
+    pub fn lyric_at(&self, time: f32) -> Option<&str> {
+        self.lyrics.iter()
+            .filter(|(start, _)| *start <= time)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, line)| line.as_str())
+    }
+
+    // Absolute time of every beat from 0 to total_length, e.g. for video editor markers. Follows the first
+    // track's tempo, time signature and tempo changes; a change mid-beat takes effect from the next beat.
+    pub fn beat_grid(&self) -> Vec<f32> {
+        let Some((track, start_time, _)) = self.tracks.first() else { return Vec::new() };
+        let mut changes = track.iter_timed()
+            .filter_map(|(t, element)| match element {
+                SequenceElement::TempoChange(tempo) => Some((start_time + t, track.beat_duration_at(*tempo))),
+                _ => None,
+            })
+            .peekable();
+
+        let mut grid = Vec::new();
+        let mut beat_dur = track.beat_duration();
+        let mut time = 0.0f64; // f64 so thousands of beats don't drift
+        while time < self.total_length as f64 {
+            while let Some((_, dur)) = changes.next_if(|&(at, _)| at as f64 <= time) {
+                beat_dur = dur;
+            }
+            if beat_dur <= 0.0 { break; }
+            grid.push(time as f32);
+            time += beat_dur as f64;
+        }
+        grid
+    }
+
     pub fn from_bmi(content: &str, mel_cache: &HashMap<String, MelodyTrack>) -> Result<Self, SynthError> {
+        Self::from_bmi_with_samples(content, mel_cache, &HashMap::new())
+    }
+
+    // Same as from_bmi, with samples available to inline `melody:` blocks
+    pub fn from_bmi_with_samples(
+        content: &str,
+        mel_cache: &HashMap<String, MelodyTrack>,
+        sample_cache: &HashMap<String, SampleData>,
+    ) -> Result<Self, SynthError> {
+        let (content, inline_melodies) = Self::extract_inline_melodies(content, sample_cache)?;
+
         let mut arrangement = Arrangement {
             name: "song".to_string(),
             tracks: Vec::new(),
@@ -36,6 +282,10 @@ impl Arrangement {
             master_tempo: None,
             fade_in: None,
             fade_out: None,
+            fade_curve: CurveShape::Linear,
+            seed: None,
+            compressor: None,
+            lyrics: Vec::new(),
         };
 
         for line in content.lines() {
@@ -52,6 +302,34 @@ impl Arrangement {
                 arrangement.fade_in = value.trim().parse().ok();
             } else if let Some(value) = line.strip_prefix("fade_out:") {
                 arrangement.fade_out = value.trim().parse().ok();
+            } else if let Some(value) = line.strip_prefix("fade_curve:") {
+                arrangement.fade_curve = match value.trim().to_lowercase().as_str() {
+                    "linear" | "lin" => CurveShape::Linear,
+                    "cosine" | "cos" | "smooth" => CurveShape::Cosine,
+                    "exponential" | "exp" => CurveShape::Exponential,
+                    _ => return Err(SynthError::ParseError("Unknown fade curve".to_string())),
+                };
+            } else if let Some(value) = line.strip_prefix("lyric:") {
+                let (time, text) = value.split_once(',')
+                    .ok_or_else(|| SynthError::ParseError("Expected 'lyric: TIME, TEXT'".to_string()))?;
+                let time: f32 = time.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid lyric time".to_string()))?;
+                arrangement.lyrics.push((time, text.trim().to_string()));
+            } else if let Some(value) = line.strip_prefix("seed:") {
+                arrangement.seed = Some(value.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid seed, expected a whole number".to_string()))?);
+            } else if let Some(value) = line.strip_prefix("compressor:") {
+                let parts: Vec<&str> = value.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 5 {
+                    arrangement.compressor = Some(CompressorParams {
+                        threshold_db: parts[0].parse().unwrap_or(-18.0),
+                        ratio: parts[1].parse().unwrap_or(4.0),
+                        attack_ms: parts[2].parse().unwrap_or(10.0),
+                        release_ms: parts[3].parse().unwrap_or(100.0),
+                        makeup_db: parts[4].parse().unwrap_or(0.0),
+                        knee: parts.get(5).and_then(|k| k.parse().ok()).unwrap_or(0.0),
+                    });
+                }
             } else if let Some(value) = line.strip_prefix("loop:") {
                 let parts: Vec<&str> = value.split(',').map(|s| s.trim()).collect();
                 if parts.len() >= 2 {
@@ -64,10 +342,16 @@ impl Arrangement {
                 let parts: Vec<&str> = value.split(',').map(|s| s.trim()).collect();
                 if parts.len() >= 2 {
                     let mel_file = parts[0];
-                    let start_time: f32 = parts[1].parse()
+                    // Seconds, or "bar N" (zero-based) converted once the track's tempo is known
+                    let (start_value, in_bars) = match parts[1].strip_prefix("bar") {
+                        Some(bar) => (bar.trim(), true),
+                        None => (parts[1], false),
+                    };
+                    let start_value: f32 = start_value.parse()
                         .map_err(|_| SynthError::ParseError("Invalid start time".to_string()))?;
                     
                     let mut overrides = TrackOverrides::default();
+                    let mut track_name = None;
                     
                     for override_str in parts.iter().skip(2) {
                         if let Some((key, val)) = override_str.split_once('=') {
@@ -87,6 +371,9 @@ impl Arrangement {
                                 "pan" => { 
                                     overrides.pan = val.parse().ok();
                                 }
+                                "name" => {
+                                    track_name = Some(val.to_string());
+                                }
                                 "filter" => {
                                     let vals: Vec<&str> = val.split(':').collect();
                                     if vals.len() >= 3 {
@@ -94,12 +381,16 @@ impl Arrangement {
                                             "lowpass" | "lp" => FilterType::LowPass,
                                             "highpass" | "hp" => FilterType::HighPass,
                                             "bandpass" | "bp" => FilterType::BandPass,
+                                            "notch" => FilterType::Notch,
+                                            "peak" => FilterType::Peak,
                                             _ => FilterType::LowPass,
                                         };
                                         overrides.filter = Some(FilterParams {
                                             filter_type,
                                             cutoff: vals[1].parse().unwrap_or(1000.0),
                                             resonance: vals[2].parse().unwrap_or(0.7),
+                                            gain_db: vals.get(3).and_then(|g| g.parse().ok()),
+                                            envelope: None,
                                         });
                                     }
                                 }
@@ -111,6 +402,7 @@ impl Arrangement {
                                             damping: vals[1].parse().unwrap_or(0.5),
                                             wet: vals[2].parse().unwrap_or(0.3),
                                             width: vals[3].parse().unwrap_or(1.0),
+                                            custom_tunings: None,
                                         });
                                     }
                                 }
@@ -121,6 +413,19 @@ impl Arrangement {
                                             time: vals[0].parse().unwrap_or(0.25),
                                             feedback: vals[1].parse().unwrap_or(0.4),
                                             wet: vals[2].parse().unwrap_or(0.3),
+                                            sync: None,
+                                            ping_pong: false,
+                                        });
+                                    }
+                                }
+                                "chorus" => {
+                                    let vals: Vec<&str> = val.split(':').collect();
+                                    if vals.len() >= 4 {
+                                        overrides.chorus = Some(ChorusParams {
+                                            rate_hz: vals[0].parse().unwrap_or(0.8),
+                                            depth_ms: vals[1].parse().unwrap_or(3.0),
+                                            mix: vals[2].parse().unwrap_or(0.4),
+                                            voices: vals[3].parse().unwrap_or(3),
                                         });
                                     }
                                 }
@@ -139,17 +444,24 @@ impl Arrangement {
                         }
                     }
                     
-                    if let Some(track) = mel_cache.get(mel_file) {
+                    if let Some(track) = inline_melodies.get(mel_file).or_else(|| mel_cache.get(mel_file)) {
                         let mut modified_track = track.clone();
+                        if let Some(name) = track_name {
+                            modified_track.name = name;
+                        }
                         
-                        if overrides.tempo.is_some() {
-                            modified_track.tempo = overrides.tempo.unwrap();
+                        if let Some(tempo) = overrides.tempo {
+                            modified_track.tempo = tempo;
                         }
                         
                         if let Some(master_tempo) = arrangement.master_tempo {
                             modified_track.tempo = master_tempo;
                         }
                         
+                        modified_track.sync_effects_to_tempo();
+
+                        let start_time = if in_bars { start_value * modified_track.bar_duration() } else { start_value };
+
                         arrangement.tracks.push((modified_track, start_time, overrides));
                         let end_time = start_time + track.length;
                         if end_time > arrangement.total_length {
@@ -161,6 +473,9 @@ impl Arrangement {
                 }
             }
         }
+        arrangement.uniquify_track_names();
+        arrangement.lyrics.sort_by(|a, b| a.0.total_cmp(&b.0));
+
         // Return error only if the arrangement has no valid tracks
         if arrangement.tracks.is_empty() {
             return Err(SynthError::InvalidInstrument(
@@ -170,4 +485,156 @@ impl Arrangement {
 
         Ok(arrangement)
     }
-}
\ No newline at end of file
+
+    // Pulls `melody: name { ... }` blocks out of a .bmi, returning the remaining lines and the parsed melodies
+    fn extract_inline_melodies(
+        content: &str,
+        sample_cache: &HashMap<String, SampleData>,
+    ) -> Result<(String, HashMap<String, MelodyTrack>), SynthError> {
+        let mut rest = String::new();
+        let mut melodies = HashMap::new();
+        let mut lines = content.lines();
+
+        while let Some(line) = lines.next() {
+            let Some(header) = line.trim().strip_prefix("melody:") else {
+                rest.push_str(line);
+                rest.push('\n');
+                continue;
+            };
+            let name = header.trim().strip_suffix('{')
+                .map(|n| n.trim().to_string())
+                .filter(|n| !n.is_empty())
+                .ok_or_else(|| SynthError::ParseError("Expected 'melody: name {'".to_string()))?;
+
+            let mut body = String::new();
+            let mut closed = false;
+            for line in lines.by_ref() {
+                if line.trim() == "}" { closed = true; break; }
+                body.push_str(line);
+                body.push('\n');
+            }
+            if !closed {
+                return Err(SynthError::ParseError(format!("Unterminated melody block '{}'", name)));
+            }
+
+            let mut track = MelodyTrack::from_mel(&body, sample_cache)?;
+            if !body.lines().any(|l| l.trim().starts_with("name:")) {
+                track.name = name.clone();
+            }
+            melodies.insert(name, track);
+        }
+
+        Ok((rest, melodies))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::InstrumentSource;
+    use crate::waveform::WaveformType;
+
+    #[test]
+    fn cosine_fade_eases_in_and_out() {
+        let cosine = CurveShape::Cosine;
+        assert!(cosine.apply(0.25) < CurveShape::Linear.apply(0.25));
+        assert!(cosine.apply(0.75) > CurveShape::Linear.apply(0.75));
+        assert!((cosine.apply(0.5) - 0.5).abs() < 1e-6);
+        assert_eq!((cosine.apply(0.0), cosine.apply(1.0)), (0.0, 1.0));
+    }
+
+    #[test]
+    fn track_lines_can_use_an_inline_melody() {
+        let bmi = "melody: stab {\n    waveform: square\n    chord: C4+E4+G4, 0.5, 0.6\n    rest: 1.5\n}\ntrack: stab, 4.0\n";
+        let arrangement = Arrangement::from_bmi(bmi, &HashMap::new()).unwrap();
+        assert_eq!(arrangement.tracks.len(), 1);
+        let (track, start, _) = &arrangement.tracks[0];
+        assert_eq!((track.name.as_str(), *start), ("stab", 4.0));
+        assert!(matches!(track.instrument.source, InstrumentSource::Synthesized(WaveformType::Square)));
+        assert_eq!(track.sequence.len(), 2);
+    }
+
+    #[test]
+    fn arrangement_timeline_offsets_and_merges_tracks() {
+        let mels = HashMap::from([
+            ("a.mel".to_string(), MelodyTrack::from_mel("tempo: 120\nnote: C4, 1, 1.0\nnote: D4, 1, 1.0", &HashMap::new()).unwrap()),
+            ("b.mel".to_string(), MelodyTrack::from_mel("tempo: 60\nnote: E4, 1, 1.0", &HashMap::new()).unwrap()),
+        ]);
+        let arrangement = Arrangement::from_bmi("track: a.mel, 0\ntrack: b.mel, 0.25\n", &mels).unwrap();
+        let timeline: Vec<(f32, f32)> = arrangement.iter_timed()
+            .map(|(t, _, e)| match e {
+                SequenceElement::Note(n) => (t, n.pitch),
+                _ => panic!("only notes here"),
+            })
+            .collect();
+        let pitch = |name: &str| crate::utils::parse_note(name).unwrap();
+        assert_eq!(timeline, vec![(0.0, pitch("C4")), (0.25, pitch("E4")), (0.5, pitch("D4"))]);
+    }
+
+    #[test]
+    fn bar_starts_follow_the_tempo_and_meter() {
+        let mels = HashMap::from([
+            ("a.mel".to_string(), MelodyTrack::from_mel("tempo: 120\nnote: C4, 1, 1.0", &HashMap::new()).unwrap()),
+            ("b.mel".to_string(), MelodyTrack::from_mel("tempo: 120\ntime_sig: 3/4\nnote: C4, 1, 1.0", &HashMap::new()).unwrap()),
+        ]);
+        let arrangement = Arrangement::from_bmi("track: a.mel, bar 2\ntrack: b.mel, 1.5\ntrack: b.mel, bar 3\n", &mels).unwrap();
+        let starts: Vec<f32> = arrangement.tracks.iter().map(|(_, start, _)| *start).collect();
+        // Bars count from 0: 4/4 bars are 2s at 120 BPM, 3/4 bars 1.5s
+        assert_eq!(starts, vec![4.0, 1.5, 4.5]);
+    }
+
+    #[test]
+    fn concat_shifts_the_second_section() {
+        let mels = HashMap::from([
+            ("intro.mel".to_string(), MelodyTrack::from_mel("tempo: 60\nnote: C4, 2, 1.0", &HashMap::new()).unwrap()),
+            ("verse.mel".to_string(), MelodyTrack::from_mel("tempo: 60\nnote: E4, 0.5, 1.0\nnote: G4, 0.5, 1.0", &HashMap::new()).unwrap()),
+        ]);
+        let intro = Arrangement::from_bmi("name: intro\ntrack: intro.mel, 0\n", &mels).unwrap();
+        let verse = Arrangement::from_bmi("name: verse\ntrack: verse.mel, 0.5\nloop: 0.5, 1.5\n", &mels).unwrap();
+        let song = intro.concat(&verse, 1.0);
+
+        assert_eq!(song.total_length, intro.total_length + 1.0 + verse.total_length);
+        assert_eq!(song.name, "intro + verse");
+        let onsets: Vec<f32> = song.iter_timed().map(|(t, _, _)| t).collect();
+        // The verse starts at 2s + 1s gap, its track another 0.5s in
+        assert_eq!(onsets, vec![0.0, 3.5, 4.0]);
+        let lp = song.loop_point.as_ref().unwrap();
+        assert_eq!((lp.start, lp.end), (3.5, 4.5));
+    }
+
+    #[test]
+    fn arpeggio_plays_single_notes_in_mode_order() {
+        let (c, e, g) = (261.63, 329.63, 392.0);
+        let pitches = |mode: ArpMode| {
+            let arrangement = Arrangement::arpeggio(&[c, e, g], mode, 8.0, Instrument::default(), 7);
+            let (track, _, _) = &arrangement.tracks[0];
+            track.iter_timed()
+                .map(|(t, element)| match element {
+                    SequenceElement::Note(note) => (t, note.pitch),
+                    other => panic!("expected single notes, got {:?}", other),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pitches(ArpMode::Up), vec![(0.0, c), (0.125, e), (0.25, g)]);
+        assert_eq!(pitches(ArpMode::Down).iter().map(|p| p.1).collect::<Vec<_>>(), vec![g, e, c]);
+        assert_eq!(pitches(ArpMode::UpDown).iter().map(|p| p.1).collect::<Vec<_>>(), vec![c, e, g, e]);
+        assert!(pitches(ArpMode::Random).iter().all(|p| [c, e, g].contains(&p.1)));
+        // The same seed gives the same random order every time
+        assert_eq!(pitches(ArpMode::Random), pitches(ArpMode::Random));
+        let seeded = |seed| Arrangement::arpeggio(&[c, e, g], ArpMode::Random, 8.0, Instrument::default(), seed).tracks[0].0.sequence.iter()
+            .map(|element| match element { SequenceElement::Note(note) => note.pitch, _ => 0.0 })
+            .collect::<Vec<_>>();
+        assert_ne!(seeded(1), seeded(2));
+    }
+
+    #[test]
+    fn beat_grid_at_120_bpm_is_half_a_second_apart() {
+        let mels = HashMap::from([
+            ("click.mel".to_string(), MelodyTrack::from_mel("tempo: 120\nnote: C4, 8, 1.0", &HashMap::new()).unwrap()),
+        ]);
+        let arrangement = Arrangement::from_bmi("track: click.mel, 0\n", &mels).unwrap();
+        let grid = arrangement.beat_grid();
+        assert!(grid.len() >= 8);
+        assert_eq!(grid[0], 0.0);
+        assert!(grid.windows(2).all(|w| (w[1] - w[0] - 0.5).abs() < 1e-6));
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::utils::soft_clip;
 
 #[derive(Debug, Clone)]
 pub struct ReverbParams {
     pub room_size: f32,
     pub damping: f32,
     pub wet: f32,
-    pub width: f32,
+    pub width: f32, // Stereo spread of the tail: 0 = the same tail on both sides, 1 = fully decorrelated sides
+    pub custom_tunings: Option<Vec<usize>>, // Comb then allpass delay lengths (in samples @ 44.1kHz), overrides Freeverb's
 }
 
 impl Default for ReverbParams {
@@ -15,6 +18,7 @@ impl Default for ReverbParams {
             damping: 0.5,
             wet: 0.3,
             width: 1.0,
+            custom_tunings: None,
         }
     }
 }
@@ -24,6 +28,8 @@ pub struct DelayParams {
     pub time: f32,
     pub feedback: f32,
     pub wet: f32,
+    pub sync: Option<f32>, // Delay length in quarter notes, `time` follows the track tempo (MelodyTrack::sync_effects_to_tempo)
+    pub ping_pong: bool, // Repeats alternate left/right (see ping_pong), a plain delay on the GPU path
 }
 
 impl Default for DelayParams {
@@ -32,6 +38,8 @@ impl Default for DelayParams {
             time: 0.25,
             feedback: 0.4,
             wet: 0.3,
+            sync: None,
+            ping_pong: false,
         }
     }
 }
@@ -58,6 +66,40 @@ pub struct FilterParams {
     pub cutoff: f32, // Cutoff frequency in Hz
     pub resonance: f32, // Q factor
     pub filter_type: FilterType,
+    pub gain_db: Option<f32>, // Peak boost/cut, ignored by the other types
+    pub envelope: Option<FilterEnvelope>, // Per-note cutoff sweep on top of cutoff
+}
+
+// Linear ADSR added to the cutoff for each note: cutoff + amount_hz * env(t)
+#[derive(Debug, Clone, Copy)]
+pub struct FilterEnvelope {
+    pub amount_hz: f32, // Negative sweeps downwards
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32, // 0.0 - 1.0
+    pub release: f32, // Taken from the end of the note, like the amplitude envelope
+}
+
+impl FilterEnvelope {
+    // Level 0.0 - 1.0 at `time` seconds into a note lasting `duration` seconds
+    pub fn level(&self, time: f32, duration: f32) -> f32 {
+        let release_start = (duration - self.release).max(0.0);
+        if time < release_start || self.release <= 0.0 {
+            self.held(time)
+        } else {
+            (self.held(release_start) * (1.0 - (time - release_start) / self.release)).max(0.0)
+        }
+    }
+
+    fn held(&self, time: f32) -> f32 {
+        if time < self.attack {
+            time / self.attack
+        } else if time < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (time - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -65,32 +107,316 @@ pub enum FilterType {
     LowPass, 
     HighPass,
     BandPass,
+    Notch,
+    Peak,
+}
+
+// Bandpass whose cutoff follows the input level: base_freq when quiet, up to base_freq + range when loud
+#[derive(Debug, Clone)]
+pub struct AutoWahParams {
+    pub sensitivity: f32, // Envelope gain before it drives the cutoff
+    pub base_freq: f32, // Hz
+    pub range: f32, // Hz
+    pub resonance: f32, // Q factor
+    pub mix: f32,
+}
+
+impl Default for AutoWahParams {
+    fn default() -> Self {
+        AutoWahParams {
+            sensitivity: 1.0,
+            base_freq: 300.0,
+            range: 2000.0,
+            resonance: 2.0,
+            mix: 1.0,
+        }
+    }
 }
 
+// Lo-fi: amplitude quantized to 2^bits levels, each result held for `downsample` samples
 #[derive(Debug, Clone)]
+pub struct BitcrusherParams {
+    pub bits: u32,
+    pub downsample: u32, // 1 = every sample
+    pub mix: f32,
+}
+
+impl Default for BitcrusherParams {
+    fn default() -> Self {
+        BitcrusherParams {
+            bits: 8,
+            downsample: 4,
+            mix: 1.0,
+        }
+    }
+}
+
+// Copies of the input on short delays swept by an LFO, each voice a fraction of a cycle apart
+#[derive(Debug, Clone)]
+pub struct ChorusParams {
+    pub rate_hz: f32,
+    pub depth_ms: f32, // Sweep of each voice's delay, on top of CHORUS_BASE_MS
+    pub mix: f32,
+    pub voices: usize,
+}
+
+impl Default for ChorusParams {
+    fn default() -> Self {
+        ChorusParams {
+            rate_hz: 0.8,
+            depth_ms: 3.0,
+            mix: 0.4,
+            voices: 3,
+        }
+    }
+}
+
+// Downward expansion: below threshold the level drops `ratio` dB per dB, above it nothing changes
+#[derive(Debug, Clone)]
+pub struct ExpanderParams {
+    pub threshold: f32, // dBFS
+    pub ratio: f32, // 1.0 = off, 2.0 = 1:2 expansion
+    pub attack: f32, // Seconds
+    pub release: f32, // Seconds
+}
+
+impl Default for ExpanderParams {
+    fn default() -> Self {
+        ExpanderParams {
+            threshold: -40.0,
+            ratio: 2.0,
+            attack: 0.005,
+            release: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressorParams {
+    pub threshold_db: f32, // dBFS
+    pub ratio: f32, // 4.0 = 4:1
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub makeup_db: f32,
+    pub knee: f32, // dB width of the soft knee centred on the threshold, 0 = hard knee
+}
+
+impl Default for CompressorParams {
+    fn default() -> Self {
+        CompressorParams {
+            threshold_db: -18.0,
+            ratio: 4.0,
+            attack_ms: 10.0,
+            release_ms: 100.0,
+            makeup_db: 0.0,
+            knee: 0.0,
+        }
+    }
+}
+
+impl CompressorParams {
+    // Static gain change (dB, never positive) for a level in dBFS. Inside the knee the
+    // reduction grows quadratically, meeting the straight ratio line without a corner.
+    pub fn gain_db(&self, level_db: f32) -> f32 {
+        let slope = 1.0 / self.ratio.max(1.0) - 1.0;
+        let over = level_db - self.threshold_db;
+        let half_knee = self.knee.max(0.0) / 2.0;
+        if over <= -half_knee {
+            0.0
+        } else if over < half_knee {
+            slope * (over + half_knee).powi(2) / (2.0 * self.knee)
+        } else {
+            slope * over
+        }
+    }
+}
+
+// Reverb from a recorded impulse response, convolved block-wise
+#[derive(Debug, Clone)]
+pub struct ConvolutionReverbParams {
+    pub ir: Arc<Vec<f32>>, // Mono impulse response, resampled to the engine rate when the processor is built
+    pub ir_sample_rate: u32,
+    pub wet: f32,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct EffectsChain {
     pub reverb: Option<ReverbParams>,
     pub delay: Option<DelayParams>,
     pub distortion: Option<DistortionParams>,
     pub filter: Option<FilterParams>,
+    pub filter2: Option<FilterParams>, // Runs after `filter`, e.g. HP + LP for a band with separate edges
+    pub autowah: Option<AutoWahParams>,
+    pub chorus: Option<ChorusParams>,
+    pub bitcrusher: Option<BitcrusherParams>,
+    pub expander: Option<ExpanderParams>,
+    pub compressor: Option<CompressorParams>,
+    pub conv_reverb: Option<ConvolutionReverbParams>,
 }
 
 impl EffectsChain {
     pub fn has_any(&self) -> bool {
         self.reverb.is_some() || self.delay.is_some() || self.distortion.is_some() || self.filter.is_some()
+            || self.filter2.is_some() || self.autowah.is_some() || self.expander.is_some() || self.compressor.is_some()
+            || self.conv_reverb.is_some() || self.chorus.is_some() || self.bitcrusher.is_some()
+    }
+
+    // Whether the reverb wants a decorrelated tail on each side or the delay bounces between them,
+    // so centred tracks need two channels
+    pub fn stereo_effects(&self) -> bool {
+        self.reverb.as_ref().is_some_and(|r| r.width > 0.0) || self.delay.as_ref().is_some_and(|d| d.ping_pong)
+    }
+
+    // Whether a filter sweeps with each note, so the renderer has to report note positions
+    pub fn filter_envelope(&self) -> bool {
+        [&self.filter, &self.filter2].into_iter().flatten().any(|f| f.envelope.is_some())
+    }
+
+    // Rough time (seconds) for reverb/delay to decay by 60dB after the input stops, capped at 10s
+    pub fn tail_seconds(&self) -> f32 {
+        // Time for a feedback loop of period `period` and gain `gain` to drop 60dB
+        let rt60 = |period: f32, gain: f32| {
+            let gain = gain.abs().clamp(1e-3, 0.999);
+            period * 3.0 / -gain.log10()
+        };
+        let reverb = self.reverb.as_ref().map_or(0.0, |r| {
+            let longest_comb = FREEVERB_COMB_TUNINGS.iter().max().copied().unwrap_or(0) as f32 / 44100.0;
+            rt60(longest_comb, r.room_size)
+        });
+        let delay = self.delay.as_ref().map_or(0.0, |d| d.time + rt60(d.time, d.feedback));
+        let conv = self.conv_reverb.as_ref().map_or(0.0, |c| c.ir.len() as f32 / c.ir_sample_rate.max(1) as f32);
+        reverb.max(delay).max(conv).min(10.0)
     }
 }
 
 
-impl Default for EffectsChain {
-    fn default() -> Self {
-        EffectsChain {
-            reverb: None,
-            delay: None,
-            distortion: None,
-            filter: None,
+// Cross-mixes the reverb tails of a left/right processor pair (Freeverb's wet1/wet2): width 1 keeps
+// each side's own tail, 0 puts the average of both on each side
+pub fn reverb_width(left: f32, right: f32, tails: (f32, f32), width: f32) -> (f32, f32) {
+    let shift = (tails.1 - tails.0) * (1.0 - width.clamp(0.0, 1.0)) * 0.5;
+    (left + shift, right - shift)
+}
+
+// Cross-feeds the delay lines of a left/right processor pair, called before each pair of samples.
+// The right input is sent to the left line, and each line's feedback comes from the other side,
+// so the first repeat is on the left, the second on the right and so on.
+pub fn ping_pong(left: &mut EffectsProcessor, right: &mut EffectsProcessor, params: &DelayParams) {
+    left.delay_buffer[0] += std::mem::take(&mut right.delay_send);
+    let (from_left, from_right) = (left.delay_buffer[left.delay_index(params)], right.delay_buffer[right.delay_index(params)]);
+    left.delay_return = Some(from_right);
+    right.delay_return = Some(from_left);
+}
+
+// Linear gain (makeup included) for a sample at `level`, moving the smoothed reduction `gain_db` along
+fn compressor_gain(gain_db: &mut f32, level: f32, params: &CompressorParams, sample_rate: f32) -> f32 {
+    let target = params.gain_db(20.0 * level.max(1e-6).log10());
+    // Attack while the reduction deepens, release while it recovers
+    let time_ms = if target < *gain_db { params.attack_ms } else { params.release_ms };
+    let coef = (-1.0 / (time_ms.max(0.01) * 0.001 * sample_rate)).exp();
+    *gain_db = target + coef * (*gain_db - target);
+    10f32.powf((*gain_db + params.makeup_db) / 20.0)
+}
+
+// Master bus compressor, both channels share one gain so the image doesn't shift
+pub struct BusCompressor {
+    sample_rate: f32,
+    gain_db: f32, // Smoothed gain reduction
+}
+
+impl BusCompressor {
+    pub fn new(sample_rate: f32) -> Self {
+        BusCompressor { sample_rate, gain_db: 0.0 }
+    }
+
+    pub fn process(&mut self, input: f32, params: &CompressorParams) -> f32 {
+        input * compressor_gain(&mut self.gain_db, input.abs(), params, self.sample_rate)
+    }
+
+    pub fn process_stereo(&mut self, left: f32, right: f32, params: &CompressorParams) -> (f32, f32) {
+        let gain = compressor_gain(&mut self.gain_db, left.abs().max(right.abs()), params, self.sample_rate);
+        (left * gain, right * gain)
+    }
+}
+
+// Peak-following gain for live output: instant attack, slow recovery, never above `ceiling`.
+// Unlike the offline normalize it can't look ahead, so it only ever turns things down.
+pub struct AutoGain {
+    ceiling: f32,
+    release_coef: f32,
+    envelope: f32,
+}
+
+impl AutoGain {
+    pub fn new(sample_rate: f32) -> Self {
+        AutoGain {
+            ceiling: 0.9,
+            release_coef: (-1.0 / (2.0 * sample_rate)).exp(), // ~2s recovery
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.envelope = input.abs().max(self.envelope * self.release_coef);
+        input * self.gain()
+    }
+
+    // Linked stereo: both channels get the same gain, following the louder one
+    pub fn process_stereo(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.envelope = left.abs().max(right.abs()).max(self.envelope * self.release_coef);
+        let gain = self.gain();
+        (left * gain, right * gain)
+    }
+
+    pub fn gain(&self) -> f32 {
+        if self.envelope > self.ceiling { self.ceiling / self.envelope } else { 1.0 }
+    }
+}
+
+// Output meter for UIs: holds each new peak for `hold` seconds, then falls ~20dB per 0.3s.
+// The clip flag latches until reset.
+pub struct PeakMeter {
+    sample_rate: f32,
+    hold_samples: usize,
+    hold_counter: usize,
+    decay_coef: f32,
+    peak: f32,
+    clipped: bool,
+}
+
+impl PeakMeter {
+    pub fn new(sample_rate: f32, hold: f32) -> Self {
+        PeakMeter {
+            sample_rate,
+            hold_samples: (hold.max(0.0) * sample_rate) as usize,
+            hold_counter: 0,
+            decay_coef: (-1.0 / (0.13 * sample_rate)).exp(),
+            peak: 0.0,
+            clipped: false,
         }
     }
+
+    pub fn process(&mut self, sample: f32) {
+        let level = sample.abs();
+        if level >= 1.0 { self.clipped = true; }
+        if level >= self.peak {
+            self.peak = level;
+            self.hold_counter = self.hold_samples;
+        } else if self.hold_counter > 0 {
+            self.hold_counter -= 1;
+        } else {
+            self.peak *= self.decay_coef;
+        }
+    }
+
+    pub fn set_hold(&mut self, hold: f32) {
+        self.hold_samples = (hold.max(0.0) * self.sample_rate) as usize;
+    }
+
+    pub fn peak(&self) -> f32 { self.peak }
+
+    pub fn clipped(&self) -> bool { self.clipped }
+
+    pub fn reset_clip(&mut self) { self.clipped = false; }
 }
 
 pub struct EffectsProcessor {
@@ -99,30 +425,80 @@ pub struct EffectsProcessor {
     comb_filter_state: Vec<f32>,
     allpass_buffers: Vec<VecDeque<f32>>,
     delay_buffer: VecDeque<f32>,
+    delay_return: Option<f32>, // Ping-pong: the other side's delayed sample, fed back instead of our own
+    delay_send: f32, // Ping-pong, right side: last delay input, moved to the left line
+    ping_pong_right: bool,
+    chorus_buffer: VecDeque<f32>,
+    chorus_phase: f32, // LFO phase of the first voice, 0..1
+    crush_hold: f32, // Bitcrusher output being held
+    crush_count: u32, // Samples left before the bitcrusher takes a new one
     lowpass_state: f32,
-    filter_state: (f32, f32), // Biquad filter state (y[n-1], y[n-2])
+    filter_state: [[f32; 4]; 2], // Biquad state x[n-1], x[n-2], y[n-1], y[n-2] for filter and filter2
+    autowah_env: f32,
+    autowah_state: [f32; 4], // x[n-1], x[n-2], y[n-1], y[n-2]
+    cutoff_scale: f32, // Filter keytracking, multiplies FilterParams::cutoff
+    note_time: f32, // Seconds into the playing note and its length, for FilterParams::envelope
+    note_duration: f32,
+    expander_env: f32,
+    compressor_gain_db: f32, // Smoothed gain reduction
+    #[cfg(feature = "convolution")]
+    convolver: Option<Convolver>,
+    reverb_tail: f32, // What the reverb added to the last output sample, for reverb_width
 }
 
+const FREEVERB_COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617]; // Freeverb design, 8 combs
+const FREEVERB_ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const CHORUS_BASE_MS: f32 = 10.0; // Shortest chorus delay, keeps the voices clear of flanging
+const CHORUS_MAX_DEPTH_MS: f32 = 30.0;
+const REVERB_STEREO_SPREAD: usize = 23; // Freeverb's right channel offset on every comb and allpass, in samples @ 44.1kHz
+const FEEDBACK_CEILING: f32 = 1.0; // Delay/comb feedback saturates here, so extreme settings can't run away
+
 impl EffectsProcessor {
     pub fn new(sample_rate: f32) -> Self {
-        let scale = sample_rate / 44100.0; 
-        let comb_delays = vec![ // Freeverb design, 8 combs
-            (1116.0 * scale) as usize,
-            (1188.0 * scale) as usize,
-            (1277.0 * scale) as usize,
-            (1356.0 * scale) as usize,
-            (1422.0 * scale) as usize,
-            (1491.0 * scale) as usize,
-            (1557.0 * scale) as usize,
-            (1617.0 * scale) as usize,
-        ];
-
-        let allpass_delays = vec![
-            (556.0 * scale) as usize,
-            (441.0 * scale) as usize,
-            (341.0 * scale) as usize,
-            (225.0 * scale) as usize,
-        ];
+        Self::with_reverb_tunings(sample_rate, None)
+    }
+
+    // Builds the processor for a given chain, picking up any reverb tunings it carries.
+    pub fn for_chain(sample_rate: f32, effects: &EffectsChain) -> Self {
+        Self::for_chain_spread(sample_rate, effects, 0)
+    }
+
+    // Same, for the right channel: reverb delays offset by the Freeverb stereo spread so the tail decorrelates
+    pub fn for_chain_right(sample_rate: f32, effects: &EffectsChain) -> Self {
+        let mut processor = Self::for_chain_spread(sample_rate, effects, REVERB_STEREO_SPREAD);
+        processor.ping_pong_right = true;
+        processor
+    }
+
+    fn for_chain_spread(sample_rate: f32, effects: &EffectsChain, spread: usize) -> Self {
+        let tunings = effects.reverb.as_ref().and_then(|r| r.custom_tunings.as_deref());
+        let processor = Self::with_tunings(sample_rate, tunings, spread);
+        // Partitioning the IR is the expensive part, keep it off the audio thread
+        #[cfg(feature = "convolution")]
+        let processor = Self { convolver: effects.conv_reverb.as_ref().map(|conv| Convolver::new(conv, sample_rate)), ..processor };
+        processor
+    }
+
+    // The first 8 tunings replace the comb delays, the next 4 the allpass delays.
+    // Missing entries keep their Freeverb default.
+    pub fn with_reverb_tunings(sample_rate: f32, tunings: Option<&[usize]>) -> Self {
+        Self::with_tunings(sample_rate, tunings, 0)
+    }
+
+    fn with_tunings(sample_rate: f32, tunings: Option<&[usize]>, spread: usize) -> Self {
+        let scale = sample_rate / 44100.0;
+        let tuned = |i: usize, default: usize| {
+            let base = tunings.and_then(|t| t.get(i)).copied().unwrap_or(default) + spread;
+            ((base as f32 * scale) as usize).max(1)
+        };
+        let comb_delays: Vec<usize> = FREEVERB_COMB_TUNINGS.iter()
+            .enumerate()
+            .map(|(i, &d)| tuned(i, d))
+            .collect();
+        let allpass_delays: Vec<usize> = FREEVERB_ALLPASS_TUNINGS.iter()
+            .enumerate()
+            .map(|(i, &d)| tuned(FREEVERB_COMB_TUNINGS.len() + i, d))
+            .collect();
 
         EffectsProcessor {
             sample_rate,
@@ -134,38 +510,120 @@ impl EffectsProcessor {
                 .map(|&size| VecDeque::from(vec![0.0; size]))
                 .collect(),
             delay_buffer: VecDeque::from(vec![0.0; (sample_rate * 2.0) as usize]),
+            delay_return: None,
+            delay_send: 0.0,
+            ping_pong_right: false,
+            chorus_buffer: VecDeque::from(vec![0.0; ((CHORUS_BASE_MS + CHORUS_MAX_DEPTH_MS) / 1000.0 * sample_rate) as usize + 2]),
+            chorus_phase: 0.0,
+            crush_hold: 0.0,
+            crush_count: 0,
             lowpass_state: 0.0,
-            filter_state: (0.0, 0.0),
+            filter_state: [[0.0; 4]; 2],
+            autowah_env: 0.0,
+            autowah_state: [0.0; 4],
+            cutoff_scale: 1.0,
+            note_time: 0.0,
+            note_duration: f32::INFINITY,
+            expander_env: 0.0,
+            compressor_gain_db: 0.0,
+            #[cfg(feature = "convolution")]
+            convolver: None,
+            reverb_tail: 0.0,
         }
     }
 
     pub fn process(&mut self, input: f32, effects: &EffectsChain) -> f32 {
+        self.process_split(input, effects).0
+    }
+
+    // Only what the delay/reverb add on top of the direct signal, for auditioning the tails alone
+    pub fn process_wet(&mut self, input: f32, effects: &EffectsChain) -> f32 {
+        let (output, direct) = self.process_split(input, effects);
+        output - direct
+    }
+
+    // (full output, direct part of it): the direct part is the signal after the insert effects,
+    // scaled by the dry share of the delay and reverb mixes
+    fn process_split(&mut self, input: f32, effects: &EffectsChain) -> (f32, f32) {
         let mut output = input;
 
+        // Expander works on the raw level, ahead of anything that colours it
+        if let Some(exp) = &effects.expander {
+            output = self.apply_expander(output, exp);
+        }
+
+        if let Some(comp) = &effects.compressor {
+            output = self.apply_compressor(output, comp);
+        }
+
         // Apply filter first in the chain for cleaner frequency shaping
         if let Some(filter) = &effects.filter {
-            output = self.apply_filter(output, filter);
+            output = self.apply_filter(output, filter, 0);
+        }
+        if let Some(filter) = &effects.filter2 {
+            output = self.apply_filter(output, filter, 1);
+        }
+
+        // Wah ahead of the drive, like the pedal order
+        if let Some(wah) = &effects.autowah {
+            output = self.apply_autowah(output, wah);
         }
 
         if let Some(dist) = &effects.distortion {
             output = self.apply_distortion(output, dist);
         }
 
+        if let Some(crush) = &effects.bitcrusher {
+            output = self.apply_bitcrusher(output, crush);
+        }
+
+        if let Some(chorus) = &effects.chorus {
+            output = self.apply_chorus(output, chorus);
+        }
+
+        let mut direct = output;
+
         if let Some(delay) = &effects.delay {
             output = self.apply_delay(output, delay);
+            direct *= 1.0 - delay.wet;
         }
 
         if let Some(reverb) = &effects.reverb {
             output = self.apply_reverb(output, reverb);
+            direct *= 1.0 - reverb.wet;
         }
 
-        output
+        #[cfg(feature = "convolution")]
+        if let Some(conv) = &effects.conv_reverb {
+            let sample_rate = self.sample_rate;
+            let convolver = self.convolver.get_or_insert_with(|| Convolver::new(conv, sample_rate));
+            output = output * (1.0 - conv.wet) + convolver.process(output) * conv.wet;
+            direct *= 1.0 - conv.wet;
+            self.reverb_tail *= 1.0 - conv.wet;
+        }
+
+        (output, direct)
+    }
+
+    // Scales the filter cutoff from here on (keytracking follows the playing note)
+    pub fn set_cutoff_scale(&mut self, scale: f32) {
+        self.cutoff_scale = scale;
+    }
+
+    // Position in the playing note, drives the filter envelope
+    pub fn set_note_time(&mut self, time: f32, duration: f32) {
+        self.note_time = time;
+        self.note_duration = duration;
     }
 
-    // Biquad filter implementation for lowpass/highpass/bandpass
-    fn apply_filter(&mut self, input: f32, params: &FilterParams) -> f32 {
-        let omega = std::f32::consts::TAU * params.cutoff / self.sample_rate;
+    // Biquad filter implementation for lowpass/highpass/bandpass/notch/peak
+    fn apply_filter(&mut self, input: f32, params: &FilterParams, slot: usize) -> f32 {
+        let sweep = params.envelope.map_or(0.0, |env| env.amount_hz * env.level(self.note_time, self.note_duration));
+        let cutoff = (params.cutoff * self.cutoff_scale + sweep).clamp(10.0, self.sample_rate * 0.45);
+        let omega = std::f32::consts::TAU * cutoff / self.sample_rate;
         let alpha = omega.sin() * params.resonance;
+        // Notch and peak take resonance as a Q, like the autowah's bandpass
+        let q_alpha = omega.sin() / (2.0 * params.resonance.max(0.1));
         
         // Calculate biquad coefficients based on filter type
         let (b0, b1, b2, a0, a1, a2) = match params.filter_type {
@@ -202,18 +660,75 @@ impl EffectsProcessor {
                     1.0 - alpha,
                 )
             }
+            FilterType::Notch => {
+                let cos_omega = omega.cos();
+                (
+                    1.0,
+                    -2.0 * cos_omega,
+                    1.0,
+                    1.0 + q_alpha,
+                    -2.0 * cos_omega,
+                    1.0 - q_alpha,
+                )
+            }
+            FilterType::Peak => {
+                let cos_omega = omega.cos();
+                let a = 10f32.powf(params.gain_db.unwrap_or(0.0) / 40.0);
+                (
+                    1.0 + q_alpha * a,
+                    -2.0 * cos_omega,
+                    1.0 - q_alpha * a,
+                    1.0 + q_alpha / a,
+                    -2.0 * cos_omega,
+                    1.0 - q_alpha / a,
+                )
+            }
         };
 
         // y[n] = (b0*x[n] + b1*x[n-1] + b2*x[n-2] - a1*y[n-1] - a2*y[n-2]) / a0
-        let output = (b0 * input + b1 * self.filter_state.0 + b2 * self.filter_state.1
-            - a1 * self.filter_state.0 - a2 * self.filter_state.1) / a0;
-
-        self.filter_state.1 = self.filter_state.0;
-        self.filter_state.0 = output;
+        let [x1, x2, y1, y2] = self.filter_state[slot];
+        let output = (b0 * input + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2) / a0;
+        self.filter_state[slot] = [input, x1, output, y1];
 
         output
     }
 
+    fn apply_expander(&mut self, input: f32, params: &ExpanderParams) -> f32 {
+        let level = input.abs();
+        let time = if level > self.expander_env { params.attack } else { params.release };
+        let coef = (-1.0 / (time.max(1e-4) * self.sample_rate)).exp();
+        self.expander_env = level + coef * (self.expander_env - level);
+
+        let env_db = 20.0 * self.expander_env.max(1e-6).log10();
+        if env_db >= params.threshold { return input; }
+        let gain_db = (env_db - params.threshold) * (params.ratio.max(1.0) - 1.0);
+        input * 10f32.powf(gain_db / 20.0)
+    }
+
+    fn apply_compressor(&mut self, input: f32, params: &CompressorParams) -> f32 {
+        input * compressor_gain(&mut self.compressor_gain_db, input.abs(), params, self.sample_rate)
+    }
+
+    fn apply_autowah(&mut self, input: f32, params: &AutoWahParams) -> f32 {
+        // Envelope follower, ~5ms attack and ~80ms release
+        let coef = if input.abs() > self.autowah_env { 0.005 } else { 0.08 };
+        let coef = (-1.0 / (coef * self.sample_rate)).exp();
+        self.autowah_env = input.abs() + coef * (self.autowah_env - input.abs());
+
+        let sweep = (self.autowah_env * params.sensitivity).min(1.0);
+        let cutoff = (params.base_freq + params.range * sweep).clamp(20.0, self.sample_rate * 0.45);
+        let omega = std::f32::consts::TAU * cutoff / self.sample_rate;
+        let alpha = omega.sin() / (2.0 * params.resonance.max(0.1));
+
+        // Constant 0dB peak bandpass
+        let [x1, x2, y1, y2] = self.autowah_state;
+        let a0 = 1.0 + alpha;
+        let wet = (alpha * input - alpha * x2 + 2.0 * omega.cos() * y1 - (1.0 - alpha) * y2) / a0;
+        self.autowah_state = [input, x1, wet, y1];
+
+        input * (1.0 - params.mix) + wet * params.mix
+    }
+
     fn apply_distortion(&mut self, input: f32, params: &DistortionParams) -> f32 {
         let driven = input * params.drive;
         let distorted = if driven > 1.0 {
@@ -230,17 +745,58 @@ impl EffectsProcessor {
         input * (1.0 - params.wet) + self.lowpass_state * params.wet
     }
 
-    fn apply_delay(&mut self, input: f32, params: &DelayParams) -> f32 {
-        let delay_samples = (params.time * self.sample_rate) as usize;
-        let delay_samples = delay_samples.min(self.delay_buffer.len() - 1);
+    fn delay_index(&self, params: &DelayParams) -> usize {
+        ((params.time * self.sample_rate) as usize).min(self.delay_buffer.len() - 1)
+    }
 
-        let delayed = self.delay_buffer[delay_samples];
+    fn apply_delay(&mut self, input: f32, params: &DelayParams) -> f32 {
+        let delayed = self.delay_buffer[self.delay_index(params)];
 
-        Self::cycle_buffer(&mut self.delay_buffer, input + delayed * params.feedback);
+        // Only set while ping_pong drives this processor, otherwise a plain delay
+        let (feedback, send) = match self.delay_return.take() {
+            Some(other) if self.ping_pong_right => {
+                self.delay_send = input;
+                (other, 0.0)
+            }
+            Some(other) => (other, input),
+            None => (delayed, input),
+        };
+        Self::cycle_buffer(&mut self.delay_buffer, send + soft_clip(feedback * params.feedback, FEEDBACK_CEILING));
 
         input * (1.0 - params.wet) + delayed * params.wet
     }
 
+    fn apply_bitcrusher(&mut self, input: f32, params: &BitcrusherParams) -> f32 {
+        if self.crush_count == 0 {
+            let steps = 2f32.powi(params.bits.clamp(1, 24) as i32 - 1);
+            self.crush_hold = (input * steps).round() / steps;
+            self.crush_count = params.downsample.max(1);
+        }
+        self.crush_count -= 1;
+
+        input * (1.0 - params.mix) + self.crush_hold * params.mix
+    }
+
+    // Same newest-first buffer as the delay, but every voice reads between two samples
+    fn apply_chorus(&mut self, input: f32, params: &ChorusParams) -> f32 {
+        Self::cycle_buffer(&mut self.chorus_buffer, input);
+        let voices = params.voices.max(1);
+        let depth = params.depth_ms.clamp(0.0, CHORUS_MAX_DEPTH_MS);
+        let last = (self.chorus_buffer.len() - 2) as f32;
+        let mut wet = 0.0;
+        for v in 0..voices {
+            let lfo = (std::f32::consts::TAU * (self.chorus_phase + v as f32 / voices as f32)).sin();
+            let delay_ms = CHORUS_BASE_MS + depth * (0.5 + 0.5 * lfo);
+            let pos = (delay_ms / 1000.0 * self.sample_rate).min(last);
+            let idx = pos as usize;
+            let frac = pos - idx as f32;
+            wet += self.chorus_buffer[idx] * (1.0 - frac) + self.chorus_buffer[idx + 1] * frac;
+        }
+        self.chorus_phase = (self.chorus_phase + params.rate_hz / self.sample_rate).fract();
+
+        input * (1.0 - params.mix) + wet / voices as f32 * params.mix
+    }
+
     fn apply_reverb(&mut self, input: f32, params: &ReverbParams) -> f32 {
         let mut output = 0.0;
 
@@ -250,7 +806,7 @@ impl EffectsProcessor {
             self.comb_filter_state[i] = delayed * (1.0 - params.damping) + 
                                         self.comb_filter_state[i] * params.damping;
             
-            let feedback = self.comb_filter_state[i] * params.room_size;
+            let feedback = soft_clip(self.comb_filter_state[i] * params.room_size, FEEDBACK_CEILING);
             
             Self::cycle_buffer(&mut self.comb_buffers[i], input + feedback);
             
@@ -266,12 +822,369 @@ impl EffectsProcessor {
             output = delayed - output * 0.5;
         }
 
+        self.reverb_tail = output * params.wet;
         input * (1.0 - params.wet) + output * params.wet
     }
 
+    pub fn reverb_tail(&self) -> f32 {
+        self.reverb_tail
+    }
+
     #[inline]
     fn cycle_buffer(buffer: &mut VecDeque<f32>, new_value: f32) {
         buffer.pop_back();
         buffer.push_front(new_value);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "convolution")]
+const CONV_BLOCK: usize = 256; // Partition size, also the convolution reverb's latency in samples
+
+#[cfg(feature = "convolution")]
+#[derive(Debug, Clone, Copy, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+#[cfg(feature = "convolution")]
+impl Complex {
+    fn mul(self, o: Complex) -> Complex {
+        Complex { re: self.re * o.re - self.im * o.im, im: self.re * o.im + self.im * o.re }
+    }
+}
+
+// In-place iterative radix-2 FFT over a power-of-two buffer, unnormalized in both directions
+#[cfg(feature = "convolution")]
+fn fft(buf: &mut [Complex], inverse: bool) {
+    let n = buf.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j { buf.swap(i, j); }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (im, re) = (angle * k as f32).sin_cos();
+                let (a, b) = (buf[start + k], buf[start + k + len / 2].mul(Complex { re, im }));
+                buf[start + k] = Complex { re: a.re + b.re, im: a.im + b.im };
+                buf[start + k + len / 2] = Complex { re: a.re - b.re, im: a.im - b.im };
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Uniformly partitioned overlap-save convolution: the IR is cut into CONV_BLOCK partitions whose
+// spectra are multiplied against the spectra of the matching past input blocks and summed.
+#[cfg(feature = "convolution")]
+struct Convolver {
+    partitions: Vec<Vec<Complex>>, // Spectrum of each IR partition
+    history: VecDeque<Vec<Complex>>, // Spectra of the latest input frames, newest first
+    input: Vec<f32>, // Previous block, then the block being filled
+    output: Vec<f32>, // Block being played out
+    pos: usize,
+    sum: Vec<Complex>,
+}
+
+#[cfg(feature = "convolution")]
+impl Convolver {
+    fn new(params: &ConvolutionReverbParams, sample_rate: f32) -> Self {
+        let ir = Self::resample(&params.ir, params.ir_sample_rate as f32, sample_rate);
+        let size = 2 * CONV_BLOCK;
+        let partitions: Vec<Vec<Complex>> = ir.chunks(CONV_BLOCK)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); size];
+                spectrum.iter_mut().zip(chunk).for_each(|(c, &s)| c.re = s);
+                fft(&mut spectrum, false);
+                spectrum
+            })
+            .collect();
+        Convolver {
+            history: vec![vec![Complex::default(); size]; partitions.len().max(1)].into(),
+            partitions,
+            input: vec![0.0; size],
+            output: vec![0.0; CONV_BLOCK],
+            pos: 0,
+            sum: vec![Complex::default(); size],
+        }
+    }
+
+    // Linear interpolation is plenty for a reverb tail, and keeps its length in seconds
+    fn resample(ir: &[f32], from: f32, to: f32) -> Vec<f32> {
+        if from <= 0.0 || from == to { return ir.to_vec(); }
+        let step = from / to;
+        let len = (ir.len() as f32 / step) as usize;
+        (0..len)
+            .map(|i| {
+                let pos = i as f32 * step;
+                let (idx, frac) = (pos as usize, pos.fract());
+                let next = ir.get(idx + 1).copied().unwrap_or(0.0);
+                ir[idx] * (1.0 - frac) + next * frac
+            })
+            .collect()
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.input[CONV_BLOCK + self.pos] = input;
+        let out = self.output[self.pos];
+        self.pos += 1;
+        if self.pos == CONV_BLOCK {
+            self.pos = 0;
+            self.run_block();
+        }
+        out
+    }
+
+    fn run_block(&mut self) {
+        // The oldest frame drops out of reach of the IR, reuse its buffer for the newest
+        let mut frame = self.history.pop_back().unwrap_or_default();
+        frame.resize(2 * CONV_BLOCK, Complex::default());
+        frame.iter_mut().zip(&self.input).for_each(|(c, &s)| *c = Complex { re: s, im: 0.0 });
+        fft(&mut frame, false);
+        self.history.push_front(frame);
+        self.input.copy_within(CONV_BLOCK.., 0);
+
+        self.sum.fill(Complex::default());
+        for (x, h) in self.history.iter().zip(&self.partitions) {
+            for ((acc, &a), &b) in self.sum.iter_mut().zip(x).zip(h) {
+                let p = a.mul(b);
+                acc.re += p.re;
+                acc.im += p.im;
+            }
+        }
+        fft(&mut self.sum, true);
+        // Only the second half is free of circular wrap-around
+        let norm = 1.0 / (2 * CONV_BLOCK) as f32;
+        self.output.iter_mut().zip(&self.sum[CONV_BLOCK..]).for_each(|(o, c)| *o = c.re * norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 44100.0;
+
+    fn filter_chain(filter_type: FilterType, cutoff: f32, resonance: f32, gain_db: Option<f32>) -> EffectsChain {
+        EffectsChain {
+            filter: Some(FilterParams { cutoff, resonance, filter_type, gain_db, envelope: None }),
+            ..Default::default()
+        }
+    }
+
+    // RMS gain of a sine at `freq` through the chain, measured once the filter has settled
+    fn sine_gain(chain: &EffectsChain, freq: f32) -> f32 {
+        let mut fx = EffectsProcessor::for_chain(SR, chain);
+        let n = SR as usize / 2;
+        let (mut dry, mut wet) = (0.0f32, 0.0f32);
+        for i in 0..n {
+            let x = (std::f32::consts::TAU * freq * i as f32 / SR).sin();
+            let y = fx.process(x, chain);
+            if i >= n / 2 {
+                dry += x * x;
+                wet += y * y;
+            }
+        }
+        (wet / dry).sqrt()
+    }
+
+    #[test]
+    fn lowpass_attenuates_above_cutoff() {
+        let chain = filter_chain(FilterType::LowPass, 1000.0, 0.707, None);
+        assert!((sine_gain(&chain, 100.0) - 1.0).abs() < 0.05);
+        // Each octave above the cutoff comes out quieter than the last, ~12 dB apart once past the knee
+        let gains: Vec<f32> = [2000.0, 4000.0, 8000.0].iter().map(|&f| sine_gain(&chain, f)).collect();
+        assert!(gains[0] < 0.5, "2 kHz gain {}", gains[0]);
+        assert!(gains.windows(2).all(|w| w[1] < w[0] * 0.5), "gains {:?}", gains);
+    }
+
+    #[test]
+    fn notch_attenuates_its_centre() {
+        let chain = filter_chain(FilterType::Notch, 1000.0, 1.0, None);
+        assert!(sine_gain(&chain, 1000.0) < 0.05);
+        assert!(sine_gain(&chain, 100.0) > 0.9);
+    }
+
+    #[test]
+    fn peak_boosts_its_centre() {
+        let chain = filter_chain(FilterType::Peak, 1000.0, 1.0, Some(12.0));
+        let boost = sine_gain(&chain, 1000.0);
+        // +12 dB is a gain of ~3.98
+        assert!((boost - 3.98).abs() < 0.2, "peak gain {}", boost);
+        assert!((sine_gain(&chain, 100.0) - 1.0).abs() < 0.1);
+    }
+
+    fn conv_params(ir: Vec<f32>, ir_sample_rate: u32) -> ConvolutionReverbParams {
+        ConvolutionReverbParams { ir: Arc::new(ir), ir_sample_rate, wet: 1.0 }
+    }
+
+    #[cfg(feature = "convolution")]
+    #[test]
+    fn convolving_an_impulse_reproduces_the_ir() {
+        // Longer than two partitions, so the summing across partitions is covered too
+        let ir: Vec<f32> = (0..600).map(|i| ((i * 37) % 101) as f32 / 101.0 - 0.5).collect();
+        let mut conv = Convolver::new(&conv_params(ir.clone(), SR as u32), SR);
+        let out: Vec<f32> = (0..ir.len() + 2 * CONV_BLOCK).map(|i| conv.process(if i == 0 { 1.0 } else { 0.0 })).collect();
+        // One block of latency
+        for (k, &h) in ir.iter().enumerate() {
+            assert!((out[k + CONV_BLOCK] - h).abs() < 1e-4, "sample {}: {} vs {}", k, out[k + CONV_BLOCK], h);
+        }
+    }
+
+    #[test]
+    fn conv_reverb_tail_follows_the_ir_rate() {
+        let chain = EffectsChain { conv_reverb: Some(conv_params(vec![0.0; 22050], 22050)), ..Default::default() };
+        assert!((chain.tail_seconds() - 1.0).abs() < 1e-6);
+    }
+
+    // Index of the first sample the reverb puts out after an impulse
+    fn first_echo(params: ReverbParams) -> usize {
+        let chain = EffectsChain { reverb: Some(ReverbParams { wet: 1.0, ..params }), ..Default::default() };
+        let mut fx = EffectsProcessor::for_chain(SR, &chain);
+        (0..SR as usize).position(|i| fx.process(if i == 0 { 1.0 } else { 0.0 }, &chain).abs() > 1e-6).unwrap()
+    }
+
+    #[test]
+    fn custom_reverb_tunings_move_the_echoes() {
+        // Freeverb's shortest comb is 1116 samples; eight equal 700-sample combs repeat every 700 instead
+        assert_eq!(first_echo(ReverbParams::default()), 1116);
+        let tuned = ReverbParams { custom_tunings: Some(vec![700; 8]), ..Default::default() };
+        assert_eq!(first_echo(tuned), 700);
+    }
+
+    #[test]
+    fn auto_gain_holds_a_loud_sine_under_full_scale() {
+        let mut agc = AutoGain::new(SR);
+        let out: Vec<f32> = (0..SR as usize * 2)
+            .map(|i| agc.process(4.0 * (std::f32::consts::TAU * 220.0 * i as f32 / SR).sin()))
+            .collect();
+        let settled = out[out.len() / 2..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(out.iter().all(|s| s.abs() <= 1.0));
+        assert!((settled - 0.9).abs() < 0.01, "settled peak {}", settled);
+    }
+
+    #[test]
+    fn near_unity_delay_feedback_stays_bounded() {
+        let delay = DelayParams { time: 0.1, feedback: 0.99, wet: 1.0, ..Default::default() };
+        let chain = EffectsChain { delay: Some(delay), ..Default::default() };
+        let mut fx = EffectsProcessor::for_chain(SR, &chain);
+        // A full-scale tone that lines up with the delay line, so every repeat adds in phase
+        let loudest = (0..SR as usize * 10)
+            .map(|i| fx.process((std::f32::consts::TAU * 100.0 * i as f32 / SR).sin(), &chain))
+            .fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(loudest.is_finite() && loudest <= 2.0, "peak {}", loudest);
+    }
+
+    #[test]
+    fn autowah_opens_up_on_louder_input() {
+        let chain = EffectsChain { autowah: Some(AutoWahParams::default()), ..Default::default() };
+        // Relative level of a 1.5kHz tone through the wah, which only reaches it when the envelope pushes the cutoff up
+        let gain = |amplitude: f32| {
+            let mut fx = EffectsProcessor::for_chain(SR, &chain);
+            let n = SR as usize / 2;
+            let out: f32 = (0..n)
+                .map(|i| fx.process(amplitude * (std::f32::consts::TAU * 1500.0 * i as f32 / SR).sin(), &chain))
+                .skip(n / 2)
+                .map(|y| y * y)
+                .sum();
+            (out / (n / 2) as f32).sqrt() / (amplitude * std::f32::consts::FRAC_1_SQRT_2)
+        };
+        let (quiet, loud) = (gain(0.05), gain(0.8));
+        assert!(loud > 2.0 * quiet, "quiet {} loud {}", quiet, loud);
+    }
+
+    #[test]
+    fn expander_applies_its_ratio_below_threshold_only() {
+        let chain = EffectsChain { expander: Some(ExpanderParams::default()), ..Default::default() };
+        // Settled output level in dB for a constant input at `db`
+        let settled_db = |db: f32| {
+            let mut fx = EffectsProcessor::for_chain(SR, &chain);
+            let level = 10f32.powf(db / 20.0);
+            let out = (0..SR as usize).map(|_| fx.process(level, &chain)).last().unwrap();
+            20.0 * out.log10()
+        };
+        // 10 dB under the -40 dB threshold at 1:2 comes out 20 dB under
+        assert!((settled_db(-50.0) - -60.0).abs() < 0.1);
+        assert!((settled_db(-20.0) - -20.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn peak_meter_holds_then_falls_20db_per_300ms() {
+        let mut meter = PeakMeter::new(SR, 0.5);
+        meter.process(1.2);
+        let run = |meter: &mut PeakMeter, seconds: f32| (0..(seconds * SR) as usize).for_each(|_| meter.process(0.0));
+        run(&mut meter, 0.49);
+        assert_eq!(meter.peak(), 1.2);
+        run(&mut meter, 0.01 + 0.3);
+        assert!((meter.peak() / 1.2 - 0.1).abs() < 0.01, "peak {}", meter.peak());
+        assert!(meter.clipped());
+        meter.reset_clip();
+        assert!(!meter.clipped());
+    }
+
+    #[test]
+    fn soft_knee_eases_into_the_ratio() {
+        let params = CompressorParams { threshold_db: -20.0, ratio: 4.0, knee: 12.0, ..Default::default() };
+        // Inside the knee but under the threshold: a little reduction, far less than the full ratio would give
+        let below = params.gain_db(-23.0);
+        assert!(below < 0.0 && below > -0.5, "{}", below);
+        assert_eq!(params.gain_db(-27.0), 0.0);
+        assert_eq!(params.gain_db(-10.0), -7.5);
+        // No jumps across the knee: neighbouring levels 0.01 dB apart stay within a hair of each other
+        for step in 0..3000 {
+            let level = -35.0 + step as f32 * 0.01;
+            assert!((params.gain_db(level + 0.01) - params.gain_db(level)).abs() < 0.01, "jump at {} dB", level);
+        }
+        let hard = CompressorParams { knee: 0.0, ..params };
+        assert_eq!((hard.gain_db(-21.0), hard.gain_db(-16.0)), (0.0, -3.0));
+    }
+
+    #[test]
+    fn highpass_then_lowpass_passes_the_band_between() {
+        let track = crate::track::MelodyTrack::from_mel("filter: hp, 300, 0.707\nfilter2: lp, 3000, 0.707", &std::collections::HashMap::new()).unwrap();
+        let chain = &track.instrument.effects;
+        assert!(matches!(chain.filter2, Some(FilterParams { filter_type: FilterType::LowPass, .. })));
+        let mid = sine_gain(chain, 1000.0);
+        assert!(mid > 0.85 && mid < 1.05, "mid {}", mid);
+        assert!(sine_gain(chain, 40.0) < 0.05);
+        assert!(sine_gain(chain, 15000.0) < 0.1);
+    }
+
+    // Left and right reverb tails of a centred impulse, cross-mixed at `width`
+    fn stereo_tail(width: f32) -> (Vec<f32>, Vec<f32>) {
+        let chain = EffectsChain { reverb: Some(ReverbParams { wet: 1.0, width, ..Default::default() }), ..Default::default() };
+        let (mut fx_left, mut fx_right) = (EffectsProcessor::for_chain(SR, &chain), EffectsProcessor::for_chain_right(SR, &chain));
+        (0..SR as usize)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                let (l, r) = (fx_left.process(x, &chain), fx_right.process(x, &chain));
+                reverb_width(l, r, (fx_left.reverb_tail(), fx_right.reverb_tail()), width)
+            })
+            .skip(SR as usize / 10)
+            .unzip()
+    }
+
+    #[test]
+    fn reverb_width_decorrelates_the_tail() {
+        let correlation = |(l, r): (Vec<f32>, Vec<f32>)| {
+            let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+            dot(&l, &r) / (dot(&l, &l) * dot(&r, &r)).sqrt()
+        };
+        let (mono_left, mono_right) = stereo_tail(0.0);
+        assert!(mono_left.iter().any(|s| s.abs() > 1e-4));
+        assert!(mono_left.iter().zip(&mono_right).all(|(l, r)| (l - r).abs() < 1e-6));
+        let wide = correlation(stereo_tail(1.0));
+        assert!(wide < 0.5, "correlation {}", wide);
+        assert!(correlation(stereo_tail(0.5)) > wide);
+    }
+}
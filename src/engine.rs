@@ -1,14 +1,18 @@
-use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{StreamConfig, Stream};
 
 use crate::error::SynthError;
-use crate::instrument::{Instrument, InstrumentSource, SampleData, SequenceElement};
-use crate::track::MelodyTrack;
-use crate::arrangement::Arrangement;
-use crate::effects::EffectsProcessor;
+use crate::instrument::{Instrument, InstrumentSource, SampleData, Note, SequenceElement};
+use crate::track::{MelodyTrack, LoopPoint, MAX_NOTE_SHIFT};
+use crate::arrangement::{Arrangement, ArpMode, CurveShape};
+use crate::effects::{EffectsChain, EffectsProcessor, AutoGain, BusCompressor, PeakMeter, reverb_width, ping_pong};
+use crate::waveform::{WaveformType, NoiseState};
+use crate::utils::{soft_clip, stereo_width, pan_gains, stereo_balance};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PlaybackState {
@@ -17,11 +21,23 @@ pub enum PlaybackState {
     Paused,
 }
 
+// Sample format for WAV export
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WavFormat {
+    #[default]
+    Pcm16, // Normalized render, clamped to [-1, 1]
+    Float32, // Raw render, keeps anything above 1.0 for later mastering
+}
+
+#[derive(Clone)]
 pub struct DynamicParameters {
     pub master_volume: f32,
     pub master_pitch: f32,
+    pub master_width: f32, // Mid/side width: 0 = mono, 1 = unchanged, >1 = wider
     pub track_volumes: HashMap<String, f32>,
     pub track_enabled: HashMap<String, bool>,
+    pub track_wet_only: HashMap<String, bool>, // Effects solo: only the delay/reverb return is heard
+    pub track_fade: HashMap<String, f32>, // Gain of tracks ramped by fade_track, held where the ramp ended. 1.0 when absent
     pub crossfade_duration: f32,
 }
 
@@ -30,8 +46,11 @@ impl Default for DynamicParameters {
         DynamicParameters {
             master_volume: 1.0,
             master_pitch: 1.0,
+            master_width: 1.0,
             track_volumes: HashMap::new(),
             track_enabled: HashMap::new(),
+            track_wet_only: HashMap::new(),
+            track_fade: HashMap::new(),
             crossfade_duration: 1.0,
         }
     }
@@ -43,23 +62,497 @@ struct PlaybackContext {
     state: PlaybackState,
     loop_enabled: bool,
     dynamic_params: DynamicParameters,
-    param_interpolators: HashMap<String, f32>,
+    param_ramps: HashMap<RampTarget, ParamRamp>,
+    track_fades: HashMap<String, TrackFade>,
     crossfade_state: Option<CrossfadeState>,
+    live: LiveState,
+    max_polyphony: Option<usize>,
+    auto_gain: Option<AutoGain>,
+    bus_compressor: BusCompressor, // Used when the arrangement has a compressor:
+    meter: PeakMeter,
+    cue: Option<CueRender>, // Set while a cue output is open
+    zero_length: bool, // Stopped because the arrangement had nothing to play, reported by last_stream_error
+}
+
+impl PlaybackContext {
+    fn new(arrangement: Arrangement, sample_rate: f32, cue: Option<CueRender>, crossfade_state: Option<CrossfadeState>) -> Self {
+        let mut context = PlaybackContext {
+            live: LiveState::new(&arrangement, sample_rate),
+            arrangement,
+            current_sample: 0,
+            state: PlaybackState::Playing,
+            loop_enabled: false,
+            dynamic_params: DynamicParameters::default(),
+            param_ramps: HashMap::new(),
+            track_fades: HashMap::new(),
+            crossfade_state,
+            max_polyphony: None,
+            auto_gain: None,
+            bus_compressor: BusCompressor::new(sample_rate),
+            meter: PeakMeter::new(sample_rate, DEFAULT_PEAK_HOLD),
+            cue,
+            zero_length: false,
+        };
+        for (track, _, _) in &context.arrangement.tracks {
+            context.dynamic_params.track_enabled.insert(track.name.clone(), true);
+            context.dynamic_params.track_volumes.insert(track.name.clone(), 1.0);
+        }
+        context
+    }
+
+    // Nothing to play (all rests, parse edge case): stops instead of wrapping at 0 forever.
+    // Returns whether it stopped; runs on the audio thread, so it only records the reason.
+    fn stop_if_empty(&mut self, sample_rate: f32) -> bool {
+        if (self.arrangement.total_length * sample_rate) as usize > 0 { return false; }
+        self.state = PlaybackState::Stopped;
+        self.zero_length = true;
+        true
+    }
+
+    // Moves every fade_track ramp on by one sample, flipping the track's enabled flag as its ramp finishes
+    fn step_track_fades(&mut self) {
+        if self.track_fades.is_empty() { return; }
+        let params = &mut self.dynamic_params;
+        // fade_track made both entries under the lock, they're only written in place here
+        self.track_fades.retain(|name, fade| {
+            let Some(gain) = params.track_fade.get_mut(name) else { return false };
+            *gain = (*gain + fade.step).clamp(0.0, 1.0);
+            let done = if fade.target_enabled { *gain >= 1.0 } else { *gain <= 0.0 };
+            if done && let Some(enabled) = params.track_enabled.get_mut(name) {
+                *enabled = fade.target_enabled;
+            }
+            !done
+        });
+    }
+
+    // Steps the playhead one sample, wrapping at the loop region (or the whole arrangement) while looping.
+    // Returns true on the sample that ran off the end; the rest of the buffer still runs, so only that one notifies.
+    fn advance_playhead(&mut self, sample_rate: f32) -> bool {
+        self.current_sample += 1;
+        let total = (self.arrangement.total_length * sample_rate) as usize;
+        if !self.loop_enabled {
+            if self.current_sample >= total && self.state == PlaybackState::Playing {
+                self.state = PlaybackState::Stopped;
+                return true;
+            }
+            return false;
+        }
+        // An empty or inverted loop region would spin, loop the whole arrangement instead
+        if let Some(ref lp) = self.arrangement.loop_point
+            && (lp.end * sample_rate) as usize > (lp.start * sample_rate) as usize {
+            let pos = self.current_sample as f32 / sample_rate;
+            if pos >= lp.end {
+                self.current_sample = (lp.start * sample_rate) as usize;
+            }
+        } else if self.current_sample >= total {
+            self.current_sample = 0;
+        }
+        false
+    }
+
+    // Mixes the incoming arrangement of a running crossfade over this sample's output and steps the fade.
+    // Once it completes the incoming arrangement takes over, from where the fade got to.
+    fn crossfade(&mut self, left: f32, right: f32, sample_rate: f32) -> (f32, f32) {
+        let Some(cf) = self.crossfade_state.as_mut() else { return (left, right) };
+        let t = cf.progress / cf.duration_samples as f32;
+        let (tgt_left, tgt_right) = SynthEngine::synthesize_single_sample(
+            &cf.target_arrangement,
+            cf.target_sample,
+            sample_rate,
+            &self.dynamic_params,
+            None,
+            &mut cf.target_live,
+            self.max_polyphony,
+        );
+        let out = (left * (1.0 - t) + tgt_left * t, right * (1.0 - t) + tgt_right * t);
+        cf.progress += 1.0;
+        if cf.progress < cf.duration_samples as f32 {
+            cf.target_sample += 1;
+        } else if let Some(done) = self.crossfade_state.take() {
+            // The playhead steps past the fade's last sample after this
+            self.arrangement = done.target_arrangement;
+            self.current_sample = done.target_sample;
+            self.live = done.target_live;
+            if let (Some(cue), Some(live)) = (self.cue.as_mut(), done.cue_live) {
+                cue.live = live;
+            }
+        }
+        out
+    }
+}
+
+// Secondary "cue" mix for performer headphones: its own track enables plus an optional metronome
+#[derive(Debug, Clone)]
+pub struct CueMix {
+    pub track_enabled: HashMap<String, bool>, // Tracks not listed follow the main mix
+    pub metronome: bool,
+    pub metronome_level: f32,
+    pub volume: f32,
+}
+
+impl Default for CueMix {
+    fn default() -> Self {
+        CueMix {
+            track_enabled: HashMap::new(),
+            metronome: true,
+            metronome_level: 0.5,
+            volume: 1.0,
+        }
+    }
+}
+
+// Cue render state, separate from the main mix so effects tails don't share state
+struct CueRender {
+    live: LiveState,
+    limiter: AutoGain,
+    mix: CueMix, // Copy of the engine's, updated by the set_cue_* calls under the playback lock
+}
+
+impl CueRender {
+    fn new(arrangement: &Arrangement, sample_rate: f32, mix: CueMix) -> Self {
+        CueRender {
+            live: LiveState::new(arrangement, sample_rate),
+            limiter: AutoGain::new(sample_rate),
+            mix,
+        }
+    }
+}
+
+// Fixed-size single producer/single consumer queue from the main callback to the cue stream.
+// Neither side locks or allocates; once full, each push drops the oldest sample.
+struct CueRing {
+    samples: Box<[AtomicU32]>, // f32 bits
+    read: AtomicUsize, // Samples taken so far, wrapped into `samples` on access
+    write: AtomicUsize, // Samples written so far
+}
+
+impl CueRing {
+    fn new(capacity: usize) -> Self {
+        CueRing {
+            samples: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, sample: f32) {
+        let w = self.write.load(Ordering::Relaxed);
+        let r = self.read.load(Ordering::Acquire);
+        if w - r >= self.samples.len() {
+            // Fails only if the consumer just took it, which frees the slot all the same
+            let _ = self.read.compare_exchange(r, r + 1, Ordering::AcqRel, Ordering::Relaxed);
+        }
+        self.samples[w % self.samples.len()].store(sample.to_bits(), Ordering::Relaxed);
+        self.write.store(w + 1, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<f32> {
+        loop {
+            let r = self.read.load(Ordering::Acquire);
+            if r == self.write.load(Ordering::Acquire) { return None; }
+            let sample = f32::from_bits(self.samples[r % self.samples.len()].load(Ordering::Relaxed));
+            // A failed exchange means the producer dropped this sample to make room, take the next one
+            if self.read.compare_exchange(r, r + 1, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                return Some(sample);
+            }
+        }
+    }
+
+    // Drops everything queued. Only moves `read` forward, so a consumer running alongside stays consistent.
+    fn clear(&self) {
+        self.read.fetch_max(self.write.load(Ordering::Acquire), Ordering::AcqRel);
+    }
+}
+
+// Live output captured between start_recording and stop_recording
+struct Recording {
+    buffer: Vec<f32>, // Reserved in full up front, so the audio thread never reallocates
+    max_len: usize, // Samples, recording stops once reached
+}
+
+impl Recording {
+    fn new(max_len: usize) -> Self {
+        Recording { buffer: Vec::with_capacity(max_len), max_len }
+    }
+
+    fn push(&mut self, sample: f32) {
+        if self.buffer.len() < self.max_len { self.buffer.push(sample); }
+    }
+}
+
+// Mute/unmute ramp started by fade_track
+struct TrackFade {
+    step: f32, // Gain change per sample
+    target_enabled: bool,
+}
+
+// Parameter gliding to a new value, see interpolate_track_volume and friends
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RampTarget {
+    TrackVolume(String),
+    MasterVolume,
+    MasterPitch,
+}
+
+struct ParamRamp {
+    start_value: f32,
+    target_value: f32,
+    samples_total: usize,
+    samples_done: usize,
+}
+
+impl ParamRamp {
+    // Moves one sample on, returning the new value
+    fn step(&mut self) -> f32 {
+        self.samples_done += 1;
+        let progress = self.samples_done as f32 / self.samples_total as f32;
+        self.start_value + (self.target_value - self.start_value) * progress.min(1.0)
+    }
+
+    fn done(&self) -> bool {
+        self.samples_done >= self.samples_total
+    }
 }
 
 struct CrossfadeState {
     target_arrangement: Arrangement,
+    target_live: LiveState,
+    target_sample: usize, // The incoming arrangement's own playhead, from its start
+    cue_live: Option<LiveState>, // The cue's state for the incoming arrangement, built here so the handover doesn't allocate
     progress: f32,
     duration_samples: usize,
 }
 
+impl CrossfadeState {
+    fn new(target_arrangement: Arrangement, duration: f32, sample_rate: f32, cue: bool) -> Self {
+        CrossfadeState {
+            target_live: LiveState::new(&target_arrangement, sample_rate),
+            cue_live: cue.then(|| LiveState::new(&target_arrangement, sample_rate)),
+            target_arrangement,
+            target_sample: 0,
+            progress: 0.0,
+            duration_samples: (duration * sample_rate) as usize,
+        }
+    }
+}
+
+const VOICE_STEAL_RELEASE: f32 = 0.005; // Seconds
+const DEFAULT_PEAK_HOLD: f32 = 1.5; // Seconds
+const CUE_QUEUE_SECONDS: f32 = 0.5; // Size of the cue ring, samples waiting past this are dropped oldest first
+const CLICK_LENGTH: f32 = 0.03; // Seconds
+const DEFAULT_RENDER_SEED: u64 = 0x5EED;
+const OSC_DC_BLOCK_HZ: f32 = 5.0;
+
+// Per-arrangement state the realtime scan carries between samples
+struct LiveState {
+    track_fx: Vec<Option<TrackFx>>,
+    voices: Vec<LiveVoice>, // Scratch, refilled every sample
+    stolen: Vec<(VoiceKey, f32)>, // Voices taken by the polyphony cap and when, kept until they stop sounding
+    rng: fastrand::Rng, // Noise for this mix, so engines playing side by side don't share a generator
+    noise: Vec<Vec<Vec<NoiseState>>>, // Pink/brown voices: [track][element][chord pitch * unison copies + copy]
+    pitch_clock: PitchClock,
+}
+
+impl LiveState {
+    fn new(arrangement: &Arrangement, sample_rate: f32) -> Self {
+        LiveState {
+            track_fx: TrackFx::for_arrangement(arrangement, sample_rate),
+            voices: Vec::new(),
+            stolen: Vec::new(),
+            rng: fastrand::Rng::with_seed(arrangement.seed.unwrap_or(DEFAULT_RENDER_SEED)),
+            noise: Self::noise_slots(arrangement),
+            pitch_clock: PitchClock::new(arrangement),
+        }
+    }
+
+    // Filter memory for every voice that can be pink or brown noise, allocated up front so the
+    // callback never inserts. A new LiveState comes with each arrangement, so none of it outlives a swap.
+    fn noise_slots(arrangement: &Arrangement) -> Vec<Vec<Vec<NoiseState>>> {
+        arrangement.tracks.iter()
+            .map(|(track, _, _)| track.sequence.iter().map(|element| Self::element_noise(track, element)).collect())
+            .collect()
+    }
+
+    fn element_noise(track: &MelodyTrack, element: &SequenceElement) -> Vec<NoiseState> {
+        let (wf, pitches) = match element {
+            SequenceElement::Note(note) => (track.instrument.note_waveform(note.wave), 1),
+            SequenceElement::Chord(chord) => (track.instrument.note_waveform(None), chord.pitches.len()),
+            SequenceElement::Rest(_) | SequenceElement::TempoChange(_) => (None, 0),
+        };
+        let slots = if wf.is_some_and(|wf| wf.is_coloured_noise()) { pitches * track.instrument.unison_ratios().len() } else { 0 };
+        vec![NoiseState::default(); slots]
+    }
+
+    // Adds slots for elements appended to track `i` since this state was built. Runs under the
+    // playback lock, the callback only ever indexes into what is here.
+    fn extend_track(&mut self, i: usize, track: &MelodyTrack) {
+        if let Some(noise) = self.noise.get_mut(i) {
+            let from = noise.len().min(track.sequence.len());
+            noise.extend(track.sequence[from..].iter().map(|element| Self::element_noise(track, element)));
+        }
+        if let Some(voices) = self.pitch_clock.voices.get_mut(i) {
+            let from = voices.len().min(track.sequence.len());
+            voices.extend(track.sequence[from..].iter().map(|element| PitchClock::element_voices(track, element)));
+        }
+    }
+
+    // Panned notes appended to a centred track split its effects from here on, the right-hand
+    // processor is built on the control thread rather than in the callback
+    fn split_track_fx(&mut self, i: usize, sample_rate: f32) {
+        if let Some(Some(fx)) = self.track_fx.get_mut(i) && fx.right.is_none() {
+            fx.right = Some(EffectsProcessor::for_chain_right(sample_rate, &fx.chain));
+        }
+    }
+
+    // Voice `slot` of element `n` on track `i`, reset on the note's first sample so a retriggered
+    // (looped, replayed) voice doesn't start from the last run's filter memory
+    fn noise_state(noise: &mut [Vec<Vec<NoiseState>>], i: usize, n: usize, slot: usize, onset: bool) -> Option<&mut NoiseState> {
+        let state = noise.get_mut(i)?.get_mut(n)?.get_mut(slot)?;
+        if onset { *state = NoiseState::default(); }
+        Some(state)
+    }
+}
+
+// Playback time scaled by the master pitch, integrated sample by sample. Phases taken from it stay
+// continuous while the master pitch moves; time * master_pitch would jump by time * change on every step.
+struct PitchClock {
+    now: f64,
+    voices: Vec<Vec<Vec<VoiceSlot>>>, // Sample voices: [track][element][chord pitch]
+}
+
+type VoiceSlot = Option<(f64, f32)>; // Clock at onset and last note time, once the voice has been heard
+
+impl PitchClock {
+    // One slot per sample voice, allocated up front like the noise slots
+    fn new(arrangement: &Arrangement) -> Self {
+        PitchClock {
+            now: 0.0,
+            voices: arrangement.tracks.iter()
+                .map(|(track, _, _)| track.sequence.iter().map(|element| Self::element_voices(track, element)).collect())
+                .collect(),
+        }
+    }
+
+    fn element_voices(track: &MelodyTrack, element: &SequenceElement) -> Vec<VoiceSlot> {
+        let pitches = match element {
+            SequenceElement::Note(_) => 1,
+            SequenceElement::Chord(chord) => chord.pitches.len(),
+            SequenceElement::Rest(_) | SequenceElement::TempoChange(_) => 0,
+        };
+        match track.instrument.source {
+            InstrumentSource::Sample(_) | InstrumentSource::DrumMap(_) => vec![None; pitches],
+            InstrumentSource::Synthesized(_) => Vec::new(),
+        }
+    }
+
+    fn now(&self) -> f32 {
+        self.now as f32
+    }
+
+    fn advance(&mut self, master_pitch: f32, sample_rate: f32) {
+        self.now += (master_pitch / sample_rate) as f64;
+    }
+
+    // Scaled time a sample voice has played, `t` seconds after its onset. A voice seen again at an
+    // earlier `t` has restarted (loop, seek), its onset is taken again. A voice without a slot
+    // falls back to the unintegrated time.
+    fn voice_time(&mut self, (i, n, k): (usize, usize, usize), t: f32, master_pitch: f32) -> f32 {
+        let onset = self.now - (t * master_pitch) as f64;
+        let Some(slot) = self.voices.get_mut(i).and_then(|track| track.get_mut(n)).and_then(|element| element.get_mut(k)) else {
+            return t * master_pitch;
+        };
+        let voice = slot.get_or_insert((onset, t));
+        if t < voice.1 { voice.0 = onset; }
+        voice.1 = t;
+        (self.now - voice.0) as f32
+    }
+}
+
+// One sounding note (or chord pitch) at the current instant. Voices are ordered newest onset
+// first, ties broken by track, element index and pitch, so stealing never depends on push order.
+struct LiveVoice {
+    track: usize,
+    onset: f32, // Absolute start time in seconds, used to find the oldest voice
+    element: usize, // Index into the track's sequence
+    pitch: f32,
+    pan: f32,
+    value: f32,
+    image: Option<(f32, f32)>, // Left/right of a stereo sample, balanced by `pan` instead of panning `value`
+}
+
+// (track, element, pitch bits): one live voice, stable from sample to sample
+type VoiceKey = (usize, usize, u32);
+
+impl LiveVoice {
+    fn key(&self) -> VoiceKey {
+        (self.track, self.element, self.pitch.to_bits())
+    }
+
+    fn allocation_order(&self, other: &LiveVoice) -> std::cmp::Ordering {
+        other.onset.total_cmp(&self.onset)
+            .then(self.track.cmp(&other.track))
+            .then(self.element.cmp(&other.element))
+            .then(self.pitch.total_cmp(&other.pitch))
+    }
+}
+
+// Live effects state for one arrangement track. Fed silence through rests and past the
+// end of the track so reverb/delay tails keep ringing.
+struct TrackFx {
+    processor: EffectsProcessor, // Left channel, or both while the track is centred
+    right: Option<EffectsProcessor>, // Right channel once the track has panned voices or a stereo reverb
+    chain: EffectsChain,
+}
+
+impl TrackFx {
+    // One slot per arrangement track, None where the track has no effects
+    fn for_arrangement(arrangement: &Arrangement, sample_rate: f32) -> Vec<Option<TrackFx>> {
+        arrangement.tracks.iter()
+            .map(|(track, _, overrides)| {
+                let chain = overrides.effects_over(&track.instrument.effects);
+                let centred = track.is_centred() && overrides.pan.is_none_or(|p| p == 0.0);
+                chain.has_any().then(|| TrackFx {
+                    processor: EffectsProcessor::for_chain(sample_rate, &chain),
+                    right: (!centred || chain.stereo_effects()).then(|| EffectsProcessor::for_chain_right(sample_rate, &chain)),
+                    chain,
+                })
+            })
+            .collect()
+    }
+
+    fn set_cutoff_scale(&mut self, scale: f32) {
+        self.processor.set_cutoff_scale(scale);
+        if let Some(right) = self.right.as_mut() { right.set_cutoff_scale(scale); }
+    }
+
+    fn set_note_time(&mut self, time: f32, duration: f32) {
+        self.processor.set_note_time(time, duration);
+        if let Some(right) = self.right.as_mut() { right.set_note_time(time, duration); }
+    }
+}
+
+// Names loaded by load_sample_dir, and each file it skipped with the reason
+pub type SampleDirLoad = (Vec<String>, Vec<(PathBuf, SynthError)>);
+
+// Start sample, left channel, right channel when it differs from the left
+type RenderedTrack = (usize, Vec<f32>, Option<Vec<f32>>);
+
 pub struct SynthEngine {
     mel_cache: HashMap<String, MelodyTrack>,
     sample_cache: HashMap<String, SampleData>,
     stream_config: StreamConfig,
+    host_id: cpal::HostId,
     pub sample_rate: f32,
     playback_context: Arc<Mutex<Option<PlaybackContext>>>,
     stream: Option<Stream>,
+    stream_error: Arc<Mutex<Option<SynthError>>>, // Last error reported by the audio stream (device lost, ...)
+    cue_mix: Arc<Mutex<CueMix>>,
+    cue_queue: Arc<CueRing>, // Rendered by the main callback, drained by the cue stream
+    cue_stream: Option<Stream>,
+    render_seed: u64, // Used by renders of arrangements without their own seed:
+    render_tails: bool, // Offline renders run on past total_length until releases and effects have rung out
+    recording: Arc<Mutex<Option<Recording>>>,
+    finished: Arc<Mutex<Option<SyncSender<()>>>>, // Wakes the on_finished watcher thread
 }
 
 impl SynthEngine {
@@ -76,33 +569,83 @@ impl SynthEngine {
                     sample_rate: cpal::SampleRate(44100),
                     buffer_size: cpal::BufferSize::Default,
                 },
+                host_id: cpal::default_host().id(),
                 sample_rate: 44100.0,
                 playback_context: Arc::new(Mutex::new(None)),
                 stream: None,
+                stream_error: Arc::new(Mutex::new(None)),
+                cue_mix: Arc::new(Mutex::new(CueMix::default())),
+                cue_queue: Arc::new(CueRing::new((CUE_QUEUE_SECONDS * 44100.0) as usize)),
+                cue_stream: None,
+                render_seed: DEFAULT_RENDER_SEED,
+            render_tails: true,
+            recording: Arc::new(Mutex::new(None)),
+            finished: Arc::new(Mutex::new(None)),
             });
         }
 
         #[cfg(not(target_arch = "wasm32"))]
         {
-            let host = cpal::default_host();
-            let device = host.default_output_device()
-                .ok_or_else(|| SynthError::AudioError("No output device found".to_string()));
-            let config = device?.default_output_config()
-                .map_err(|e| SynthError::AudioError(e.to_string()))?;
-            let stream_config = config.config();
-
-            Ok(SynthEngine {
-                mel_cache: HashMap::new(),
-                sample_cache: HashMap::new(),
-                stream_config: stream_config.clone(),
-                sample_rate: stream_config.sample_rate.0 as f32,
-                playback_context: Arc::new(Mutex::new(None)),
-                stream: None,
-            })
+            Self::with_host(cpal::default_host().id())
+        }
+    }
+
+    // Same as new(), but on a specific cpal backend (e.g. ALSA vs JACK on Linux).
+    pub fn with_host(host_id: cpal::HostId) -> Result<Self, SynthError> {
+        Self::open(host_id, None)
+    }
+
+    // Same as with_host(), but forcing the output channel count (1 for a mono SFX bus, 2 on a surround device).
+    pub fn with_channels(host_id: cpal::HostId, channels: u16) -> Result<Self, SynthError> {
+        Self::open(host_id, Some(channels))
+    }
+
+    fn open(host_id: cpal::HostId, channels: Option<u16>) -> Result<Self, SynthError> {
+        let host = cpal::host_from_id(host_id)
+            .map_err(|e| SynthError::DeviceError(format!("Host '{}' unavailable: {}", host_id.name(), e)))?;
+        let device = host.default_output_device()
+            .ok_or_else(|| SynthError::DeviceError(format!("No output device found on host '{}'", host_id.name())))?;
+        let config = device.default_output_config()
+            .map_err(|e| SynthError::DeviceError(e.to_string()))?;
+        let mut stream_config = config.config();
+
+        if let Some(channels) = channels {
+            let rate = stream_config.sample_rate;
+            let supported = device.supported_output_configs()
+                .map_err(|e| SynthError::DeviceError(e.to_string()))?
+                .any(|c| c.channels() == channels && c.min_sample_rate() <= rate && rate <= c.max_sample_rate());
+            if !supported {
+                return Err(SynthError::DeviceError(format!("Output device does not support {} channel(s) at {} Hz", channels, rate.0)));
+            }
+            stream_config.channels = channels;
         }
+
+        Ok(SynthEngine {
+            mel_cache: HashMap::new(),
+            sample_cache: HashMap::new(),
+            stream_config: stream_config.clone(),
+            host_id,
+            sample_rate: stream_config.sample_rate.0 as f32,
+            playback_context: Arc::new(Mutex::new(None)),
+            stream: None,
+            stream_error: Arc::new(Mutex::new(None)),
+            cue_mix: Arc::new(Mutex::new(CueMix::default())),
+            cue_queue: Arc::new(CueRing::new((CUE_QUEUE_SECONDS * stream_config.sample_rate.0 as f32) as usize)),
+            cue_stream: None,
+            render_seed: DEFAULT_RENDER_SEED,
+            render_tails: true,
+            recording: Arc::new(Mutex::new(None)),
+            finished: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // Backends compiled into cpal that are usable on this machine.
+    pub fn list_hosts() -> Vec<cpal::HostId> {
+        cpal::available_hosts()
     }
 
-    // Used by GpuSynthEngine to delegate CPU-side work without requiring audio hardware, internal only.
+    // Used by GpuSynthEngine and the render threads for CPU-side work without requiring audio hardware, internal only.
+    #[cfg_attr(not(any(feature = "gpu", feature = "parallel")), allow(dead_code))]
     pub(crate) fn new_offline(sample_rate: f32) -> Self {
         SynthEngine {
             mel_cache:    HashMap::new(),
@@ -112,9 +655,18 @@ impl SynthEngine {
                 sample_rate: cpal::SampleRate(sample_rate as u32),
                 buffer_size: cpal::BufferSize::Default,
             },
+            host_id: cpal::default_host().id(),
             sample_rate,
             playback_context: Arc::new(Mutex::new(None)),
             stream: None,
+            stream_error: Arc::new(Mutex::new(None)),
+            cue_mix: Arc::new(Mutex::new(CueMix::default())),
+            cue_queue: Arc::new(CueRing::new((CUE_QUEUE_SECONDS * sample_rate) as usize)),
+            cue_stream: None,
+            render_seed: DEFAULT_RENDER_SEED,
+            render_tails: true,
+            recording: Arc::new(Mutex::new(None)),
+            finished: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -122,24 +674,134 @@ impl SynthEngine {
         &self.sample_cache
     }
 
-    pub fn load_sample(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
-        let data = std::fs::read(path)?;
+    // Seed for offline renders of arrangements that don't set their own `seed:`
+    pub fn set_render_seed(&mut self, seed: u64) {
+        self.render_seed = seed;
+    }
+
+    // Off renders exactly total_length (e.g. for loops that must tile); on by default
+    pub fn set_render_tails(&mut self, enabled: bool) {
+        self.render_tails = enabled;
+    }
+
+    // Seconds an offline render of `arrangement` runs for
+    pub(crate) fn render_seconds(&self, arrangement: &Arrangement) -> f32 {
+        if self.render_tails {
+            arrangement.total_length.max(arrangement.ring_out_seconds())
+        } else {
+            arrangement.total_length
+        }
+    }
+
+    // Buffer length for one track's render, overrides already applied
+    pub(crate) fn track_samples(&self, track: &MelodyTrack) -> usize {
+        let mut seconds = track.length;
+        if self.render_tails {
+            seconds = seconds.max(track.duration_seconds() + track.release_tail() + track.instrument.effects.tail_seconds());
+        }
+        (seconds * self.sample_rate) as usize
+    }
+
+    // Random source for one track of a render: the arrangement's seed (else the engine's), varied per track
+    pub(crate) fn track_rng(&self, arrangement: &Arrangement, track_index: usize) -> fastrand::Rng {
+        let seed = arrangement.seed.unwrap_or(self.render_seed);
+        fastrand::Rng::with_seed(seed ^ (track_index as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    }
+
+    // Parsed track (instrument and sequence) of a melody loaded with load_melody
+    pub fn get_melody(&self, name: &str) -> Option<&MelodyTrack> {
+        self.mel_cache.get(name)
+    }
+
+    pub fn load_sample(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
+        let sample_data = Self::read_sample(path)?;
+        self.sample_cache.insert(name.to_string(), sample_data);
+        Ok(())
+    }
+
+    // Like load_sample, but scaled so the peak hits 1.0 (applied gain kept in SampleData::gain)
+    pub fn load_sample_normalized(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
+        let mut sample_data = Self::read_sample(path)?;
+        sample_data.normalize();
+        self.sample_cache.insert(name.to_string(), sample_data);
+        Ok(())
+    }
+
+    // Loads every .wav/.ogg in a directory under its file stem, returning the loaded names in sorted order
+    // and the files that failed with their errors. Those are skipped so one bad file doesn't sink the kit.
+    pub fn load_sample_dir(&mut self, dir: &str) -> Result<SampleDirLoad, SynthError> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(|e| SynthError::io(dir, e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e.eq_ignore_ascii_case("wav") || e.eq_ignore_ascii_case("ogg")))
+            .collect();
+        paths.sort();
+
+        let mut loaded = Vec::new();
+        let mut failed = Vec::new();
+        for path in paths {
+            let (Some(name), Some(path_str)) = (path.file_stem().and_then(|s| s.to_str()), path.to_str()) else { continue };
+            match Self::read_sample(path_str) {
+                Ok(sample_data) => {
+                    self.sample_cache.insert(name.to_string(), sample_data);
+                    loaded.push(name.to_string());
+                }
+                Err(e) => failed.push((path, e)),
+            }
+        }
+        Ok((loaded, failed))
+    }
+
+    // Picks the decoder from the file extension, WAV unless told otherwise
+    fn read_sample(path: &str) -> Result<SampleData, SynthError> {
+        let is_ogg = std::path::Path::new(path).extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("ogg"));
+        if is_ogg { Self::read_ogg(path) } else { Self::read_wav(path) }
+    }
+
+    #[cfg(feature = "ogg")]
+    fn read_ogg(path: &str) -> Result<SampleData, SynthError> {
+        let data = std::fs::read(path).map_err(|e| SynthError::io(path, e))?;
+        let undecodable = |e: lewton::VorbisError| SynthError::UnsupportedFormat(format!("'{}': {}", path, e));
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(std::io::Cursor::new(data)).map_err(undecodable)?;
+        let channels = reader.ident_hdr.audio_channels.max(1) as usize;
+        let sample_rate = reader.ident_hdr.audio_sample_rate;
+
+        let mut data = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl().map_err(undecodable)? {
+            data.extend(packet.iter().map(|&s| s as f32 / 32768.0));
+        }
+        Ok(SampleData::from_interleaved(&data, channels, sample_rate))
+    }
+
+    #[cfg(not(feature = "ogg"))]
+    fn read_ogg(path: &str) -> Result<SampleData, SynthError> {
+        Err(SynthError::UnsupportedFormat(format!("Cannot load '{}': Ogg/Vorbis support requires the `ogg` feature", path)))
+    }
+
+    fn read_wav(path: &str) -> Result<SampleData, SynthError> {
+        let data = std::fs::read(path).map_err(|e| SynthError::io(path, e))?;
         let cursor = std::io::Cursor::new(data);
-        let mut reader = hound::WavReader::new(cursor)?;
+        let undecodable = |e: hound::Error| match e {
+            hound::Error::IoError(e) => SynthError::io(path, e),
+            e => SynthError::UnsupportedFormat(format!("'{}': {}", path, e)),
+        };
+        let mut reader = hound::WavReader::new(cursor).map_err(undecodable)?;
         let spec = reader.spec();
-        let samples: Result<Vec<f32>, _> = reader.samples::<i16>()
-            .map(|r| r.map(|s| s as f32 / 32768.0))
-            .collect();
-        let sample_data = SampleData {
-            samples: Arc::new(samples?),
-            sample_rate: spec.sample_rate,
+        // Integer PCM of any width (8, 16, 24, 32 bit) scaled by its own full scale, float taken as is
+        let samples: Result<Vec<f32>, _> = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, bits @ 1..=32) => {
+                let full_scale = (1u64 << (bits - 1)) as f32;
+                reader.samples::<i32>().map(|r| r.map(|s| s as f32 / full_scale)).collect()
+            }
+            (hound::SampleFormat::Float, 32) => reader.samples::<f32>().collect(),
+            (format, bits) => return Err(SynthError::UnsupportedFormat(format!("'{}': {} bit {:?} WAV", path, bits, format))),
         };
-        self.sample_cache.insert(name.to_string(), sample_data);
-        Ok(())
+        Ok(SampleData::from_interleaved(&samples.map_err(undecodable)?, spec.channels as usize, spec.sample_rate))
     }
 
-    pub fn load_melody(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
-        let content = std::fs::read_to_string(path)?;
+    pub fn load_melody(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
+        let content = std::fs::read_to_string(path).map_err(|e| SynthError::io(path, e))?;
         let track = MelodyTrack::from_mel(&content, &self.sample_cache)?;
         self.mel_cache.insert(name.to_string(), track);
         Ok(())
@@ -147,102 +809,403 @@ impl SynthEngine {
 
     pub fn load_arrangement(&self, path: &str) -> Result<Arrangement, SynthError> {
         let content = std::fs::read_to_string(path)
-            .map_err(|e| SynthError::FileError(e.to_string()))?;
-        Arrangement::from_bmi(&content, &self.mel_cache)
+            .map_err(|e| SynthError::io(path, e))?;
+        Arrangement::from_bmi_with_samples(&content, &self.mel_cache, &self.sample_cache)
+    }
+
+    // Arpeggiates `pitches` live until stop() (or another play call) replaces it
+    pub fn play_arp(&mut self, pitches: Vec<f32>, mode: ArpMode, rate_hz: f32, instrument: Instrument) -> Result<(), SynthError> {
+        if pitches.is_empty() {
+            return Err(SynthError::InvalidInstrument("play_arp needs at least one pitch".to_string()));
+        }
+        self.play_arrangement(Arrangement::arpeggio(&pitches, mode, rate_hz, instrument, self.render_seed))?;
+        self.set_loop_enabled(true);
+        Ok(())
     }
 
     pub fn play_arrangement(&mut self, arrangement: Arrangement) -> Result<(), SynthError> {
+        self.start_playback(arrangement, None)
+    }
+
+    fn start_playback(&mut self, arrangement: Arrangement, crossfade_state: Option<CrossfadeState>) -> Result<(), SynthError> {
         self.stop();
-        let mut context = PlaybackContext {
-            arrangement,
-            current_sample: 0,
-            state: PlaybackState::Playing,
-            loop_enabled: false,
-            dynamic_params: DynamicParameters::default(),
-            param_interpolators: HashMap::new(),
-            crossfade_state: None,
-        };
-        for (track, _, _) in &context.arrangement.tracks {
-            context.dynamic_params.track_enabled.insert(track.name.clone(), true);
-            context.dynamic_params.track_volumes.insert(track.name.clone(), 1.0);
-        }
+        let cue = self.cue_stream.is_some().then(|| CueRender::new(&arrangement, self.sample_rate, self.cue_mix.lock().unwrap().clone()));
+        let context = PlaybackContext::new(arrangement, self.sample_rate, cue, crossfade_state);
         *self.playback_context.lock().unwrap() = Some(context);
         self.start_stream()?;
         Ok(())
     }
 
     pub fn crossfade_to(&mut self, new_arrangement: Arrangement, duration: f32) -> Result<(), SynthError> {
+        let crossfade = CrossfadeState::new(new_arrangement, duration, self.sample_rate, self.cue_stream.is_some());
         {
             let mut ctx_lock = self.playback_context.lock().unwrap();
             if let Some(ctx) = ctx_lock.as_mut() {
-                ctx.crossfade_state = Some(CrossfadeState {
-                    target_arrangement: new_arrangement,
-                    progress: 0.0,
-                    duration_samples: (duration * self.sample_rate) as usize,
-                });
+                ctx.crossfade_state = Some(crossfade);
                 return Ok(());
             }
         }
-        self.play_arrangement(new_arrangement)?;
-        Ok(())
+        if crossfade.duration_samples == 0 {
+            return self.play_arrangement(crossfade.target_arrangement);
+        }
+
+        // Nothing to fade from, so fade in from silence rather than hard-starting.
+        // The silent source outlasts the crossfade so it can't stop playback first.
+        self.start_playback(Self::silence(duration + 1.0), Some(crossfade))
     }
 
-    pub fn set_loop_enabled(&self, enabled: bool) {
-        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            ctx.loop_enabled = enabled;
+    fn silence(seconds: f32) -> Arrangement {
+        Arrangement {
+            name: "silence".to_string(),
+            tracks: Vec::new(),
+            total_length: seconds,
+            loop_point: None,
+            master_tempo: None,
+            fade_in: None,
+            fade_out: None,
+            fade_curve: Default::default(),
+            seed: None,
+            compressor: None,
+            lyrics: Vec::new(),
         }
     }
 
-    pub fn pause(&self) {
-        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            if ctx.state == PlaybackState::Playing {
-                ctx.state = PlaybackState::Paused;
+    // Extends a playing track in place, new elements sound once the playhead reaches them.
+    pub fn append_to_track(&self, track_name: &str, elements: Vec<SequenceElement>) -> Result<(), SynthError> {
+        let mut ctx_lock = self.playback_context.lock().unwrap();
+        let ctx = ctx_lock.as_mut()
+            .ok_or_else(|| SynthError::AudioError("Nothing is playing".to_string()))?;
+
+        let mut found = false;
+        for (i, (track, start_time, _)) in ctx.arrangement.tracks.iter_mut().enumerate().filter(|(_, (t, _, _))| t.name == track_name) {
+            track.sequence.extend(elements.iter().cloned());
+            track.length = track.total_beats();
+            ctx.arrangement.total_length = ctx.arrangement.total_length.max(*start_time + track.length);
+            let split = !track.is_centred();
+            ctx.live.extend_track(i, track);
+            if split { ctx.live.split_track_fx(i, self.sample_rate); }
+            if let Some(cue) = ctx.cue.as_mut() {
+                cue.live.extend_track(i, track);
+                if split { cue.live.split_track_fx(i, self.sample_rate); }
             }
+            found = true;
+        }
+
+        if !found {
+            return Err(SynthError::InvalidInstrument(format!("Track not found: {}", track_name)));
         }
+        Ok(())
     }
 
-    pub fn resume(&self) {
+    pub fn set_loop_enabled(&self, enabled: bool) {
         if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            if ctx.state == PlaybackState::Paused {
-                ctx.state = PlaybackState::Playing;
-            }
+            ctx.loop_enabled = enabled;
         }
     }
 
-    pub fn stop(&mut self) {
-        if let Some(stream) = self.stream.take() { drop(stream); }
-        *self.playback_context.lock().unwrap() = None;
+    // Loops start..end (seconds, clamped to the arrangement) from now on and turns looping on
+    pub fn set_loop_region(&self, start: f32, end: f32) -> Result<(), SynthError> {
+        let mut ctx_lock = self.playback_context.lock().unwrap();
+        let ctx = ctx_lock.as_mut()
+            .ok_or_else(|| SynthError::AudioError("Nothing is playing".to_string()))?;
+        let length = ctx.arrangement.total_length;
+        let (start, end) = (start.clamp(0.0, length), end.clamp(0.0, length));
+        if start >= end {
+            return Err(SynthError::AudioError(format!("Invalid loop region {}..{} (arrangement is {}s)", start, end, length)));
+        }
+        ctx.arrangement.loop_point = Some(LoopPoint { start, end });
+        ctx.loop_enabled = true;
+        Ok(())
     }
 
-    pub fn set_master_volume(&self, volume: f32) {
+    // Drops the loop region, looping (if enabled) covers the whole arrangement again
+    pub fn clear_loop_region(&self) {
         if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            ctx.dynamic_params.master_volume = volume.max(0.0).min(2.0);
+            ctx.arrangement.loop_point = None;
         }
     }
 
-    pub fn set_master_pitch(&self, pitch: f32) {
-        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            ctx.dynamic_params.master_pitch = pitch.max(0.5).min(2.0);
+    pub fn loop_region(&self) -> Option<(f32, f32)> {
+        self.playback_context.lock().unwrap().as_ref()
+            .and_then(|ctx| ctx.arrangement.loop_point.as_ref().map(|lp| (lp.start, lp.end)))
+    }
+
+    pub fn pause(&self) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut()
+            && ctx.state == PlaybackState::Playing {
+            ctx.state = PlaybackState::Paused;
         }
     }
 
-    pub fn set_track_enabled(&self, track_name: &str, enabled: bool) {
-        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            ctx.dynamic_params.track_enabled.insert(track_name.to_string(), enabled);
+    pub fn resume(&self) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut()
+            && ctx.state == PlaybackState::Paused {
+            ctx.state = PlaybackState::Playing;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(stream) = self.stream.take() { drop(stream); }
+        *self.playback_context.lock().unwrap() = None;
+    }
+
+    // Calls `cb` each time a non-looping arrangement plays to its end (not on stop()), replacing any
+    // earlier callback. The audio thread only posts a wake-up, `cb` runs on a watcher thread of its own.
+    pub fn on_finished(&mut self, mut cb: Box<dyn FnMut() + Send>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let watcher = std::thread::Builder::new()
+            .name("boomie-finished".to_string())
+            .spawn(move || {
+                // Ends once the sender is replaced or the engine dropped
+                for () in rx { cb(); }
+            });
+        match watcher {
+            Ok(_) => *self.finished.lock().unwrap() = Some(tx),
+            Err(e) => eprintln!("Warning: couldn't start the on_finished watcher thread: {}", e),
+        }
+    }
+
+    // Most recent error from the audio stream since it was started, e.g. to re-open a disconnected device.
+    // Playback that stopped on a zero-length arrangement is reported here too.
+    pub fn last_stream_error(&self) -> Option<SynthError> {
+        if let Some(err) = self.stream_error.lock().unwrap().clone() { return Some(err); }
+        self.playback_context.lock().unwrap().as_ref()
+            .filter(|context| context.zero_length)
+            .map(|context| SynthError::AudioError(format!("arrangement '{}' has zero length, playback stopped", context.arrangement.name)))
+    }
+
+    // Opens a second output (e.g. performer headphones) rendered from the same transport as the main one.
+    // None picks the default device; the device must run at the main output's sample rate.
+    pub fn open_cue_output(&mut self, device_name: Option<&str>) -> Result<(), SynthError> {
+        let host = cpal::host_from_id(self.host_id)
+            .map_err(|e| SynthError::DeviceError(e.to_string()))?;
+        let device = match device_name {
+            Some(name) => host.output_devices()
+                .map_err(|e| SynthError::DeviceError(e.to_string()))?
+                .find(|d| d.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| SynthError::DeviceError(format!("Output device not found: {}", name)))?,
+            None => host.default_output_device()
+                .ok_or_else(|| SynthError::DeviceError("No output device".to_string()))?,
+        };
+        let config = device.default_output_config()
+            .map_err(|e| SynthError::DeviceError(e.to_string()))?
+            .config();
+        if config.sample_rate != self.stream_config.sample_rate {
+            return Err(SynthError::DeviceError(format!(
+                "Cue device runs at {} Hz, main output at {} Hz", config.sample_rate.0, self.stream_config.sample_rate.0)));
+        }
+
+        let channels = config.channels as usize;
+        let queue = Arc::clone(&self.cue_queue);
+        let stream_error = Arc::clone(&self.stream_error);
+        queue.clear();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                // Silence while the main output is stopped, paused or behind
+                for frame in data.chunks_mut(channels) {
+                    let s = queue.pop().unwrap_or(0.0);
+                    frame.iter_mut().for_each(|o| *o = s);
+                }
+            },
+            Self::stream_error_handler(stream_error, "Cue stream"),
+            None,
+        ).map_err(|e| SynthError::AudioError(e.to_string()))?;
+        stream.play().map_err(|e| SynthError::AudioError(e.to_string()))?;
+        self.cue_stream = Some(stream);
+
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.cue = Some(CueRender::new(&ctx.arrangement, self.sample_rate, self.cue_mix.lock().unwrap().clone()));
+            if let Some(cf) = ctx.crossfade_state.as_mut() {
+                cf.cue_live = Some(LiveState::new(&cf.target_arrangement, self.sample_rate));
+            }
+        }
+        Ok(())
+    }
+
+    // Captures the live output (mono mix of what the device gets, live tweaks included) from here on,
+    // up to `max_seconds`. Only frames rendered while playing are kept; restarting discards a recording in progress.
+    pub fn start_recording(&self, max_seconds: f32) {
+        let recording = Recording::new((max_seconds.max(0.0) * self.sample_rate) as usize);
+        *self.recording.lock().unwrap() = Some(recording);
+    }
+
+    // Ends the recording and hands back what was captured, empty if none was running
+    pub fn stop_recording(&self) -> Vec<f32> {
+        self.recording.lock().unwrap().take().map_or_else(Vec::new, |rec| rec.buffer)
+    }
+
+    pub fn close_cue_output(&mut self) {
+        self.cue_stream = None;
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.cue = None;
+        }
+        self.cue_queue.clear();
+    }
+
+    // Cue mix settings persist across arrangements, unlike the main mix's dynamic parameters
+    pub fn set_cue_track_enabled(&self, track_name: &str, enabled: bool) {
+        self.update_cue_mix(|mix| { mix.track_enabled.insert(track_name.to_string(), enabled); });
+    }
+
+    pub fn set_cue_metronome(&self, enabled: bool, level: f32) {
+        self.update_cue_mix(|mix| {
+            mix.metronome = enabled;
+            mix.metronome_level = level.clamp(0.0, 1.0);
+        });
+    }
+
+    pub fn set_cue_volume(&self, volume: f32) {
+        self.update_cue_mix(|mix| mix.volume = volume.clamp(0.0, 2.0));
+    }
+
+    // Applies a change to the stored cue mix and to the one the callback is rendering with
+    fn update_cue_mix(&self, change: impl Fn(&mut CueMix)) {
+        change(&mut self.cue_mix.lock().unwrap());
+        if let Some(cue) = self.playback_context.lock().unwrap().as_mut().and_then(|ctx| ctx.cue.as_mut()) {
+            change(&mut cue.mix);
+        }
+    }
+
+    pub fn set_master_volume(&self, volume: f32) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.param_ramps.remove(&RampTarget::MasterVolume);
+            ctx.dynamic_params.master_volume = volume.clamp(0.0, 2.0);
+        }
+    }
+
+    // Peak-following gain on the live output so hot arrangements don't clip (offline renders normalize instead)
+    pub fn set_auto_gain(&self, enabled: bool) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.auto_gain = if enabled { Some(AutoGain::new(self.sample_rate)) } else { None };
+        }
+    }
+
+    // Held output peak and whether anything hit 1.0 since the last reset_clip_indicator()
+    pub fn get_peak_hold(&self) -> (f32, bool) {
+        self.playback_context.lock().unwrap().as_ref()
+            .map_or((0.0, false), |ctx| (ctx.meter.peak(), ctx.meter.clipped()))
+    }
+
+    pub fn reset_clip_indicator(&self) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.meter.reset_clip();
+        }
+    }
+
+    // How long a new peak is held before the meter starts falling
+    pub fn set_peak_hold_time(&self, seconds: f32) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.meter.set_hold(seconds);
+        }
+    }
+
+    pub fn set_master_pitch(&self, pitch: f32) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.param_ramps.remove(&RampTarget::MasterPitch);
+            ctx.dynamic_params.master_pitch = pitch.clamp(0.5, 2.0);
+        }
+    }
+
+    pub fn set_master_width(&self, width: f32) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.dynamic_params.master_width = width.clamp(0.0, 2.0);
+        }
+    }
+
+    // Caps simultaneously sounding voices (across tracks and chord pitches), the oldest are stolen
+    pub fn set_max_polyphony(&self, max_voices: usize) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.max_polyphony = Some(max_voices);
+        }
+    }
+
+    pub fn set_track_enabled(&self, track_name: &str, enabled: bool) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.dynamic_params.track_enabled.insert(track_name.to_string(), enabled);
+            // A finished fade out holds its gain at zero, turning the track back on clears it
+            if !ctx.track_fades.contains_key(track_name) {
+                ctx.dynamic_params.track_fade.remove(track_name);
+            }
+        }
+    }
+
+    // Monitor only the wet (delay/reverb) return of a track's effects while dialing them in
+    pub fn set_effects_solo(&self, track_name: &str, wet_only: bool) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            ctx.dynamic_params.track_wet_only.insert(track_name.to_string(), wet_only);
         }
     }
 
     pub fn set_track_volume(&self, track_name: &str, volume: f32) {
         if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            ctx.dynamic_params.track_volumes.insert(track_name.to_string(), volume.max(0.0).min(2.0));
+            ctx.param_ramps.remove(&RampTarget::TrackVolume(track_name.to_string()));
+            ctx.dynamic_params.track_volumes.insert(track_name.to_string(), volume.clamp(0.0, 2.0));
+        }
+    }
+
+    // Ramps a track to silence (then disables it) or back in from silence over `duration` seconds,
+    // so muting mid-note doesn't click. Starts from wherever a previous fade left off.
+    pub fn fade_track(&self, track_name: &str, target_enabled: bool, duration: f32) {
+        let mut ctx_lock = self.playback_context.lock().unwrap();
+        let Some(ctx) = ctx_lock.as_mut() else { return };
+        let was_enabled = ctx.dynamic_params.track_enabled.get(track_name).copied().unwrap_or(true);
+        let gain = match ctx.dynamic_params.track_fade.get(track_name) {
+            Some(&gain) if ctx.track_fades.contains_key(track_name) => gain,
+            _ if was_enabled => 1.0,
+            _ => 0.0,
+        };
+        let samples = duration * self.sample_rate;
+        if samples < 1.0 {
+            ctx.track_fades.remove(track_name);
+            ctx.dynamic_params.track_fade.remove(track_name);
+            ctx.dynamic_params.track_enabled.insert(track_name.to_string(), target_enabled);
+            return;
         }
+        // Fading in needs the track audible from the first step
+        ctx.dynamic_params.track_enabled.insert(track_name.to_string(), true);
+        ctx.dynamic_params.track_fade.insert(track_name.to_string(), gain);
+        let step = if target_enabled { 1.0 / samples } else { -1.0 / samples };
+        ctx.track_fades.insert(track_name.to_string(), TrackFade { step, target_enabled });
     }
 
+    // Glides a track's volume to `target` over `duration` seconds, from wherever it is now (mid-glide included)
     pub fn interpolate_track_volume(&self, track_name: &str, target: f32, duration: f32) {
-        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
-            let key = format!("vol_{}", track_name);
-            ctx.param_interpolators.insert(key, duration);
-            ctx.dynamic_params.track_volumes.insert(track_name.to_string(), target);
+        self.start_ramp(RampTarget::TrackVolume(track_name.to_string()), target.clamp(0.0, 2.0), duration);
+    }
+
+    pub fn interpolate_master_volume(&self, target: f32, duration: f32) {
+        self.start_ramp(RampTarget::MasterVolume, target.clamp(0.0, 2.0), duration);
+    }
+
+    // Notes bend along with it, phases stay continuous
+    pub fn interpolate_master_pitch(&self, target: f32, duration: f32) {
+        self.start_ramp(RampTarget::MasterPitch, target.clamp(0.5, 2.0), duration);
+    }
+
+    fn start_ramp(&self, target: RampTarget, value: f32, duration: f32) {
+        let mut ctx_lock = self.playback_context.lock().unwrap();
+        let Some(ctx) = ctx_lock.as_mut() else { return };
+        // The track's volume entry is made here, so the callback only ever writes to it in place
+        let start_value = match &target {
+            RampTarget::TrackVolume(name) => *ctx.dynamic_params.track_volumes.entry(name.clone()).or_insert(1.0),
+            RampTarget::MasterVolume => ctx.dynamic_params.master_volume,
+            RampTarget::MasterPitch => ctx.dynamic_params.master_pitch,
+        };
+        let samples_total = (duration.max(0.0) * self.sample_rate) as usize;
+        if samples_total == 0 {
+            ctx.param_ramps.remove(&target);
+            Self::set_ramped(&mut ctx.dynamic_params, &target, value);
+            return;
+        }
+        ctx.param_ramps.insert(target, ParamRamp { start_value, target_value: value, samples_total, samples_done: 0 });
+    }
+
+    fn set_ramped(params: &mut DynamicParameters, target: &RampTarget, value: f32) {
+        match target {
+            RampTarget::TrackVolume(name) => if let Some(volume) = params.track_volumes.get_mut(name) { *volume = value },
+            RampTarget::MasterVolume => params.master_volume = value,
+            RampTarget::MasterPitch => params.master_pitch = value,
         }
     }
 
@@ -254,6 +1217,27 @@ impl SynthEngine {
         }
     }
 
+    // Moves the playhead to `seconds`, clamped to the arrangement. Effect tails, noise and bus
+    // compressor state start over so nothing from the old position rings on at the new one.
+    pub fn seek(&self, seconds: f32) {
+        if let Some(ctx) = self.playback_context.lock().unwrap().as_mut() {
+            let total = (ctx.arrangement.total_length * self.sample_rate) as usize;
+            ctx.current_sample = ((seconds.max(0.0) * self.sample_rate) as usize).min(total);
+            ctx.live = LiveState::new(&ctx.arrangement, self.sample_rate);
+            if let Some(cue) = ctx.cue.as_mut() {
+                cue.live = LiveState::new(&ctx.arrangement, self.sample_rate);
+            }
+            ctx.bus_compressor = BusCompressor::new(self.sample_rate);
+        }
+    }
+
+    // Lyric line at the playhead (see Arrangement::lyric_at), owned since it lives behind the playback lock
+    pub fn current_lyric(&self) -> Option<String> {
+        let ctx_lock = self.playback_context.lock().unwrap();
+        let ctx = ctx_lock.as_ref()?;
+        ctx.arrangement.lyric_at(ctx.current_sample as f32 / self.sample_rate).map(str::to_string)
+    }
+
     pub fn get_playback_state(&self) -> PlaybackState {
         if let Some(ctx) = self.playback_context.lock().unwrap().as_ref() {
             ctx.state
@@ -263,12 +1247,22 @@ impl SynthEngine {
     }
 
     fn start_stream(&mut self) -> Result<(), SynthError> {
-        let host = cpal::default_host();
+        let host = cpal::host_from_id(self.host_id)
+            .map_err(|e| SynthError::DeviceError(e.to_string()))?;
         let device = host.default_output_device()
-            .ok_or_else(|| SynthError::AudioError("No output device".to_string()))?;
+            .ok_or_else(|| SynthError::DeviceError("No output device".to_string()))?;
         let config = self.stream_config.clone();
         let sample_rate = self.sample_rate;
         let ctx = Arc::clone(&self.playback_context);
+        let stream_error = Arc::clone(&self.stream_error);
+        let cue_queue = Arc::clone(&self.cue_queue);
+        let recording = Arc::clone(&self.recording);
+        let finished = Arc::clone(&self.finished);
+        // try_send never blocks the audio thread, a wake-up the watcher hasn't taken yet covers this one
+        let notify_finished = move || {
+            if let Some(tx) = finished.lock().unwrap().as_ref() { let _ = tx.try_send(()); }
+        };
+        *stream_error.lock().unwrap() = None;
 
         let stream = device.build_output_stream(
             &config,
@@ -279,56 +1273,72 @@ impl SynthEngine {
                         for s in data.iter_mut() { *s = 0.0; }
                         return;
                     }
+                    if context.stop_if_empty(sample_rate) {
+                        notify_finished();
+                        for s in data.iter_mut() { *s = 0.0; }
+                        return;
+                    }
+                    let grid = Self::metronome_grid(&context.arrangement);
+                    let mut recording = recording.lock().unwrap();
+
                     for frame in data.chunks_mut(config.channels as usize) {
-                        let mut output = Self::synthesize_single_sample(
-                            &context.arrangement,
-                            context.current_sample,
-                            sample_rate,
-                            &context.dynamic_params,
-                        );
-                        if let Some(ref mut cf) = context.crossfade_state {
-                            let t = cf.progress / cf.duration_samples as f32;
-                            let tgt = Self::synthesize_single_sample(
-                                &cf.target_arrangement,
+                        if !context.param_ramps.is_empty() {
+                            let params = &mut context.dynamic_params;
+                            context.param_ramps.retain(|target, ramp| {
+                                Self::set_ramped(params, target, ramp.step());
+                                !ramp.done()
+                            });
+                        }
+                        context.step_track_fades();
+                        if let Some(cue) = context.cue.as_mut() {
+                            // Main params with the cue's track enables on top
+                            let (left, right) = Self::synthesize_single_sample(
+                                &context.arrangement,
                                 context.current_sample,
                                 sample_rate,
                                 &context.dynamic_params,
+                                Some(&cue.mix.track_enabled),
+                                &mut cue.live,
+                                context.max_polyphony,
                             );
-                            output = output * (1.0 - t) + tgt * t;
-                            cf.progress += 1.0;
-                            if cf.progress >= cf.duration_samples as f32 {
-                                context.arrangement = cf.target_arrangement.clone();
-                                context.crossfade_state = None;
-                            }
+                            let program = (left + right) * 0.5;
+                            let time = context.current_sample as f32 / sample_rate;
+                            cue_queue.push(Self::cue_sample(program * cue.mix.volume, time, grid, &cue.mix, &mut cue.limiter));
                         }
-                        context.current_sample += 1;
-                        if context.loop_enabled {
-                            if let Some(ref lp) = context.arrangement.loop_point {
-                                let pos = context.current_sample as f32 / sample_rate;
-                                if pos >= lp.end {
-                                    context.current_sample = (lp.start * sample_rate) as usize;
-                                }
-                            } else {
-                                let total = (context.arrangement.total_length * sample_rate) as usize;
-                                if context.current_sample >= total { context.current_sample = 0; }
-                            }
-                        } else {
-                            let total = (context.arrangement.total_length * sample_rate) as usize;
-                            if context.current_sample >= total { context.state = PlaybackState::Stopped; }
+                        let (mut out_left, mut out_right) = Self::synthesize_single_sample(
+                            &context.arrangement,
+                            context.current_sample,
+                            sample_rate,
+                            &context.dynamic_params,
+                            None,
+                            &mut context.live,
+                            context.max_polyphony,
+                        );
+                        (out_left, out_right) = context.crossfade(out_left, out_right, sample_rate);
+                        if context.advance_playhead(sample_rate) {
+                            notify_finished();
                         }
                         let cur_t = context.current_sample as f32 / sample_rate;
                         let tot   = context.arrangement.total_length;
                         let mut fade = 1.0f32;
-                        if let Some(fi) = context.arrangement.fade_in  { if cur_t < fi { fade *= cur_t / fi; } }
-                        if let Some(fo) = context.arrangement.fade_out { let fs = tot - fo; if cur_t > fs { fade *= (tot - cur_t) / fo; } }
-                        let final_out = output * context.dynamic_params.master_volume * fade;
-                        for s in frame.iter_mut() { *s = final_out; }
+                        if let Some(fi) = context.arrangement.fade_in && cur_t < fi { fade *= context.arrangement.fade_curve.apply(cur_t / fi); }
+                        if let Some(fo) = context.arrangement.fade_out && cur_t > tot - fo { fade *= context.arrangement.fade_curve.apply((tot - cur_t) / fo); }
+                        let volume = context.dynamic_params.master_volume;
+                        let (mut left, mut right) = stereo_width(out_left * volume, out_right * volume, context.dynamic_params.master_width);
+                        if let Some(comp) = &context.arrangement.compressor {
+                            (left, right) = context.bus_compressor.process_stereo(left, right, comp);
+                        }
+                        (left, right) = (left * fade, right * fade);
+                        if let Some(agc) = context.auto_gain.as_mut() { (left, right) = agc.process_stereo(left, right); }
+                        context.meter.process(left.abs().max(right.abs()));
+                        if let Some(rec) = recording.as_mut() { rec.push((left + right) * 0.5); }
+                        Self::write_frame(frame, left, right);
                     }
                 } else {
                     for s in data.iter_mut() { *s = 0.0; }
                 }
             },
-            |err| eprintln!("Stream error: {}", err),
+            Self::stream_error_handler(stream_error, "Stream"),
             None,
         ).map_err(|e| SynthError::AudioError(e.to_string()))?;
 
@@ -337,226 +1347,2005 @@ impl SynthEngine {
         Ok(())
     }
 
+    // One interleaved output frame: mono folds down, extra channels repeat the left/right pair
+    fn write_frame(frame: &mut [f32], left: f32, right: f32) {
+        if frame.len() == 1 {
+            frame[0] = (left + right) * 0.5;
+        } else {
+            for (ch, s) in frame.iter_mut().enumerate() { *s = if ch % 2 == 0 { left } else { right }; }
+        }
+    }
+
+    // cpal error callback: keeps the error for last_stream_error
+    fn stream_error_handler(slot: Arc<Mutex<Option<SynthError>>>, label: &'static str) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        move |err| {
+            eprintln!("{} error: {}", label, err);
+            *slot.lock().unwrap() = Some(SynthError::AudioError(err.to_string()));
+        }
+    }
+
+    // Beat length and beats per bar the metronome follows, from the first track
+    fn metronome_grid(arrangement: &Arrangement) -> (f32, u32) {
+        arrangement.tracks.first()
+            .map_or((0.5, 4), |(track, _, _)| (track.beat_duration(), track.time_signature.0.max(1)))
+    }
+
+    // Short decaying blip on every beat, higher on the downbeat
+    fn metronome_click(time: f32, beat_dur: f32, beats_per_bar: u32) -> f32 {
+        if beat_dur <= 0.0 || time < 0.0 { return 0.0; }
+        let beat = (time / beat_dur).floor();
+        let t = time - beat * beat_dur;
+        if t >= CLICK_LENGTH { return 0.0; }
+        let freq = if (beat as u32).is_multiple_of(beats_per_bar) { 1500.0 } else { 1000.0 };
+        let env = 1.0 - t / CLICK_LENGTH;
+        (std::f32::consts::TAU * freq * t).sin() * env * env
+    }
+
+    // One cue output sample: program plus click (the main mix never gets the click), through the cue limiter
+    fn cue_sample(program: f32, time: f32, grid: (f32, u32), mix: &CueMix, limiter: &mut AutoGain) -> f32 {
+        let click = if mix.metronome { Self::metronome_click(time, grid.0, grid.1) * mix.metronome_level } else { 0.0 };
+        limiter.process(program + click)
+    }
+
+    // One (left, right) frame of the live mix
     fn synthesize_single_sample(
         arrangement: &Arrangement,
         sample_idx: usize,
         sample_rate: f32,
         params: &DynamicParameters,
-    ) -> f32 {
-        let mut output = 0.0;
+        enabled_override: Option<&HashMap<String, bool>>, // Track enables taking precedence over params', for the cue mix
+        live: &mut LiveState,
+        max_polyphony: Option<usize>,
+    ) -> (f32, f32) {
+        let track_enabled = |name: &String| enabled_override.and_then(|o| o.get(name))
+            .or_else(|| params.track_enabled.get(name))
+            .copied()
+            .unwrap_or(true);
         let current_time = sample_idx as f32 / sample_rate;
-        for (track, start_time, overrides) in &arrangement.tracks {
-            let enabled = params.track_enabled.get(&track.name).copied().unwrap_or(true);
-            if !enabled { continue; }
-            let track_vol = params.track_volumes.get(&track.name).copied().unwrap_or(1.0);
+        let clock = live.pitch_clock.now();
+        let voices = &mut live.voices;
+        voices.clear();
+
+        for (i, (track, start_time, overrides)) in arrangement.tracks.iter().enumerate() {
+            if !track_enabled(&track.name) { continue; }
+            let track_vol = params.track_volumes.get(&track.name).copied().unwrap_or(1.0)
+                * params.track_fade.get(&track.name).copied().unwrap_or(1.0);
             if current_time < *start_time { continue; }
             let track_time = current_time - start_time;
+            let gate = track.gate.as_ref().map_or(1.0, |g| g.gain(track_time, track.beat_duration()));
             let mut cum = 0.0;
-            let beat_dur = 60.0 / track.tempo;
-            for element in &track.sequence {
+            let mut beat_dur = track.beat_duration();
+            for (n, element) in track.sequence.iter().enumerate() {
+                // Nothing further on can have started yet, even pulled early
+                if cum > track_time + MAX_NOTE_SHIFT { break; }
                 match element {
                     SequenceElement::Note(note) => {
-                        let nd = note.duration * beat_dur;
+                        let nd = element.seconds(beat_dur);
                         let next = cum + nd;
-                        if track_time >= cum && track_time < next {
-                            let t = track_time - cum;
-                            let env = Self::calculate_envelope_static(t, nd, &track.instrument);
+                        // A shifted note can overlap its neighbours, both sound through the overlap
+                        let onset = cum + note.shift.clamp(-MAX_NOTE_SHIFT, MAX_NOTE_SHIFT);
+                        // Drum hits ring out for the whole sample, whatever the note length
+                        let drum = track.instrument.drum_sample(note.pitch);
+                        let len = drum.map_or(note.voice_seconds(nd, &track.instrument), |sd| sd.duration(track.instrument.pitch * params.master_pitch));
+                        if track_time >= onset && track_time < onset + len {
+                            let t = track_time - onset;
+                            let env = Self::calculate_envelope_static(t, len, &track.instrument, note.release.unwrap_or(track.instrument.release));
                             let mut pitch = note.pitch;
                             if let Some(st) = note.slide_to {
-                                pitch = note.pitch * (1.0 - t / nd) + st * (t / nd);
+                                let s = (t / nd).min(1.0);
+                                pitch = note.pitch * (1.0 - s) + st * s;
                             }
-                            let sample = match &track.instrument.source {
-                                InstrumentSource::Synthesized(wf) => wf.generate_sample((track_time * pitch * params.master_pitch) % 1.0),
-                                InstrumentSource::Sample(sd)      => Self::interpolate_sample(sd, t, track.instrument.pitch * params.master_pitch),
+                            if let Some(Some(fx)) = live.track_fx.get_mut(i) {
+                                fx.set_cutoff_scale(track.instrument.keytrack_scale(pitch * params.master_pitch));
+                                fx.set_note_time(t, len);
+                            }
+                            let vibrato = &track.instrument.vibrato;
+                            let unison = track.instrument.unison_ratios();
+                            let (sample, image) = if let Some(wf) = track.instrument.note_waveform(note.wave) {
+                                let mut sum = 0.0;
+                                for (u, ratio) in unison.clone().enumerate() {
+                                    let cycles = (clock + vibrato.time_offset(t) * params.master_pitch) * pitch * ratio;
+                                    let mut scratch = NoiseState::default();
+                                    let noise = LiveState::noise_state(&mut live.noise, i, n, u, t * sample_rate < 1.0).unwrap_or(&mut scratch);
+                                    let inc = pitch * ratio * params.master_pitch * vibrato.pitch_ratio(t) / sample_rate;
+                                    sum += wf.generate_sample_stateful(cycles % 1.0, inc, &mut live.rng, noise) + track.instrument.sub_sample_with((cycles * track.instrument.sub_ratio()) % 1.0, &mut live.rng);
+                                }
+                                (sum / unison.len() as f32, None)
+                            } else if let InstrumentSource::Sample(sd) = &track.instrument.source {
+                                let played = live.pitch_clock.voice_time((i, n, 0), t, params.master_pitch) + vibrato.time_offset(t) * params.master_pitch;
+                                let mono = unison.clone()
+                                    .map(|ratio| Self::interpolate_sample(sd, played, track.instrument.pitch * ratio))
+                                    .sum::<f32>() / unison.len() as f32;
+                                (mono, Self::interpolate_image(sd, played, track.instrument.pitch, unison.clone()))
+                            } else if let Some(sd) = drum {
+                                (Self::interpolate_sample(sd, live.pitch_clock.voice_time((i, n, 0), t, params.master_pitch), track.instrument.pitch), None)
+                            } else {
+                                (0.0, None)
                             };
-                            let vol = track.instrument.volume * overrides.volume.unwrap_or(1.0) * track_vol;
-                            output += sample * env * note.velocity * vol;
-                            break;
+                            let vol = track.instrument.volume * overrides.volume.unwrap_or(1.0) * track_vol * gate;
+                            let pan = track.instrument.note_pan(note.pan.or(overrides.pan), &mut Self::voice_rng(i, n));
+                            let scale = env * note.velocity * vol;
+                            voices.push(LiveVoice { track: i, onset: start_time + onset, element: n, pitch: note.pitch, pan, value: sample * scale, image: image.map(|(l, r)| (l * scale, r * scale)) });
                         }
                         cum = next;
                     }
                     SequenceElement::Chord(chord) => {
-                        let cd = chord.duration * beat_dur;
+                        let cd = element.seconds(beat_dur);
                         let next = cum + cd;
-                        if track_time >= cum && track_time < next {
+                        if let InstrumentSource::DrumMap(_) = &track.instrument.source {
+                            // Every pitch is its own hit, layered at full velocity
+                            let rate = track.instrument.pitch * params.master_pitch;
+                            let pan = track.instrument.note_pan(overrides.pan, &mut Self::voice_rng(i, n));
+                            for (k, &pitch) in chord.pitches.iter().enumerate() {
+                                let Some(sd) = track.instrument.drum_sample(pitch) else { continue };
+                                let len = sd.duration(rate);
+                                if track_time >= cum && track_time < cum + len {
+                                    let t = track_time - cum;
+                                    let env = Self::calculate_envelope_static(t, len, &track.instrument, chord.release.unwrap_or(track.instrument.release));
+                                    let vol = track.instrument.volume * overrides.volume.unwrap_or(1.0) * track_vol * gate;
+                                    voices.push(LiveVoice { track: i, onset: start_time + cum, element: n, pitch, pan, value: Self::interpolate_sample(sd, live.pitch_clock.voice_time((i, n, k), t, params.master_pitch), track.instrument.pitch) * env * chord.velocity * vol, image: None });
+                                }
+                            }
+                        } else if track_time >= cum && track_time < next {
                             let t = track_time - cum;
-                            let env = Self::calculate_envelope_static(t, cd, &track.instrument);
-                            for pitch in &chord.pitches {
-                                let sample = match &track.instrument.source {
-                                    InstrumentSource::Synthesized(wf) => wf.generate_sample((track_time * pitch * params.master_pitch) % 1.0),
-                                    InstrumentSource::Sample(sd)      => Self::interpolate_sample(sd, t, track.instrument.pitch * params.master_pitch),
+                            let env = Self::calculate_envelope_static(t, cd, &track.instrument, chord.release.unwrap_or(track.instrument.release));
+                            if let Some(Some(fx)) = live.track_fx.get_mut(i) {
+                                let root = chord.pitches.iter().copied().fold(f32::INFINITY, f32::min);
+                                fx.set_cutoff_scale(track.instrument.keytrack_scale(root * params.master_pitch));
+                                fx.set_note_time(t, cd);
+                            }
+                            let pan = track.instrument.note_pan(overrides.pan, &mut Self::voice_rng(i, n));
+                            let vibrato = &track.instrument.vibrato;
+                            let unison = track.instrument.unison_ratios();
+                            for (k, pitch) in chord.pitches.iter().enumerate() {
+                                let (sample, image) = match &track.instrument.source {
+                                    InstrumentSource::Synthesized(wf) => {
+                                        let mut sum = 0.0;
+                                        for (u, ratio) in unison.clone().enumerate() {
+                                            let cycles = (clock + vibrato.time_offset(t) * params.master_pitch) * pitch * ratio;
+                                            let mut scratch = NoiseState::default();
+                                            let noise = LiveState::noise_state(&mut live.noise, i, n, k * unison.len() + u, t * sample_rate < 1.0).unwrap_or(&mut scratch);
+                                            let inc = pitch * ratio * params.master_pitch * vibrato.pitch_ratio(t) / sample_rate;
+                                            sum += wf.generate_sample_stateful(cycles % 1.0, inc, &mut live.rng, noise) + track.instrument.sub_sample_with((cycles * track.instrument.sub_ratio()) % 1.0, &mut live.rng);
+                                        }
+                                        (sum / unison.len() as f32, None)
+                                    }
+                                    InstrumentSource::Sample(sd)      => {
+                                        let played = live.pitch_clock.voice_time((i, n, k), t, params.master_pitch) + vibrato.time_offset(t) * params.master_pitch;
+                                        let mono = unison.clone()
+                                            .map(|ratio| Self::interpolate_sample(sd, played, track.instrument.pitch * ratio))
+                                            .sum::<f32>() / unison.len() as f32;
+                                        (mono, Self::interpolate_image(sd, played, track.instrument.pitch, unison.clone()))
+                                    }
+                                    InstrumentSource::DrumMap(_)      => (0.0, None),
                                 };
-                                let vol = track.instrument.volume * overrides.volume.unwrap_or(1.0) * track_vol;
-                                output += sample * env * chord.velocity * vol / chord.pitches.len() as f32;
+                                let vol = track.instrument.volume * overrides.volume.unwrap_or(1.0) * track_vol * gate;
+                                let scale = env * chord.velocity * vol / chord.pitches.len() as f32;
+                                voices.push(LiveVoice { track: i, onset: start_time + cum, element: n, pitch: *pitch, pan, value: sample * scale, image: image.map(|(l, r)| (l * scale, r * scale)) });
                             }
-                            break;
                         }
                         cum = next;
                     }
                     SequenceElement::Rest(d) => { cum += d * beat_dur; }
+                    SequenceElement::TempoChange(tempo) => { beat_dur = track.beat_duration_at(*tempo); }
+                }
+            }
+        }
+
+        match max_polyphony {
+            Some(max) => Self::steal_voices(voices, &mut live.stolen, max, current_time),
+            None => live.stolen.clear(),
+        }
+
+        let (mut out_left, mut out_right) = (0.0, 0.0);
+        for (i, (track, start_time, _)) in arrangement.tracks.iter().enumerate() {
+            if !track_enabled(&track.name) || current_time < *start_time { continue; }
+            let (mut left, mut right) = (0.0f32, 0.0f32);
+            for v in voices.iter().filter(|v| v.track == i) {
+                let (l, r) = match v.image {
+                    Some((l, r)) => stereo_balance(l, r, v.pan),
+                    None => {
+                        let (gl, gr) = pan_gains(v.pan);
+                        (v.value * gl, v.value * gr)
+                    }
+                };
+                left += l;
+                right += r;
+            }
+            if let Some(ceiling) = track.instrument.clip_ceiling {
+                (left, right) = (soft_clip(left, ceiling), soft_clip(right, ceiling));
+            }
+            let wet_only = params.track_wet_only.get(&track.name).copied().unwrap_or(false);
+            // Runs during rests too (silence there), so tails aren't cut
+            if let Some(Some(fx)) = live.track_fx.get_mut(i) {
+                let run = |processor: &mut EffectsProcessor, input: f32, chain: &EffectsChain| {
+                    if wet_only { processor.process_wet(input, chain) } else { processor.process(input, chain) }
+                };
+                if let (Some(processor), Some(delay)) = (fx.right.as_mut(), fx.chain.delay.as_ref().filter(|d| d.ping_pong)) {
+                    ping_pong(&mut fx.processor, processor, delay);
+                }
+                left = run(&mut fx.processor, left, &fx.chain);
+                right = match fx.right.as_mut() {
+                    Some(processor) => run(processor, right, &fx.chain),
+                    None => left,
+                };
+                if let (Some(processor), Some(reverb)) = (fx.right.as_ref(), fx.chain.reverb.as_ref()) {
+                    (left, right) = reverb_width(left, right, (fx.processor.reverb_tail(), processor.reverb_tail()), reverb.width);
                 }
+            } else if wet_only {
+                // No effects, so there's no return to hear
+                (left, right) = (0.0, 0.0);
             }
+            out_left += left;
+            out_right += right;
+        }
+        live.pitch_clock.advance(params.master_pitch, sample_rate);
+        (out_left, out_right)
+    }
+
+    // Draws for one live voice's pan_spread, the same on every sample of the note
+    fn voice_rng(track: usize, element: usize) -> fastrand::Rng {
+        fastrand::Rng::with_seed(((track as u64) << 32) | element as u64)
+    }
+
+    // Keeps the newest `max` voices. Each older one is stolen once: it fades out over VOICE_STEAL_RELEASE
+    // from the sample it was taken on, so stealing doesn't click, and stays silent even after other voices end.
+    fn steal_voices(voices: &mut [LiveVoice], stolen: &mut Vec<(VoiceKey, f32)>, max: usize, now: f32) {
+        stolen.retain(|(key, _)| voices.iter().any(|v| v.key() == *key));
+        if stolen.is_empty() && voices.len() <= max { return; }
+        voices.sort_by(LiveVoice::allocation_order);
+        let mut kept = 0;
+        for v in voices.iter_mut() {
+            let stolen_at = match stolen.iter().find(|(key, _)| *key == v.key()) {
+                Some(&(_, at)) => at,
+                None if kept < max => { kept += 1; continue; }
+                None => { stolen.push((v.key(), now)); now }
+            };
+            let fade = (1.0 - (now - stolen_at) / VOICE_STEAL_RELEASE).max(0.0);
+            v.value *= fade;
+            if let Some((left, right)) = v.image.as_mut() { (*left, *right) = (*left * fade, *right * fade); }
+        }
+    }
+
+    // Renders one note of `instrument` (envelope, pre-filters and effects) for auditioning a patch.
+    // The buffer runs past `duration` so release and effect tails are included.
+    pub fn preview_instrument(&self, instrument: &Instrument, pitch: f32, duration: f32) -> Vec<f32> {
+        let track = MelodyTrack {
+            name: "preview".to_string(),
+            instrument: instrument.clone(),
+            sequence: vec![SequenceElement::Note(Note {
+                pitch,
+                duration,
+                in_seconds: true,
+                velocity: 1.0,
+                pan: None,
+                slide_to: None,
+                release: None,
+                shift: 0.0,
+                wave: None,
+                overlap: false,
+            })],
+            tempo: 120.0,
+            length: 0.0,
+            loop_point: None,
+            time_signature: (4, 4),
+            swing: 0.0,
+            gate: None,
+        };
+
+        let total = ((duration + instrument.effects.tail_seconds()) * self.sample_rate) as usize;
+        let mut buffer = vec![0.0f32; total];
+        self.synthesize_track_into(&mut buffer, &track, 0, &mut fastrand::Rng::with_seed(self.render_seed));
+        if instrument.effects.has_any() {
+            let mut fx = EffectsProcessor::for_chain(self.sample_rate, &instrument.effects);
+            for s in buffer.iter_mut() { *s = fx.process(*s, &instrument.effects); }
+        }
+        buffer
+    }
+
+    // 16-bit PCM WAV of the normalized mono render, clamped to [-1, 1]
+    pub fn export_wav(&self, arrangement: &Arrangement, path: &str) -> Result<(), SynthError> {
+        self.export_wav_with_format(arrangement, path, WavFormat::Pcm16)
+    }
+
+    // Renders and writes a mono WAV at the engine's sample rate
+    pub fn export_wav_with_format(&self, arrangement: &Arrangement, path: &str, format: WavFormat) -> Result<(), SynthError> {
+        let buffer = match format {
+            WavFormat::Pcm16 => self.synthesize_arrangement(arrangement)?,
+            WavFormat::Float32 => self.synthesize_arrangement_raw(arrangement)?,
+        };
+        self.write_wav(path, &buffer, 1, format)
+    }
+
+    // 16-bit PCM stereo WAV of synthesize_arrangement_stereo, pans applied
+    pub fn export_wav_stereo(&self, arrangement: &Arrangement, path: &str) -> Result<(), SynthError> {
+        let interleaved: Vec<f32> = self.synthesize_arrangement_stereo(arrangement)?
+            .into_iter()
+            .flat_map(|(l, r)| [l, r])
+            .collect();
+        self.write_wav(path, &interleaved, 2, WavFormat::Pcm16)
+    }
+
+    fn write_wav(&self, path: &str, interleaved: &[f32], channels: u16, format: WavFormat) -> Result<(), SynthError> {
+        let (bits, sample_format) = match format {
+            WavFormat::Pcm16 => (16, hound::SampleFormat::Int),
+            WavFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: bits,
+            sample_format,
+        };
+        let wav_error = |e: hound::Error| match e {
+            hound::Error::IoError(e) => SynthError::io(path, e),
+            e => SynthError::FileError(format!("'{}': {}", path, e)),
+        };
+
+        let mut writer = hound::WavWriter::create(path, spec).map_err(wav_error)?;
+        for &s in interleaved {
+            match format {
+                WavFormat::Pcm16 => writer.write_sample((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+                WavFormat::Float32 => writer.write_sample(s),
+            }.map_err(wav_error)?;
+        }
+        writer.finalize().map_err(wav_error)
+    }
+
+    // Logarithmic test sweep from start_hz to end_hz, handy for measuring filters and effects
+    pub fn render_sweep(&self, start_hz: f32, end_hz: f32, duration: f32, waveform: WaveformType) -> Vec<f32> {
+        let total = (duration * self.sample_rate) as usize;
+        let start_hz = start_hz.max(f32::MIN_POSITIVE);
+        let ratio = end_hz.max(f32::MIN_POSITIVE) / start_hz;
+        let mut phase = 0.0f32;
+        let mut out = Vec::with_capacity(total);
+        let mut rng = fastrand::Rng::with_seed(self.render_seed);
+        for i in 0..total {
+            let t = i as f32 / total as f32;
+            out.push(waveform.generate_sample_with(phase, &mut rng));
+            phase += start_hz * ratio.powf(t) / self.sample_rate;
+            phase -= phase.floor();
         }
-        output
+        out
     }
 
     pub fn synthesize_arrangement(&self, arrangement: &Arrangement) -> Result<Vec<f32>, SynthError> {
-        self.synthesize_arrangement_private(arrangement, &DynamicParameters::default())
+        self.synthesize_arrangement_private(arrangement, &DynamicParameters::default(), true)
     }
 
+    // Arrangement::duration_seconds at this engine's sample rate, rounded up
+    pub fn arrangement_samples(&self, arrangement: &Arrangement) -> usize {
+        (arrangement.duration_seconds() * self.sample_rate).ceil() as usize
+    }
+
+    // Overview waveform: the normalized mono render split into `buckets` (min, max) pairs for drawing.
+    // Renders shorter than `buckets` samples repeat samples so there are always `buckets` entries.
+    pub fn render_peaks(&self, arrangement: &Arrangement, buckets: usize) -> Result<Vec<(f32, f32)>, SynthError> {
+        let buffer = self.synthesize_arrangement(arrangement)?;
+        if buffer.is_empty() {
+            return Ok(vec![(0.0, 0.0); buckets]);
+        }
+        Ok((0..buckets)
+            .map(|b| {
+                let start = b * buffer.len() / buckets;
+                let end = ((b + 1) * buffer.len() / buckets).max(start + 1);
+                buffer[start..end].iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &s| (lo.min(s), hi.max(s)))
+            })
+            .collect())
+    }
+
+    // Same render without the final peak normalize: may exceed 1.0, but separately rendered stems sum to the mix.
+    pub fn synthesize_arrangement_raw(&self, arrangement: &Arrangement) -> Result<Vec<f32>, SynthError> {
+        self.synthesize_arrangement_private(arrangement, &DynamicParameters::default(), false)
+    }
+
+    // Renders `loops` passes into one buffer: the first plays up to the loop end (or the whole arrangement without
+    // a loop region), later ones repeat the loop region. Each seam overlaps by `crossfade` seconds (0 = butt splice).
+    // The fade-in only opens the first pass and the fade-out only closes the last.
+    pub fn synthesize_arrangement_looped(&self, arrangement: &Arrangement, loops: u32, crossfade: f32) -> Result<Vec<f32>, SynthError> {
+        let mut single = arrangement.clone();
+        single.fade_out = None;
+        let pass = self.synthesize_arrangement_private(&single, &DynamicParameters::default(), false)?;
+
+        let to_sample = |t: f32| ((t * self.sample_rate) as usize).min(pass.len());
+        // Whole passes repeat every total_length, the ring-out past it (if any) only closes the last one
+        let (start, end, ring_out) = match &arrangement.loop_point {
+            Some(lp) if to_sample(lp.end) > to_sample(lp.start) => (to_sample(lp.start), to_sample(lp.end), 0),
+            _ => (0, to_sample(arrangement.total_length), pass.len() - to_sample(arrangement.total_length)),
+        };
+
+        let mut buffer = pass[..end].to_vec();
+        let fade = (crossfade.max(0.0) * self.sample_rate) as usize;
+        for _ in 1..loops {
+            let repeat = &pass[start..end];
+            let overlap = fade.min(buffer.len()).min(repeat.len());
+            let seam = buffer.len() - overlap;
+            for (i, &s) in repeat[..overlap].iter().enumerate() {
+                let w = CurveShape::Cosine.apply((i + 1) as f32 / (overlap + 1) as f32);
+                buffer[seam + i] = buffer[seam + i] * (1.0 - w) + s * w;
+            }
+            buffer.extend_from_slice(&repeat[overlap..]);
+        }
+        buffer.extend_from_slice(&pass[end..end + ring_out]);
+        if loops == 0 { buffer.clear(); }
+
+        if let Some(fo) = arrangement.fade_out {
+            let n  = (fo * self.sample_rate) as usize;
+            let fs = buffer.len().saturating_sub(n);
+            for i in fs..buffer.len() { buffer[i] *= arrangement.fade_curve.apply((buffer.len() - i) as f32 / n as f32); }
+        }
+        if let Some(max) = buffer.iter().map(|v| v.abs()).max_by(|a, b| a.partial_cmp(b).unwrap())
+            && max > 1.0 {
+            buffer.iter_mut().for_each(|s| *s /= max);
+        }
+        Ok(buffer)
+    }
+
+    // Stereo render, normalized like synthesize_arrangement: per-note and per-track pans through the pan law,
+    // each panned track running through its effects once per channel.
+    pub fn synthesize_arrangement_stereo(&self, arrangement: &Arrangement) -> Result<Vec<(f32, f32)>, SynthError> {
+        let (left, right) = self.synthesize_arrangement_channels(arrangement, &DynamicParameters::default())?;
+        let mut frames: Vec<(f32, f32)> = left.into_iter().zip(right).collect();
+        let max = frames.iter().fold(0.0f32, |m, (l, r)| m.max(l.abs()).max(r.abs()));
+        if max > 1.0 {
+            frames.iter_mut().for_each(|(l, r)| { *l /= max; *r /= max; });
+        }
+        Ok(frames)
+    }
+
+    // Mono renders are the (left + right) / 2 fold-down of the stereo mix, the same as before for centred tracks
     fn synthesize_arrangement_private(
         &self,
         arrangement: &Arrangement,
         params: &DynamicParameters,
+        normalize: bool,
     ) -> Result<Vec<f32>, SynthError> {
-        let total_samples = (arrangement.total_length * self.sample_rate) as usize;
-        let mut buffer = vec![0.0f32; total_samples];
-        let chunk_size = 1024;
-
-        for (track, start_time, overrides) in &arrangement.tracks {
-            let enabled = params.track_enabled.get(&track.name).copied().unwrap_or(true);
-            if !enabled { continue; }
-            let track_vol = params.track_volumes.get(&track.name).copied().unwrap_or(1.0);
-            let start_sample = (start_time * self.sample_rate) as usize;
-            let mut t = track.clone();
-            if let Some(v) = overrides.volume      { t.instrument.volume = v; }
-            if let Some(p) = overrides.pitch        { t.instrument.pitch  = p * params.master_pitch; }
-            if let Some(tm) = overrides.tempo       { t.tempo = tm; }
-            if let Some(r) = &overrides.reverb      { t.instrument.effects.reverb     = Some(r.clone()); }
-            if let Some(d) = &overrides.delay       { t.instrument.effects.delay      = Some(d.clone()); }
-            if let Some(x) = &overrides.distortion  { t.instrument.effects.distortion = Some(x.clone()); }
-            if let Some(f) = &overrides.filter      { t.instrument.effects.filter     = Some(f.clone()); }
-            t.instrument.volume *= track_vol;
-
-            let track_total = (t.length * self.sample_rate) as usize;
-            let mut fx = if t.instrument.effects.has_any() { Some(EffectsProcessor::new(self.sample_rate)) } else { None };
-
-            let mut offset = 0;
-            while offset < track_total {
-                let csz = chunk_size.min(track_total - offset);
-                let mut chunk = vec![0.0f32; csz];
-                self.synthesize_track_into(&mut chunk, &t, offset);
-                if let Some(fx) = &mut fx {
-                    for s in chunk.iter_mut() { *s = fx.process(*s, &t.instrument.effects); }
-                }
-                for (i, &s) in chunk.iter().enumerate() {
-                    if let Some(dst) = buffer.get_mut(start_sample + offset + i) {
+        let (left, right) = self.synthesize_arrangement_channels(arrangement, params)?;
+        let mut buffer: Vec<f32> = left.iter().zip(&right).map(|(l, r)| (l + r) * 0.5).collect();
+        if normalize
+            && let Some(max) = buffer.iter().map(|v| v.abs()).max_by(|a, b| a.partial_cmp(b).unwrap())
+            && max > 1.0 {
+            buffer.iter_mut().for_each(|s| *s /= max);
+        }
+        Ok(buffer)
+    }
+
+    // Left and right mix with fades and master width applied, not normalized
+    fn synthesize_arrangement_channels(&self, arrangement: &Arrangement, params: &DynamicParameters) -> Result<(Vec<f32>, Vec<f32>), SynthError> {
+        let total_samples = (self.render_seconds(arrangement) * self.sample_rate) as usize;
+        let mut left = vec![0.0f32; total_samples];
+        let mut right = vec![0.0f32; total_samples];
+
+        // Summed in track order whichever way they were rendered, so the mix is the same to the bit
+        for (start_sample, track_left, track_right) in self.render_tracks(arrangement, params).into_iter().flatten() {
+            let mix = |track_buf: &[f32], out: &mut [f32]| {
+                for (i, &s) in track_buf.iter().enumerate() {
+                    if let Some(dst) = out.get_mut(start_sample + i) {
                         *dst += s * params.master_volume;
                     }
                 }
-                offset += csz;
+            };
+            mix(&track_left, &mut left);
+            mix(track_right.as_ref().unwrap_or(&track_left), &mut right);
+        }
+
+        if params.master_width != 1.0 {
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                (*l, *r) = stereo_width(*l, *r, params.master_width);
+            }
+        }
+        if let Some(comp) = &arrangement.compressor {
+            let mut bus = BusCompressor::new(self.sample_rate);
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                (*l, *r) = bus.process_stereo(*l, *r, comp);
+            }
+        }
+        for buffer in [&mut left, &mut right] {
+            self.apply_arrangement_fades(buffer, arrangement);
+        }
+        Ok((left, right))
+    }
+
+    // Every track of an arrangement rendered on its own with its effects, None where disabled
+    fn render_tracks(&self, arrangement: &Arrangement, params: &DynamicParameters) -> Vec<Option<RenderedTrack>> {
+        let count = arrangement.tracks.len();
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        {
+            let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(count);
+            if workers > 1 {
+                let (sample_rate, render_seed, render_tails) = (self.sample_rate, self.render_seed, self.render_tails);
+                let mut rendered: Vec<Option<RenderedTrack>> = (0..count).map(|_| None).collect();
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = (0..workers)
+                        .map(|w| scope.spawn(move || {
+                            // Own engine per worker, this one may hold an audio stream that can't cross threads
+                            let mut engine = Self::new_offline(sample_rate);
+                            engine.render_seed = render_seed;
+                            engine.render_tails = render_tails;
+                            (w..count).step_by(workers)
+                                .map(|i| (i, engine.render_track(arrangement, params, i)))
+                                .collect::<Vec<_>>()
+                        }))
+                        .collect();
+                    for handle in handles {
+                        for (i, track) in handle.join().expect("track render thread panicked") {
+                            rendered[i] = track;
+                        }
+                    }
+                });
+                return rendered;
             }
         }
+        (0..count).map(|i| self.render_track(arrangement, params, i)).collect()
+    }
+
+    // Track `i` with overrides and effects applied, at its own length (the mix places it)
+    fn render_track(&self, arrangement: &Arrangement, params: &DynamicParameters, i: usize) -> Option<RenderedTrack> {
+        let (track, start_time, overrides) = &arrangement.tracks[i];
+        let enabled = params.track_enabled.get(&track.name).copied().unwrap_or(true);
+        if !enabled { return None; }
+        let track_vol = params.track_volumes.get(&track.name).copied().unwrap_or(1.0);
+        let start_sample = (start_time * self.sample_rate) as usize;
+        let mut t = track.clone();
+        if let Some(v) = overrides.volume      { t.instrument.volume = v; }
+        if let Some(p) = overrides.pitch        { t.instrument.pitch  = p; }
+        Self::apply_master_pitch(&mut t, params.master_pitch);
+        if let Some(tm) = overrides.tempo       { t.tempo = tm; }
+        if let Some(p) = overrides.pan          { t.instrument.pan = p; }
+        t.instrument.effects = overrides.effects_over(&t.instrument.effects);
+        t.instrument.volume *= track_vol;
+
+        let track_total = self.track_samples(&t);
+        let mut rng = self.track_rng(arrangement, i);
+        if t.is_centred() && !t.instrument.effects.stereo_effects() {
+            // Both channels would come out identical, render once
+            let mut track_buf = vec![0.0f32; track_total];
+            self.synthesize_track_into(&mut track_buf, &t, 0, &mut rng);
+            self.apply_track_effects(&mut track_buf, &t);
+            Some((start_sample, track_buf, None))
+        } else {
+            let mut left_buf = vec![0.0f32; track_total];
+            let mut right_buf = if t.is_centred() {
+                // Same dry signal on both sides, only the reverb tails and delay repeats differ
+                self.synthesize_track_into(&mut left_buf, &t, 0, &mut rng);
+                left_buf.clone()
+            } else {
+                let mut buf = vec![0.0f32; track_total];
+                self.synthesize_track_channel(&mut left_buf, &t, 0, &mut rng.clone(), Some(0));
+                self.synthesize_track_channel(&mut buf, &t, 0, &mut rng, Some(1));
+                buf
+            };
+            self.apply_track_effects_stereo(&mut left_buf, &mut right_buf, &t);
+            Some((start_sample, left_buf, Some(right_buf)))
+        }
+    }
 
+    fn apply_arrangement_fades(&self, buffer: &mut [f32], arrangement: &Arrangement) {
         if let Some(fi) = arrangement.fade_in {
             let n = (fi * self.sample_rate) as usize;
-            for i in 0..n.min(buffer.len()) { buffer[i] *= i as f32 / n as f32; }
+            for i in 0..n.min(buffer.len()) { buffer[i] *= arrangement.fade_curve.apply(i as f32 / n as f32); }
         }
         if let Some(fo) = arrangement.fade_out {
             let n  = (fo * self.sample_rate) as usize;
             let fs = buffer.len().saturating_sub(n);
-            for i in fs..buffer.len() { buffer[i] *= (buffer.len() - i) as f32 / n as f32; }
+            for i in fs..buffer.len() { buffer[i] *= arrangement.fade_curve.apply((buffer.len() - i) as f32 / n as f32); }
         }
-        if let Some(max) = buffer.iter().map(|v| v.abs()).max_by(|a, b| a.partial_cmp(b).unwrap()) {
-            if max > 1.0 { buffer.iter_mut().for_each(|s| *s /= max); }
-        }
-        Ok(buffer)
     }
  
-    pub(crate) fn synthesize_track_into(&self, buffer: &mut [f32], track: &MelodyTrack, start_sample: usize) {
-        let mut cur = 0usize;
-        let beat_dur = 60.0 / track.tempo;
+    pub(crate) fn synthesize_track_into(&self, buffer: &mut [f32], track: &MelodyTrack, start_sample: usize, rng: &mut fastrand::Rng) {
+        self.synthesize_track_channel(buffer, track, start_sample, rng, None);
+    }
+
+    // One channel of a panned track render (0 = left, 1 = right), None for the plain mono render.
+    // Both channels must be rendered from clones of the same rng so they draw the same pans and noise.
+    fn synthesize_track_channel(&self, buffer: &mut [f32], track: &MelodyTrack, start_sample: usize, rng: &mut fastrand::Rng, channel: Option<usize>) {
+        // Element onsets come from a running time in seconds, rounded once, so truncation can't pile up over many notes
+        let sr = self.sample_rate as f64;
+        let to_sample = |t: f64| (t * sr).round() as usize;
+        let mut elapsed = 0.0f64;
+        // Span of the buffer the voices wrote to: release tails, drum hits and pushed notes can reach past the timeline
+        let mut written = (start_sample, start_sample);
+        let mut wrote = |from: usize, len: usize, shift: isize| {
+            written.0 = written.0.min(from.saturating_add_signed(shift));
+            written.1 = written.1.max((from + len).saturating_add_signed(shift));
+        };
+        let sub_ratio = track.instrument.sub_ratio();
+        let vibrato = &track.instrument.vibrato;
+        let unison = track.instrument.unison_ratios();
+        let dc_pole = (-std::f32::consts::TAU * OSC_DC_BLOCK_HZ / self.sample_rate).exp();
+        let chord_dc = track.instrument.note_waveform(None).filter(|wf| track.instrument.dc_blocks(*wf)).map(|_| dc_pole);
+        let mut beat_dur = track.beat_duration();
+        // Pans are only drawn for the channel renders, so the mono render's rng sequence is untouched
+        let note_pan = |note_pan: Option<f32>, rng: &mut fastrand::Rng| channel.map(|_| track.instrument.note_pan(note_pan, rng));
+        let side = |(left, right): (f32, f32)| if channel == Some(0) { left } else { right };
+        let pan_gain = |pan: Option<f32>| pan.map_or(1.0, |p| side(pan_gains(p)));
         for element in &track.sequence {
+            let cur = to_sample(elapsed);
             match element {
                 SequenceElement::Note(note) => {
-                    let nd = note.duration * beat_dur;
-                    match &track.instrument.source {
-                        InstrumentSource::Synthesized(_) => {
-                            let ns = (nd * self.sample_rate) as usize;
-                            let mut phase = 0.0f32;
+                    let nd = element.seconds(beat_dur);
+                    // Pushed/pulled notes just land elsewhere in the additive buffer, overlaps included
+                    let shift = (note.shift.clamp(-MAX_NOTE_SHIFT, MAX_NOTE_SHIFT) as f64 * sr).round() as isize;
+                    let pan = note_pan(note.pan, rng);
+                    let gain = note.velocity * pan_gain(pan);
+                    if let Some(wf) = track.instrument.note_waveform(note.wave) {
+                        // Overlapping notes ring past `nd` and mix over the next ones in the buffer
+                        let vd = note.voice_seconds(nd, &track.instrument);
+                        let ns = to_sample(elapsed + vd as f64) - cur;
+                        let dc_coeff = track.instrument.dc_blocks(wf).then_some(dc_pole);
+                        wrote(start_sample + cur, ns, shift);
+                        for ratio in unison.clone() {
+                            let (mut phase, mut sub_phase, mut dc, mut noise) = (0.0f32, 0.0f32, 0.0f32, NoiseState::default());
                             for i in 0..ns {
-                                let idx = start_sample + cur + i;
+                                let Some(idx) = (start_sample + cur + i).checked_add_signed(shift) else { continue };
                                 if idx >= buffer.len() { break; }
                                 let t = i as f32 / self.sample_rate;
-                                let env = self.calculate_envelope(t, nd, &track.instrument);
+                                let env = self.calculate_envelope(t, vd, &track.instrument, note.release.unwrap_or(track.instrument.release));
                                 let mut pitch = note.pitch;
-                                if let Some(st) = note.slide_to { pitch = note.pitch * (1.0 - t / nd) + st * (t / nd); }
-                                if let InstrumentSource::Synthesized(wf) = &track.instrument.source {
-                                    buffer[idx] += wf.generate_sample(phase) * env * note.velocity * track.instrument.volume;
-                                    phase += pitch / self.sample_rate;
-                                    if phase >= 1.0 { phase -= 1.0; }
+                                if let Some(st) = note.slide_to {
+                                    let s = (t / nd).min(1.0);
+                                    pitch = note.pitch * (1.0 - s) + st * s;
+                                }
+                                pitch *= ratio * vibrato.pitch_ratio(t);
+                                let mut voice = wf.generate_sample_stateful(phase, pitch / self.sample_rate, rng, &mut noise) + track.instrument.sub_sample_with(sub_phase, rng);
+                                if let Some(a) = dc_coeff {
+                                    dc = (1.0 - a) * voice + a * dc;
+                                    voice -= dc;
                                 }
+                                buffer[idx] += voice * env * gain * track.instrument.volume / unison.len() as f32;
+                                phase += pitch / self.sample_rate;
+                                if phase >= 1.0 { phase -= 1.0; }
+                                sub_phase = (sub_phase + pitch * sub_ratio / self.sample_rate).fract();
                             }
-                            cur += ns;
                         }
-                        InstrumentSource::Sample(sd) => {
-                            let pr  = track.instrument.pitch;
-                            let olen = (sd.samples.len() as f32 / pr) as usize;
-                            let adur = olen as f32 / self.sample_rate;
-                            for i in 0..olen {
-                                let idx = start_sample + cur + i;
-                                if idx >= buffer.len() { break; }
-                                let t = i as f32 / self.sample_rate;
-                                let env = self.calculate_envelope(t, adur, &track.instrument);
-                                buffer[idx] += Self::interpolate_sample(sd, t, pr) * env * note.velocity * track.instrument.volume;
-                            }
-                            cur += olen;
+                        elapsed += nd as f64;
+                    } else if let InstrumentSource::Sample(sd) = &track.instrument.source {
+                        let pr  = track.instrument.pitch;
+                        let olen = (sd.samples.len() as f32 / pr) as usize;
+                        let adur = olen as f32 / self.sample_rate;
+                        wrote(start_sample + cur, olen, shift);
+                        for i in 0..olen {
+                            let Some(idx) = (start_sample + cur + i).checked_add_signed(shift) else { continue };
+                            if idx >= buffer.len() { break; }
+                            let t = i as f32 / self.sample_rate;
+                            let env = self.calculate_envelope(t, adur, &track.instrument, note.release.unwrap_or(track.instrument.release));
+                            let read_t = t + vibrato.time_offset(t);
+                            let voice = match pan.zip(Self::interpolate_image(sd, read_t, pr, unison.clone())) {
+                                Some((pan, (l, r))) => side(stereo_balance(l, r, pan)) * note.velocity,
+                                None => unison.clone().map(|ratio| Self::interpolate_sample(sd, read_t, pr * ratio)).sum::<f32>() / unison.len() as f32 * gain,
+                            };
+                            buffer[idx] += voice * env * track.instrument.volume;
                         }
+                        elapsed += olen as f64 / sr;
+                    } else {
+                        if let Some(sd) = track.instrument.drum_sample(note.pitch) {
+                            let at = (start_sample + cur).checked_add_signed(shift);
+                            wrote(start_sample + cur, self.mix_drum_hit(buffer, at, sd, &track.instrument, gain, note.release), shift);
+                        }
+                        elapsed += nd as f64;
                     }
                 }
                 SequenceElement::Chord(chord) => {
-                    let cd = chord.duration * beat_dur;
-                    let cs = (cd * self.sample_rate) as usize;
-                    for pitch in &chord.pitches {
-                        let mut phase = 0.0f32;
+                    let cd = element.seconds(beat_dur);
+                    let cs = to_sample(elapsed + cd as f64) - cur;
+                    let gain = chord.velocity * pan_gain(note_pan(None, rng));
+                    // Every pitch is its own drum hit, layered at full velocity
+                    for sd in chord.pitches.iter().filter_map(|p| track.instrument.drum_sample(*p)) {
+                        wrote(start_sample + cur, self.mix_drum_hit(buffer, Some(start_sample + cur), sd, &track.instrument, gain, chord.release), 0);
+                    }
+                    let voices = chord.pitches.iter().flat_map(|p| unison.clone().map(move |ratio| p * ratio));
+                    for pitch in voices {
+                        let (mut phase, mut sub_phase, mut dc, mut noise) = (0.0f32, 0.0f32, 0.0f32, NoiseState::default());
                         for i in 0..cs {
                             let idx = start_sample + cur + i;
                             if idx >= buffer.len() { break; }
                             let t = i as f32 / self.sample_rate;
-                            let env = self.calculate_envelope(t, cd, &track.instrument);
+                            let env = self.calculate_envelope(t, cd, &track.instrument, chord.release.unwrap_or(track.instrument.release));
                             if let InstrumentSource::Synthesized(wf) = &track.instrument.source {
-                                buffer[idx] += wf.generate_sample(phase) * env * chord.velocity * track.instrument.volume / chord.pitches.len() as f32;
+                                let pitch = pitch * vibrato.pitch_ratio(t);
+                                let mut voice = wf.generate_sample_stateful(phase, pitch / self.sample_rate, rng, &mut noise) + track.instrument.sub_sample_with(sub_phase, rng);
+                                if let Some(a) = chord_dc {
+                                    dc = (1.0 - a) * voice + a * dc;
+                                    voice -= dc;
+                                }
+                                buffer[idx] += voice * env * gain * track.instrument.volume / (chord.pitches.len() * unison.len()) as f32;
                                 phase += pitch / self.sample_rate;
                                 if phase >= 1.0 { phase -= 1.0; }
+                                sub_phase = (sub_phase + pitch * sub_ratio / self.sample_rate).fract();
                             }
                         }
                     }
-                    cur += cs;
+                    elapsed += cd as f64;
                 }
-                SequenceElement::Rest(d) => { cur += (d * beat_dur * self.sample_rate) as usize; }
+                SequenceElement::Rest(d) => { elapsed += (d * beat_dur) as f64; }
+                SequenceElement::TempoChange(tempo) => { beat_dur = track.beat_duration_at(*tempo); }
+            }
+        }
+
+        let end = written.1.max(start_sample + to_sample(elapsed)).min(buffer.len());
+        let start = written.0.min(end);
+        if let Some(region) = buffer.get_mut(start..end) {
+            self.condition_track(region, track, start_sample - start);
+        }
+    }
+
+    // Runs a rendered track through its effects chain, moving the keytracked filter cutoff and filter envelope at each note.
+    pub(crate) fn apply_track_effects(&self, buffer: &mut [f32], track: &MelodyTrack) {
+        let chain = &track.instrument.effects;
+        if !chain.has_any() { return; }
+        let mut fx = EffectsProcessor::for_chain(self.sample_rate, chain);
+        let mut changes = self.note_changes(track).into_iter().peekable();
+        let mut note = (0, f32::INFINITY);
+        for (i, s) in buffer.iter_mut().enumerate() {
+            while let Some((onset, scale, duration)) = changes.next_if(|&(onset, ..)| onset <= i) {
+                fx.set_cutoff_scale(scale);
+                note = (onset, duration);
+            }
+            fx.set_note_time((i - note.0) as f32 / self.sample_rate, note.1);
+            *s = fx.process(*s, chain);
+        }
+    }
+
+    // Both channels of a track through their own processors, reverb tails cross-mixed by the reverb width
+    // and ping-pong delay lines cross-fed
+    fn apply_track_effects_stereo(&self, left: &mut [f32], right: &mut [f32], track: &MelodyTrack) {
+        let chain = &track.instrument.effects;
+        if !chain.has_any() { return; }
+        let mut fx_left = EffectsProcessor::for_chain(self.sample_rate, chain);
+        let mut fx_right = EffectsProcessor::for_chain_right(self.sample_rate, chain);
+        let mut changes = self.note_changes(track).into_iter().peekable();
+        let mut note = (0, f32::INFINITY);
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            while let Some((onset, scale, duration)) = changes.next_if(|&(onset, ..)| onset <= i) {
+                fx_left.set_cutoff_scale(scale);
+                fx_right.set_cutoff_scale(scale);
+                note = (onset, duration);
+            }
+            let time = (i - note.0) as f32 / self.sample_rate;
+            fx_left.set_note_time(time, note.1);
+            fx_right.set_note_time(time, note.1);
+            if let Some(delay) = chain.delay.as_ref().filter(|d| d.ping_pong) {
+                ping_pong(&mut fx_left, &mut fx_right, delay);
+            }
+            (*l, *r) = (fx_left.process(*l, chain), fx_right.process(*r, chain));
+            if let Some(reverb) = &chain.reverb {
+                (*l, *r) = reverb_width(*l, *r, (fx_left.reverb_tail(), fx_right.reverb_tail()), reverb.width);
+            }
+        }
+    }
+
+    // (onset sample, cutoff scale, seconds) per note when the filter keytracks or has an envelope,
+    // stepped the same way synthesize_track_into lays notes out
+    fn note_changes(&self, track: &MelodyTrack) -> Vec<(usize, f32, f32)> {
+        let chain = &track.instrument.effects;
+        let keytracks = (chain.filter.is_some() || chain.filter2.is_some())
+            && track.instrument.filter_keytrack != 0.0
+            && matches!(track.instrument.source, InstrumentSource::Synthesized(_));
+        let scale = |pitch: f32| if keytracks { track.instrument.keytrack_scale(pitch) } else { 1.0 };
+
+        let mut changes = Vec::new();
+        if keytracks || chain.filter_envelope() {
+            let onset = |t: f64| (t * self.sample_rate as f64).round() as usize;
+            let (mut elapsed, mut beat_dur) = (0.0f64, track.beat_duration());
+            for element in &track.sequence {
+                let seconds = element.seconds(beat_dur);
+                match element {
+                    SequenceElement::Note(note) => changes.push((onset(elapsed), scale(note.pitch), note.voice_seconds(seconds, &track.instrument))),
+                    SequenceElement::Chord(chord) => {
+                        let root = chord.pitches.iter().copied().fold(f32::INFINITY, f32::min);
+                        changes.push((onset(elapsed), scale(root), seconds));
+                    }
+                    SequenceElement::TempoChange(tempo) => beat_dur = track.beat_duration_at(*tempo),
+                    SequenceElement::Rest(_) => {}
+                }
+                elapsed += element.seconds(beat_dur) as f64;
+            }
+        }
+        changes
+    }
+
+    // Offline counterpart of the live master pitch: notes for synthesized tracks, playback rate for samples.
+    pub(crate) fn apply_master_pitch(track: &mut MelodyTrack, master_pitch: f32) {
+        if master_pitch == 1.0 { return; }
+        match track.instrument.source {
+            InstrumentSource::Sample(_) | InstrumentSource::DrumMap(_) => track.instrument.pitch *= master_pitch,
+            InstrumentSource::Synthesized(_) => {
+                for element in &mut track.sequence {
+                    match element {
+                        SequenceElement::Note(note) => {
+                            note.pitch *= master_pitch;
+                            if let Some(st) = note.slide_to.as_mut() { *st *= master_pitch; }
+                        }
+                        SequenceElement::Chord(chord) => chord.pitches.iter_mut().for_each(|p| *p *= master_pitch),
+                        SequenceElement::Rest(_) | SequenceElement::TempoChange(_) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Per-track cleanup ahead of the effects chain: gate, rumble/fizz pre-filters, then the clip ceiling.
+    // `lead` is how many samples of the region come before the track's start (notes pulled early).
+    pub(crate) fn condition_track(&self, region: &mut [f32], track: &MelodyTrack, lead: usize) {
+        let instr = &track.instrument;
+        if let Some(gate) = &track.gate {
+            let beat_dur = track.beat_duration();
+            for (i, s) in region.iter_mut().enumerate() {
+                *s *= gate.gain((i as f32 - lead as f32) / self.sample_rate, beat_dur);
+            }
+        }
+        if let Some(cutoff) = instr.hpf {
+            let a = (-std::f32::consts::TAU * cutoff / self.sample_rate).exp();
+            let mut lp = 0.0f32;
+            for s in region.iter_mut() {
+                lp = (1.0 - a) * *s + a * lp;
+                *s -= lp;
             }
         }
+        if let Some(cutoff) = instr.lpf {
+            let a = (-std::f32::consts::TAU * cutoff / self.sample_rate).exp();
+            let mut lp = 0.0f32;
+            for s in region.iter_mut() {
+                lp = (1.0 - a) * *s + a * lp;
+                *s = lp;
+            }
+        }
+        // Keep the track inside its own headroom before it hits the effects chain
+        if let Some(ceiling) = instr.clip_ceiling {
+            region.iter_mut().for_each(|s| *s = soft_clip(*s, ceiling));
+        }
+    }
+
+    // Adds one drum map sample at `at`, played to its end at the instrument pitch. Returns its length in samples.
+    fn mix_drum_hit(&self, buffer: &mut [f32], at: Option<usize>, sd: &SampleData, instr: &Instrument, gain: f32, release: Option<f32>) -> usize {
+        let Some(at) = at else { return 0 };
+        let olen = (sd.samples.len() as f32 / instr.pitch) as usize;
+        let adur = olen as f32 / self.sample_rate;
+        for i in 0..olen {
+            let Some(out) = buffer.get_mut(at + i) else { break };
+            let t = i as f32 / self.sample_rate;
+            let env = self.calculate_envelope(t, adur, instr, release.unwrap_or(instr.release));
+            *out += Self::interpolate_sample(sd, t, instr.pitch) * env * gain * instr.volume;
+        }
+        olen
     }
 
     #[inline]
     fn interpolate_sample(sd: &SampleData, t: f32, pitch: f32) -> f32 {
-        let pos = t * sd.sample_rate as f32 * pitch;
+        Self::interpolate_buffer(&sd.samples, sd.sample_rate, t, pitch)
+    }
+
+    // Left/right of a stereo sample, averaged over the unison voices; None for mono samples
+    fn interpolate_image(sd: &SampleData, t: f32, pitch: f32, unison: impl ExactSizeIterator<Item = f32>) -> Option<(f32, f32)> {
+        let (left, right) = sd.stereo.as_deref()?;
+        let voices = unison.len() as f32;
+        let (l, r) = unison.fold((0.0, 0.0), |(l, r), ratio| (
+            l + Self::interpolate_buffer(left, sd.sample_rate, t, pitch * ratio),
+            r + Self::interpolate_buffer(right, sd.sample_rate, t, pitch * ratio),
+        ));
+        Some((l / voices, r / voices))
+    }
+
+    fn interpolate_buffer(samples: &[f32], sample_rate: u32, t: f32, pitch: f32) -> f32 {
+        let pos = t * sample_rate as f32 * pitch;
         let idx = pos as usize;
-        if idx >= sd.samples.len() { return 0.0; }
-        if idx + 1 < sd.samples.len() {
+        if idx >= samples.len() { return 0.0; }
+        if idx + 1 < samples.len() {
             let frac = pos - idx as f32;
-            sd.samples[idx] * (1.0 - frac) + sd.samples[idx + 1] * frac
+            samples[idx] * (1.0 - frac) + samples[idx + 1] * frac
         } else {
-            sd.samples[idx]
+            samples[idx]
         }
     }
 
-    fn calculate_envelope(&self, time: f32, duration: f32, instr: &Instrument) -> f32 {
-        Self::calculate_envelope_static(time, duration, instr)
+    fn calculate_envelope(&self, time: f32, duration: f32, instr: &Instrument, release: f32) -> f32 {
+        Self::calculate_envelope_static(time, duration, instr, release)
     }
 
-    fn calculate_envelope_static(time: f32, duration: f32, instr: &Instrument) -> f32 {
+    // `release` is the instrument's unless the note overrides it. Tremolo rides on top of the AHDSR.
+    fn calculate_envelope_static(time: f32, duration: f32, instr: &Instrument, release: f32) -> f32 {
         let ae = instr.attack;
-        let de = ae + instr.decay;
-        let rs = duration - instr.release;
-        if time < ae {
-            time / ae
+        let he = ae + instr.hold;
+        let de = he + instr.decay;
+        let rs = duration - release;
+        let curve = instr.env_curve;
+        let adsr = if time < ae {
+            curve.rise(time / ae)
+        } else if time < he {
+            1.0
         } else if time < de {
-            1.0 - (time - ae) / instr.decay * (1.0 - instr.sustain)
+            instr.sustain + (1.0 - instr.sustain) * curve.fall((time - he) / instr.decay)
         } else if time < rs {
             instr.sustain
         } else {
-            instr.sustain * (1.0 - (time - rs) / instr.release)
+            instr.sustain * curve.fall((time - rs) / release)
+        };
+        adsr * instr.tremolo.gain(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mel(content: &str) -> MelodyTrack {
+        MelodyTrack::from_mel(content, &HashMap::new()).unwrap()
+    }
+
+    fn render(track: &MelodyTrack, sr: f32) -> Vec<f32> {
+        let engine = SynthEngine::new_offline(sr);
+        let mut buffer = vec![0.0; (track.duration_seconds() * sr) as usize];
+        engine.synthesize_track_into(&mut buffer, track, 0, &mut fastrand::Rng::with_seed(1));
+        buffer
+    }
+
+    // One track per `(name, mel source, start seconds)`
+    fn arrangement(tracks: &[(&str, &str, f32)]) -> Arrangement {
+        let mels: HashMap<String, MelodyTrack> = tracks.iter()
+            .map(|(name, content, _)| (format!("{}.mel", name), mel(content)))
+            .collect();
+        let bmi: String = tracks.iter()
+            .map(|(name, _, start)| format!("track: {}.mel, {}, name={}\n", name, start, name))
+            .collect();
+        Arrangement::from_bmi(&bmi, &mels).unwrap()
+    }
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn narrow_pulse_is_dc_blocked_by_default() {
+        let sr = 44100.0;
+        let blocked = render(&mel("waveform: pulse 0.1\nnote: A4, 4, 1.0"), sr);
+        let raw = render(&mel("waveform: pulse 0.1\nosc_dc_block: off\nnote: A4, 4, 1.0"), sr);
+        // Past the blocker's settling time, the middle of the held note
+        let (a, b) = (sr as usize / 2, 3 * sr as usize / 2);
+        assert!(mean(&blocked[a..b]).abs() < 0.01, "blocked mean {}", mean(&blocked[a..b]));
+        assert!(mean(&raw[a..b]).abs() > 0.1, "raw mean {}", mean(&raw[a..b]));
+    }
+
+    #[test]
+    fn polyphony_cap_steals_the_oldest_voice_for_good() {
+        let sr = 1000.0;
+        let long = "waveform: square\nnote: A4, 4, 1.0";
+        // Starts last and ends first, so a voice frees up while the stolen one is still sounding
+        let short = "waveform: square\nnote: A4, 0.4, 1.0";
+        let arr = arrangement(&[("a", long, 0.0), ("b", long, 0.1), ("c", short, 0.2)]);
+        let params = DynamicParameters::default();
+        let mut live = LiveState::new(&arr, sr);
+        // Summed level per track while all three overlap, then after the short note has ended
+        let (mut overlap, mut after) = ([0.0f32; 3], [0.0f32; 3]);
+        for idx in 0..1000 {
+            SynthEngine::synthesize_single_sample(&arr, idx, sr, &params, None, &mut live, Some(2));
+            let t = idx as f32 / sr;
+            let window = if t > 0.21 && t < 0.4 { &mut overlap } else if t > 0.45 { &mut after } else { continue };
+            for v in &live.voices { window[v.track] += v.value.abs(); }
+        }
+        assert_eq!(overlap[0], 0.0, "oldest voice still sounding");
+        assert!(overlap[1] > 0.0 && overlap[2] > 0.0, "newest two not sounding: {:?}", overlap);
+        assert_eq!(after[0], 0.0, "stolen voice came back");
+        assert!(after[1] > 0.0);
+    }
+
+    #[test]
+    fn zero_length_arrangement_stops() {
+        let sr = 44100.0;
+        // A track with no notes, the arrangement has nothing to play
+        let empty = arrangement(&[("a", "waveform: sine", 0.0)]);
+        let mut context = PlaybackContext::new(empty, sr, None, None);
+        assert!(context.stop_if_empty(sr));
+        assert_eq!(context.state, PlaybackState::Stopped);
+        assert!(context.zero_length);
+
+        let mut context = PlaybackContext::new(arrangement(&[("a", "note: A4, 1, 1.0", 0.0)]), sr, None, None);
+        assert!(!context.stop_if_empty(sr));
+        assert_eq!(context.state, PlaybackState::Playing);
+    }
+
+    #[test]
+    fn cue_mix_gets_the_click_and_main_mix_does_not() {
+        let sr = 44100.0;
+        // Silent program, so anything left in the cue is the click
+        let arr = arrangement(&[("a", "note: A4, 4, 0.0", 0.0)]);
+        let params = DynamicParameters::default();
+        let mix = CueMix::default();
+        let grid = SynthEngine::metronome_grid(&arr);
+        let (mut main_live, mut cue_live) = (LiveState::new(&arr, sr), LiveState::new(&arr, sr));
+        let mut limiter = AutoGain::new(sr);
+        let (mut main, mut cue) = (0.0f32, 0.0f32);
+        for idx in 0..(CLICK_LENGTH * sr) as usize {
+            let (l, r) = SynthEngine::synthesize_single_sample(&arr, idx, sr, &params, None, &mut main_live, None);
+            main += l.abs() + r.abs();
+            let (l, r) = SynthEngine::synthesize_single_sample(&arr, idx, sr, &params, Some(&mix.track_enabled), &mut cue_live, None);
+            cue += SynthEngine::cue_sample((l + r) * 0.5, idx as f32 / sr, grid, &mix, &mut limiter).abs();
+        }
+        assert_eq!(main, 0.0);
+        assert!(cue > 1.0, "cue level {}", cue);
+    }
+
+    #[test]
+    fn cue_ring_drops_oldest_when_full() {
+        let ring = CueRing::new(4);
+        for i in 0..6 { ring.push(i as f32); }
+        let popped: Vec<f32> = std::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(popped, vec![2.0, 3.0, 4.0, 5.0]);
+        ring.push(7.0);
+        ring.clear();
+        assert_eq!(ring.pop(), None);
+    }
+
+    fn constant_sample(value: f32, seconds: f32, sr: f32) -> SampleData {
+        SampleData { samples: Arc::new(vec![value; (seconds * sr) as usize]), sample_rate: sr as u32, gain: 1.0, stereo: None }
+    }
+
+    #[test]
+    fn drum_map_picks_the_sample_by_pitch() {
+        let sr = 44100.0;
+        let samples = HashMap::from([
+            ("kick".to_string(), constant_sample(0.8, 0.1, sr)),
+            ("snare".to_string(), constant_sample(-0.8, 0.1, sr)),
+        ]);
+        let track = MelodyTrack::from_mel("drum: C1=kick, D1=snare\nsustain: 1.0\nnote: C1, 1, 1.0\nnote: D1, 1, 1.0\nnote: E1, 1, 1.0", &samples).unwrap();
+        let buffer = render(&track, sr);
+        let at = |seconds: f32| buffer[(seconds * sr) as usize];
+        assert!(at(0.05) > 0.1, "C1 should play the kick");
+        assert!(at(0.55) < -0.1, "D1 should play the snare");
+        assert_eq!(at(1.05), 0.0, "E1 has no mapping");
+    }
+
+    #[test]
+    fn drum_tails_past_the_timeline_are_conditioned() {
+        let sr = 44100.0;
+        let samples = HashMap::from([("crash".to_string(), constant_sample(1.0, 2.0, sr))]);
+        // A short note whose hit rings on well past the end of the sequence
+        let track = MelodyTrack::from_mel("drum: C1=crash\nvolume: 1.0\nsustain: 1.0\nclip_ceiling: 0.3\nnote: C1, 0.25, 1.0", &samples).unwrap();
+        let engine = SynthEngine::new_offline(sr);
+        let mut buffer = vec![0.0; 2 * sr as usize];
+        engine.synthesize_track_into(&mut buffer, &track, 0, &mut fastrand::Rng::with_seed(1));
+        let tail = buffer[sr as usize];
+        assert!(tail > 0.0 && tail <= 0.3, "tail sample {}", tail);
+    }
+
+    // Fresh scratch directory for files a test writes, removed by the test when done
+    fn fixture_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("boomie-{}-{}", tag, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_pcm16(path: &std::path::Path, samples: &[i16]) {
+        let spec = hound::WavSpec { channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for &s in samples { writer.write_sample(s).unwrap(); }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn load_sample_dir_loads_each_wav_and_reports_failures() {
+        let dir = fixture_dir("sample-dir");
+        let ramp: Vec<i16> = (0..100).map(|i| i * 100).collect();
+        for name in ["kick", "snare"] {
+            write_pcm16(&dir.join(format!("{}.wav", name)), &ramp);
+        }
+        std::fs::write(dir.join("broken.wav"), b"not a wav").unwrap();
+
+        let mut engine = SynthEngine::new_offline(44100.0);
+        let result = engine.load_sample_dir(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (loaded, failed) = result.unwrap();
+        assert_eq!(loaded, vec!["kick", "snare"]);
+        assert!(engine.get_sample_cache().contains_key("kick") && engine.get_sample_cache().contains_key("snare"));
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0.file_name().unwrap(), "broken.wav");
+    }
+
+    #[test]
+    fn effects_solo_keeps_only_the_reverb_tail() {
+        let sr = 44100.0;
+        let arr = arrangement(&[
+            ("verb", "waveform: sine\nreverb: 0.8, 0.3, 0.5, 0.0\nnote: A4, 0.5, 1.0", 0.0),
+            ("plain", "waveform: sine\nnote: A4, 0.5, 1.0", 0.0),
+        ]);
+        let mut params = DynamicParameters::default();
+        params.track_wet_only.insert("verb".to_string(), true);
+        params.track_wet_only.insert("plain".to_string(), true);
+        let level = |track: &str, from: f32, to: f32| {
+            let mut params = params.clone();
+            params.track_enabled.insert(if track == "verb" { "plain" } else { "verb" }.to_string(), false);
+            let mut live = LiveState::new(&arr, sr);
+            let mut sum = 0.0f32;
+            for idx in 0..(to * sr) as usize {
+                let (l, r) = SynthEngine::synthesize_single_sample(&arr, idx, sr, &params, None, &mut live, None);
+                if idx as f32 >= from * sr { sum += l.abs() + r.abs(); }
+            }
+            sum
+        };
+        // The note plays for the first 0.25s; the reverb's shortest comb is ~25ms, so before that only the direct signal could sound
+        assert!(level("verb", 0.0, 0.02) < 1e-3, "direct signal leaked");
+        assert!(level("verb", 0.3, 0.6) > 1.0, "reverb tail missing");
+        assert_eq!(level("plain", 0.0, 0.6), 0.0, "track without effects should be silent");
+    }
+
+    #[test]
+    fn recording_stops_at_its_reserved_length() {
+        let engine = SynthEngine::new_offline(1000.0);
+        engine.start_recording(0.01);
+        {
+            let mut guard = engine.recording.lock().unwrap();
+            let rec = guard.as_mut().unwrap();
+            for i in 0..25 { rec.push(i as f32); }
+            assert_eq!(rec.buffer.capacity(), 10);
+        }
+        let captured = engine.stop_recording();
+        assert_eq!(captured, (0..10).map(|i| i as f32).collect::<Vec<_>>());
+        assert!(engine.stop_recording().is_empty());
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0f32, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn clip_ceiling_holds_a_hot_track_down() {
+        let sr = 44100.0;
+        let hot = "waveform: square\nvolume: 2.0\nsustain: 1.0\nnote: A4, 2, 1.0";
+        assert!(peak(&render(&mel(hot), sr)) > 1.0);
+        let clipped = render(&mel(&format!("clip_ceiling: 0.8\n{}", hot)), sr);
+        assert!(peak(&clipped) <= 0.8, "peak {}", peak(&clipped));
+    }
+
+    #[test]
+    fn default_host_is_listed() {
+        let hosts = SynthEngine::list_hosts();
+        // Machines without any usable backend (headless CI) have nothing to compare against
+        if hosts.is_empty() { return; }
+        assert!(hosts.contains(&cpal::default_host().id()));
+    }
+
+    // Sum of |left| + |right| the context's live mix puts out over sample indices `from..to`
+    fn live_level(context: &mut PlaybackContext, from: usize, to: usize, sr: f32) -> f32 {
+        (from..to)
+            .map(|idx| {
+                let (l, r) = SynthEngine::synthesize_single_sample(&context.arrangement, idx, sr, &context.dynamic_params, None, &mut context.live, None);
+                l.abs() + r.abs()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn appended_notes_play_later_in_the_session() {
+        let sr = 8000.0;
+        let engine = SynthEngine::new_offline(sr);
+        let arr = arrangement(&[("lead", "waveform: sine\nnote: A4, 1, 1.0", 0.0)]);
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+        let after = (0.6 * sr) as usize..sr as usize;
+        {
+            let mut guard = engine.playback_context.lock().unwrap();
+            assert_eq!(live_level(guard.as_mut().unwrap(), after.start, after.end, sr), 0.0);
+        }
+
+        let note = mel("note: C5, 1, 1.0").sequence;
+        engine.append_to_track("lead", note).unwrap();
+        assert!(engine.append_to_track("missing", Vec::new()).is_err());
+        let mut guard = engine.playback_context.lock().unwrap();
+        let context = guard.as_mut().unwrap();
+        assert!(live_level(context, after.start, after.end, sr) > 1.0);
+    }
+
+    #[test]
+    fn appending_panned_notes_splits_effects_outside_the_callback() {
+        let sr = 8000.0;
+        let engine = SynthEngine::new_offline(sr);
+        let arr = arrangement(&[("lead", "waveform: sine\ndelay: 0.1, 0.3, 0.3\nnote: A4, 1, 1.0", 0.0)]);
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+        let right = |engine: &SynthEngine| engine.playback_context.lock().unwrap().as_ref()
+            .and_then(|ctx| ctx.live.track_fx[0].as_ref()).is_some_and(|fx| fx.right.is_some());
+        assert!(!right(&engine));
+
+        engine.append_to_track("lead", mel("note: C5, 1, 1.0, pan=0.8").sequence).unwrap();
+        assert!(right(&engine));
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn hpf_removes_most_of_a_low_tone() {
+        let sr = 44100.0;
+        // G1 is ~49 Hz
+        let tone = "waveform: sine\nsustain: 1.0\nnote: G1, 4, 1.0";
+        let dry = render(&mel(tone), sr);
+        let filtered = render(&mel(&format!("hpf: 200\n{}", tone)), sr);
+        let (a, b) = (sr as usize / 2, 3 * sr as usize / 2);
+        assert!(rms(&filtered[a..b]) < 0.3 * rms(&dry[a..b]), "{} vs {}", rms(&filtered[a..b]), rms(&dry[a..b]));
+    }
+
+    #[test]
+    fn normalized_load_brings_a_quiet_sample_to_full_scale() {
+        let dir = fixture_dir("normalize");
+        let path = dir.join("quiet.wav");
+        // Peaks at a quarter of full scale
+        let quiet: Vec<i16> = (0..1000).map(|i| ((i as f32 * 0.05).sin() * 8192.0) as i16).collect();
+        write_pcm16(&path, &quiet);
+        let mut engine = SynthEngine::new_offline(44100.0);
+        engine.load_sample("raw", path.to_str().unwrap()).unwrap();
+        engine.load_sample_normalized("loud", path.to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cache = engine.get_sample_cache();
+        assert!((cache["raw"].peak() - 0.25).abs() < 0.01);
+        assert!((cache["loud"].peak() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sweep_passes_the_geometric_mean_at_its_midpoint() {
+        let sr = 48000.0;
+        let sweep = SynthEngine::new_offline(sr).render_sweep(100.0, 10000.0, 2.0, WaveformType::Sine);
+        // Interpolated upward zero crossings either side of the midpoint give one period
+        let crossing = |from: usize| (from..sweep.len()).find(|&i| sweep[i] < 0.0 && sweep[i + 1] >= 0.0)
+            .map(|i| i as f32 + sweep[i] / (sweep[i] - sweep[i + 1]))
+            .unwrap();
+        let first = crossing(sweep.len() / 2);
+        let period = crossing(first as usize + 1) - first;
+        let freq = sr / period;
+        assert!((freq - 1000.0).abs() < 10.0, "midpoint frequency {}", freq);
+    }
+
+    // LSB-first bit packer for the Vorbis headers
+    #[cfg(feature = "ogg")]
+    #[derive(Default)]
+    struct Bits { bytes: Vec<u8>, used: usize }
+
+    #[cfg(feature = "ogg")]
+    impl Bits {
+        fn put(&mut self, value: u32, bits: usize) {
+            for b in 0..bits {
+                if self.used.is_multiple_of(8) { self.bytes.push(0); }
+                *self.bytes.last_mut().unwrap() |= (((value >> b) & 1) as u8) << (self.used % 8);
+                self.used += 1;
+            }
+        }
+
+        fn put_bytes(&mut self, bytes: &[u8]) {
+            bytes.iter().for_each(|&b| self.put(b as u32, 8));
+        }
+    }
+
+    // Smallest mono Vorbis stream: one 2-entry codebook, a floor 1 and a residue that are never used,
+    // and `packets` 256-sample audio packets whose floor is flagged unused, so they decode to silence.
+    // Each packet after the first yields 128 frames.
+    #[cfg(feature = "ogg")]
+    fn silent_vorbis(sample_rate: u32, packets: usize) -> Vec<u8> {
+        let header = |kind: u32| {
+            let mut bits = Bits::default();
+            bits.put(kind, 8);
+            bits.put_bytes(b"vorbis");
+            bits
+        };
+        let mut ident = header(1);
+        ident.put(0, 32); // Version
+        ident.put(1, 8); // Channels
+        ident.put(sample_rate, 32);
+        (0..3).for_each(|_| ident.put(0, 32)); // Bitrates
+        ident.put(8, 4); // Both block sizes 2^8
+        ident.put(8, 4);
+        ident.put(1, 1);
+
+        let mut comment = header(3);
+        comment.put(0, 32); // No vendor string
+        comment.put(0, 32); // No comments
+        comment.put(1, 1);
+
+        let mut setup = header(5);
+        setup.put(0, 8); // One codebook
+        setup.put(0x564342, 24);
+        setup.put(1, 16); // Dimensions
+        setup.put(2, 24); // Entries
+        setup.put(0, 1); // Not ordered
+        setup.put(0, 1); // Not sparse
+        setup.put(0, 5); // Both codewords 1 bit long
+        setup.put(0, 5);
+        setup.put(0, 4); // No lookup
+        setup.put(0, 6); // One time domain transform
+        setup.put(0, 16);
+        setup.put(0, 6); // One floor, type 1, no partitions
+        setup.put(1, 16);
+        setup.put(0, 5);
+        setup.put(0, 2); // Multiplier 1
+        setup.put(1, 4); // Range bits
+        setup.put(0, 6); // One residue, type 0
+        setup.put(0, 16);
+        setup.put(0, 24); // Begin
+        setup.put(0, 24); // End
+        setup.put(0, 24); // Partition size 1
+        setup.put(0, 6); // One classification
+        setup.put(0, 8); // Class book
+        setup.put(0, 3); // No cascade books
+        setup.put(0, 1);
+        setup.put(0, 6); // One mapping, type 0
+        setup.put(0, 16);
+        setup.put(0, 1); // One submap
+        setup.put(0, 1); // No coupling
+        setup.put(0, 2);
+        setup.put(0, 8); // Submap: time, floor 0, residue 0
+        setup.put(0, 8);
+        setup.put(0, 8);
+        setup.put(0, 6); // One mode: short block, mapping 0
+        setup.put(0, 1);
+        setup.put(0, 16);
+        setup.put(0, 16);
+        setup.put(0, 8);
+        setup.put(1, 1);
+
+        let mut out = Vec::new();
+        let mut writer = ogg::PacketWriter::new(&mut out);
+        // Each header but the comment ends its page, so audio starts on a fresh one
+        for (i, bits) in [ident, comment, setup].into_iter().enumerate() {
+            let end = if i != 1 { ogg::PacketWriteEndInfo::EndPage } else { ogg::PacketWriteEndInfo::NormalPacket };
+            writer.write_packet(bits.bytes.into_boxed_slice(), 1, end, 0).unwrap();
+        }
+        for p in 0..packets {
+            // Audio packet, mode 0 (no bits), floor unused
+            let end = if p + 1 == packets { ogg::PacketWriteEndInfo::EndStream } else { ogg::PacketWriteEndInfo::NormalPacket };
+            writer.write_packet(vec![0u8].into_boxed_slice(), 1, end, (p * 128) as u64).unwrap();
+        }
+        out
+    }
+
+    #[cfg(feature = "ogg")]
+    #[test]
+    fn ogg_sample_decodes_to_its_frame_count() {
+        let dir = fixture_dir("ogg");
+        let path = dir.join("silence.ogg");
+        std::fs::write(&path, silent_vorbis(22050, 11)).unwrap();
+        let mut engine = SynthEngine::new_offline(44100.0);
+        let result = engine.load_sample("silence", path.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        let sample = &engine.get_sample_cache()["silence"];
+        assert_eq!(sample.sample_rate, 22050);
+        assert_eq!(sample.samples.len(), 10 * 128);
+        assert!(sample.samples.iter().all(|&s| s == 0.0));
+    }
+
+    #[cfg(not(feature = "ogg"))]
+    #[test]
+    fn ogg_needs_the_feature() {
+        let engine_result = SynthEngine::new_offline(44100.0).load_sample("x", "missing.ogg");
+        assert!(matches!(engine_result, Err(SynthError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn zero_width_centres_a_hard_panned_track() {
+        let mels = HashMap::from([("lead.mel".to_string(), mel("tempo: 120\nnote: A4, 1, 0.8\n"))]);
+        let arrangement = Arrangement::from_bmi("track: lead.mel, 0, pan=-1\n", &mels).unwrap();
+        let engine = SynthEngine::new_offline(44100.0);
+
+        let (wide_left, wide_right) = engine.synthesize_arrangement_channels(&arrangement, &DynamicParameters::default()).unwrap();
+        assert!(rms(&wide_left) > 10.0 * rms(&wide_right));
+
+        let params = DynamicParameters { master_width: 0.0, ..Default::default() };
+        let (left, right) = engine.synthesize_arrangement_channels(&arrangement, &params).unwrap();
+        assert_eq!(left, right);
+        assert!((rms(&left) - rms(&wide_left) / 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reverb_rings_on_through_a_rest() {
+        let sr = 8000.0;
+        let dry = "tempo: 120\nwaveform: sine\nnote: A4, 0.5, 1.0\nrest: 4\n";
+        let wet = format!("reverb: 0.9, 0.2, 1.0, 0\n{}", dry);
+        let window = ((0.75 * sr) as usize, sr as usize);
+        let engine = SynthEngine::new_offline(sr);
+
+        for (content, ringing) in [(dry, false), (wet.as_str(), true)] {
+            let arr = arrangement(&[("lead", content, 0.0)]);
+            let (left, _) = engine.synthesize_arrangement_channels(&arr, &DynamicParameters::default()).unwrap();
+            assert_eq!(rms(&left[window.0..window.1]) > 1e-3, ringing);
+
+            let mut context = PlaybackContext::new(arr, sr, None, None);
+            live_level(&mut context, 0, window.0, sr);
+            assert_eq!(live_level(&mut context, window.0, window.1, sr) > 1.0, ringing);
+        }
+    }
+
+    #[test]
+    fn reverb_preview_rings_past_the_note() {
+        let sr = 8000.0;
+        let engine = SynthEngine::new_offline(sr);
+        let mut instrument = Instrument::default();
+        let dry = engine.preview_instrument(&instrument, 440.0, 0.25);
+        instrument.effects.reverb = Some(crate::effects::ReverbParams { room_size: 0.9, wet: 1.0, ..Default::default() });
+        let wet = engine.preview_instrument(&instrument, 440.0, 0.25);
+
+        // Past the note and its release
+        let after = (0.5 * sr) as usize;
+        assert!(wet.len() > after && dry[after.min(dry.len())..].iter().all(|&s| s == 0.0));
+        assert!(rms(&wet[after..]) > 1e-3);
+    }
+
+    // Upward zero crossings per second, a pitch estimate for clean tones
+    fn crossing_rate(samples: &[f32], sr: f32) -> f32 {
+        let ups = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        ups as f32 * sr / samples.len() as f32
+    }
+
+    #[test]
+    fn master_pitch_transposes_an_offline_render() {
+        let sr = 44100.0;
+        let engine = SynthEngine::new_offline(sr);
+        let arr = arrangement(&[("lead", "tempo: 60\nwaveform: sine\nnote: A3, 2, 1.0", 0.0)]);
+        let body = (0.25 * sr) as usize..(1.75 * sr) as usize;
+        let rate = |master_pitch: f32| {
+            let params = DynamicParameters { master_pitch, ..Default::default() };
+            let (left, _) = engine.synthesize_arrangement_channels(&arr, &params).unwrap();
+            crossing_rate(&left[body.clone()], sr)
+        };
+        assert!((rate(1.0) - 220.0).abs() < 2.0);
+        assert!((rate(2.0) - 440.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn midi_velocity_scale_maps_127_to_full() {
+        let sr = 8000.0;
+        let level = |header: &str, velocity: &str| {
+            peak(&render(&mel(&format!("{}waveform: sine\nnote: A4, 1, {}", header, velocity)), sr))
+        };
+        let full = level("", "1.0");
+        assert!((level("velocity_scale: midi\n", "127") - full).abs() < 1e-6);
+        assert!((level("velocity_scale: midi\n", "64") / full - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn runaway_velocity_and_volume_are_clamped() {
+        let sr = 8000.0;
+        let hot = mel("volume: 50\nwaveform: sine\nnote: A4, 1, 100");
+        assert_eq!(hot.instrument.volume, 2.0);
+        assert!(matches!(&hot.sequence[0], SequenceElement::Note(n) if n.velocity == 1.0));
+
+        let reference = render(&mel("volume: 2\nwaveform: sine\nnote: A4, 1, 1.0"), sr);
+        assert_eq!(render(&hot, sr), reference);
+        assert!(peak(&reference) <= 2.0);
+    }
+
+    #[test]
+    fn stream_errors_reach_last_stream_error() {
+        let engine = SynthEngine::new_offline(8000.0);
+        assert!(engine.last_stream_error().is_none());
+        SynthEngine::stream_error_handler(Arc::clone(&engine.stream_error), "Stream")(cpal::StreamError::DeviceNotAvailable);
+        let expected = cpal::StreamError::DeviceNotAvailable.to_string();
+        assert!(matches!(engine.last_stream_error(), Some(SynthError::AudioError(msg)) if msg == expected));
+    }
+
+    #[test]
+    fn mono_output_writes_one_sample_per_frame() {
+        let pairs = [(0.2, 0.4), (-1.0, 0.0), (0.5, 0.5)];
+        let fill = |channels: usize| {
+            let mut data = vec![9.0f32; pairs.len() * channels];
+            for (frame, &(l, r)) in data.chunks_mut(channels).zip(&pairs) {
+                SynthEngine::write_frame(frame, l, r);
+            }
+            data
+        };
+        assert_eq!(fill(1), vec![0.3, -0.5, 0.5]);
+        assert_eq!(fill(2), vec![0.2, 0.4, -1.0, 0.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn half_duty_gate_chops_eighths() {
+        let sr = 8000.0;
+        // At 120 BPM an 8th is 0.25s: open for the first 0.125s of each, shut for the rest
+        let out = render(&mel("tempo: 120\nwaveform: sine\ngate_pattern: 0.5, 0.5\nnote: A4, 4, 1.0"), sr);
+        let level = |from: f32, to: f32| rms(&out[(from * sr) as usize..(to * sr) as usize]);
+        for step in 1..7 {
+            let start = step as f32 * 0.25;
+            assert!(level(start + 0.03, start + 0.1) > 0.1, "8th {} should sound", step);
+            assert!(level(start + 0.16, start + 0.23) < 1e-3, "8th {} should be silent", step);
+        }
+    }
+    #[test]
+    fn panned_stereo_sample_keeps_its_right_channel() {
+        let sr = 8000.0;
+        // Content on the right side only
+        let frames: Vec<f32> = (0..sr as usize).flat_map(|_| [0.0, 0.5]).collect();
+        let samples = HashMap::from([("pad".to_string(), SampleData::from_interleaved(&frames, 2, sr as u32))]);
+        let engine = SynthEngine::new_offline(sr);
+        let channels = |pan: f32| {
+            let content = format!("sample: pad\npan: {}\nsustain: 1.0\nnote: C4, 1, 1.0", pan);
+            let track = MelodyTrack::from_mel(&content, &samples).unwrap();
+            let at = (0.25 * sr) as usize;
+            [0, 1].map(|channel| {
+                let mut buffer = vec![0.0; sr as usize];
+                engine.synthesize_track_channel(&mut buffer, &track, 0, &mut fastrand::Rng::with_seed(1), Some(channel));
+                buffer[at]
+            })
+        };
+        let [_, centred] = channels(0.0);
+        assert!(centred > 0.1);
+        // Hard left folds the right channel over instead of dropping it
+        let [left, right] = channels(-1.0);
+        assert!((left - centred).abs() < 1e-4 && right.abs() < 1e-4);
+        // Half way keeps cos(pi/4) of it in place
+        let [left, right] = channels(-0.5);
+        assert!((right / centred - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!((left / centred - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        use std::error::Error;
+        let mut engine = SynthEngine::new_offline(8000.0);
+        let path = fixture_dir("missing").join("nothing-here.wav");
+        let err = engine.load_sample("x", path.to_str().unwrap()).unwrap_err();
+        match &err {
+            SynthError::IoError { path: p, source } => {
+                assert_eq!(p, path.to_str().unwrap());
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected IoError, got {:?}", other),
+        }
+        assert!(err.source().is_some());
+        assert!(matches!(engine.load_melody("x", path.with_extension("mel").to_str().unwrap()), Err(SynthError::IoError { .. })));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn note_release_override_lengthens_the_fade() {
+        let sr = 8000.0;
+        // A 2s note: the instrument's 0.2s release starts at 1.8s, rel=1.0 starts fading at 1.0s
+        let level = |extra: &str, from: f32, to: f32| {
+            let out = render(&mel(&format!("tempo: 120\nwaveform: sine\nsustain: 1.0\nnote: A4, 4, 1.0{}\nrest: 1", extra)), sr);
+            rms(&out[(from * sr) as usize..(to * sr) as usize])
+        };
+        let held = level("", 1.2, 1.5);
+        let fading = level(", rel=1.0", 1.2, 1.5);
+        assert!(fading > 0.1 * held && fading < 0.8 * held, "held {} fading {}", held, fading);
+        assert_eq!(level(", rel=1.0", 0.5, 0.9), level("", 0.5, 0.9));
+    }
+
+    #[test]
+    fn crossfade_from_nothing_fades_in() {
+        let sr = 8000.0;
+        let target = arrangement(&[("lead", "tempo: 60\nwaveform: square\nattack: 0\nnote: A4, 2, 1.0", 0.0)]);
+        let fade = CrossfadeState::new(target, 0.5, sr, false);
+        let mut context = PlaybackContext::new(SynthEngine::silence(1.5), sr, None, Some(fade));
+        let out: Vec<f32> = (0..sr as usize)
+            .map(|_| {
+                let (left, right) = SynthEngine::synthesize_single_sample(&context.arrangement, context.current_sample, sr, &context.dynamic_params, None, &mut context.live, None);
+                let (left, _) = context.crossfade(left, right, sr);
+                context.current_sample += 1;
+                left
+            })
+            .collect();
+        let level = |from: f32, to: f32| peak(&out[(from * sr) as usize..(to * sr) as usize]);
+        assert!(level(0.0, 0.01) < 0.05 * level(0.6, 1.0));
+        assert!(level(0.0, 0.01) < level(0.2, 0.21) && level(0.2, 0.21) < level(0.4, 0.41));
+        assert_eq!(context.arrangement.tracks.len(), 1, "the fade should have handed over");
+    }
+
+    #[test]
+    fn raw_render_keeps_level_and_stems_sum_to_the_mix() {
+        let engine = SynthEngine::new_offline(8000.0);
+        let a = ("a", "tempo: 120\nvolume: 2\nwaveform: square\nnote: A3, 2, 1.0", 0.0);
+        let b = ("b", "tempo: 120\nvolume: 2\nwaveform: square\nnote: E4, 4, 1.0", 0.5);
+        let mix = engine.synthesize_arrangement_raw(&arrangement(&[a, b])).unwrap();
+        assert!(peak(&mix) > 1.0);
+        assert!(peak(&engine.synthesize_arrangement(&arrangement(&[a, b])).unwrap()) <= 1.0);
+
+        let mut stems = vec![0.0f32; mix.len()];
+        for stem in [a, b] {
+            let rendered = engine.synthesize_arrangement_raw(&arrangement(&[stem])).unwrap();
+            assert!(rendered.len() <= mix.len());
+            stems.iter_mut().zip(&rendered).for_each(|(sum, s)| *sum += s);
+        }
+        assert_eq!(stems, mix);
+    }
+
+    // Amplitude of the `freq` component (single DFT bin), for whole numbers of cycles in `samples`
+    fn tone_level(samples: &[f32], freq: f32, sr: f32) -> f32 {
+        let (re, im) = samples.iter().enumerate().fold((0.0f32, 0.0f32), |(re, im), (i, &s)| {
+            let phase = std::f32::consts::TAU * freq * i as f32 / sr;
+            (re + s * phase.cos(), im + s * phase.sin())
+        });
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    #[test]
+    fn octave_down_sub_adds_half_the_frequency() {
+        let sr = 8000.0;
+        // A4 for 2s; the middle second holds whole cycles of both 440 and 220 Hz
+        let body = |content: &str| render(&mel(content), sr)[(0.5 * sr) as usize..(1.5 * sr) as usize].to_vec();
+        let plain = body("tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A4, 2, 1.0");
+        let with_sub = body("tempo: 60\nwaveform: sine\nsustain: 1.0\nsub_osc: sine\nsub_level: 0.5\nsub_octave: -1\nnote: A4, 2, 1.0");
+        let fundamental = tone_level(&plain, 440.0, sr);
+        assert!(tone_level(&plain, 220.0, sr) < 0.01 * fundamental);
+        assert!(tone_level(&with_sub, 220.0, sr) > 0.3 * fundamental);
+    }
+
+    #[test]
+    fn loop_region_wraps_the_playhead() {
+        let sr = 100.0;
+        let engine = SynthEngine::new_offline(sr);
+        let arr = arrangement(&[("lead", "tempo: 60\nnote: A4, 4, 1.0", 0.0)]);
+        assert!(engine.set_loop_region(1.0, 2.0).is_err(), "nothing is playing yet");
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+        assert!(engine.set_loop_region(2.0, 1.0).is_err());
+        engine.set_loop_region(1.0, 2.5).unwrap();
+        assert_eq!(engine.loop_region(), Some((1.0, 2.5)));
+
+        let mut guard = engine.playback_context.lock().unwrap();
+        let context = guard.as_mut().unwrap();
+        let positions: Vec<usize> = (0..1000)
+            .map(|_| {
+                assert!(!context.advance_playhead(sr));
+                context.current_sample
+            })
+            .collect();
+        assert!(positions.iter().all(|&p| p < 250));
+        assert!(positions[300..].iter().all(|&p| p >= 100));
+        let wraps = positions.windows(2).filter(|w| w[1] < w[0]).count();
+        assert_eq!(wraps, 6, "wraps back to 1.0s every 1.5s");
+    }
+
+    #[test]
+    fn float_wav_export_keeps_samples_over_full_scale() {
+        let engine = SynthEngine::new_offline(8000.0);
+        let hot = arrangement(&[
+            ("a", "tempo: 60\nvolume: 2\nwaveform: square\nnote: A3, 1, 1.0", 0.0),
+            ("b", "tempo: 60\nvolume: 2\nwaveform: square\nnote: A3, 1, 1.0", 0.0),
+        ]);
+        let dir = fixture_dir("float-wav");
+        let path = dir.join("hot.wav");
+        engine.export_wav_with_format(&hot, path.to_str().unwrap(), WavFormat::Float32).unwrap();
+        let read: Vec<f32> = hound::WavReader::open(&path).unwrap().samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(peak(&read) > 1.0);
+        assert_eq!(read, engine.synthesize_arrangement_raw(&hot).unwrap());
+    }
+
+    #[test]
+    fn same_named_tracks_are_controlled_separately() {
+        let sr = 8000.0;
+        let mels = HashMap::from([
+            ("low.mel".to_string(), mel("tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A4, 2, 1.0")),
+            ("high.mel".to_string(), mel("tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A5, 2, 1.0")),
+        ]);
+        let arr = Arrangement::from_bmi("track: low.mel, 0, name=lead\ntrack: high.mel, 0, name=lead\n", &mels).unwrap();
+        let engine = SynthEngine::new_offline(sr);
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+        engine.set_track_volume("lead#2", 0.0);
+
+        // Levels of the two tones over the middle second
+        let mut guard = engine.playback_context.lock().unwrap();
+        let context = guard.as_mut().unwrap();
+        let out: Vec<f32> = (0..(1.5 * sr) as usize)
+            .map(|idx| SynthEngine::synthesize_single_sample(&context.arrangement, idx, sr, &context.dynamic_params, None, &mut context.live, None).0)
+            .skip((0.5 * sr) as usize)
+            .collect();
+        assert!(tone_level(&out, 440.0, sr) > 0.1);
+        assert!(tone_level(&out, 880.0, sr) < 1e-3);
+        assert_eq!(context.dynamic_params.track_volumes["lead"], 1.0);
+    }
+
+    #[test]
+    fn late_onsets_do_not_drift_at_common_rates() {
+        // 150 odd-length note/rest pairs: per-note sample truncation would pile up to many samples
+        let pair = "note: C1, 0.137, 1.0\nrest: 0.211\n";
+        let content = format!("tempo: 97\ndrum: C1=hit\nattack: 0\nsustain: 1.0\n{}note: C1, 1, 1.0", pair.repeat(150));
+        let theory = 150.0 * (0.137 + 0.211) * 60.0 / 97.0;
+        for sr in [44100.0, 48000.0] {
+            // A short hit that is non-zero from its first sample, silent well before the next onset
+            let samples = HashMap::from([("hit".to_string(), constant_sample(0.5, 0.05, sr))]);
+            let out = render(&MelodyTrack::from_mel(&content, &samples).unwrap(), sr);
+            let search = ((theory - 0.05) * sr) as usize;
+            let onset = search + out[search..].iter().position(|s| s.abs() > 1e-6).unwrap();
+            assert!((onset as f64 - theory as f64 * sr as f64).abs() <= 1.0, "{} Hz: onset {} vs {}", sr, onset, theory * sr);
+        }
+    }
+
+    #[test]
+    fn loaded_melodies_can_be_inspected() {
+        let dir = fixture_dir("get-melody");
+        let path = dir.join("riff.mel");
+        std::fs::write(&path, "waveform: triangle\nnote: C4, 1, 1.0\nchord: C4+E4, 1, 1.0\nrest: 1\nnote: G4, 1, 1.0\n").unwrap();
+        let mut engine = SynthEngine::new_offline(8000.0);
+        let loaded = engine.load_melody("riff", path.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+
+        let track = engine.get_melody("riff").unwrap();
+        let notes = track.sequence.iter().filter(|e| matches!(e, SequenceElement::Note(_))).count();
+        assert_eq!((track.sequence.len(), notes), (4, 2));
+        assert!(matches!(track.instrument.source, InstrumentSource::Synthesized(WaveformType::Triangle)));
+        assert!(engine.get_melody("missing").is_none());
+    }
+
+    #[test]
+    fn shifted_note_moves_alone() {
+        let sr = 8000.0;
+        let samples = HashMap::from([("hit".to_string(), constant_sample(0.5, 0.05, sr))]);
+        let content = "tempo: 60\ndrum: C1=hit\nattack: 0\nsustain: 1.0\nnote: C1, 1, 1.0\nnote: C1, 1, 1.0, shift=-20\nnote: C1, 1, 1.0";
+        let out = render(&MelodyTrack::from_mel(content, &samples).unwrap(), sr);
+        let onsets: Vec<usize> = (0..out.len())
+            .filter(|&i| out[i] != 0.0 && (i == 0 || out[i - 1] == 0.0))
+            .collect();
+        assert_eq!(onsets, vec![0, (0.98 * sr) as usize, (2.0 * sr) as usize]);
+    }
+
+    #[test]
+    fn wave_override_changes_only_its_note() {
+        let sr = 8000.0;
+        let out = render(&mel("tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A4, 1, 1.0\nnote: A4, 1, 1.0, wave=square\nnote: A4, 1, 1.0"), sr);
+        // RMS over peak in the middle of each note: 1/sqrt(2) for a sine, near 1 for a (band-limited) square
+        let crest = |note: usize| {
+            let body = &out[((note as f32 + 0.25) * sr) as usize..((note as f32 + 0.75) * sr) as usize];
+            rms(body) / peak(body)
+        };
+        assert!((crest(0) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(crest(1) > 0.85, "square crest {}", crest(1));
+        assert!((crest(2) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+    }
+
+    #[test]
+    fn synced_ping_pong_taps_alternate_on_the_dotted_eighth_grid() {
+        let sr = 8000.0;
+        let arr = arrangement(&[("lead", "tempo: 120\nattack: 0\nsustain: 1.0\ndelay_synced_pingpong: 1/8., 0.5, 1.0\nnote: A4, 0.1, 1.0\nrest: 7.9", 0.0)]);
+        let (left, right) = SynthEngine::new_offline(sr)
+            .synthesize_arrangement_channels(&arr, &DynamicParameters::default())
+            .unwrap();
+        // A dotted eighth at 120 BPM is 0.375 s: odd taps on the left, even taps on the right
+        for tap in 1..=4 {
+            let at = (tap as f32 * 0.375 * sr) as usize;
+            let window = at..at + (0.05 * sr) as usize;
+            let (l, r) = (rms(&left[window.clone()]), rms(&right[window]));
+            let (near, far) = if tap % 2 == 1 { (l, r) } else { (r, l) };
+            assert!(near > 0.01 && far < near * 0.01, "tap {}: left {} right {}", tap, l, r);
+            // Nothing just before the tap on either side
+            let before = at - (0.03 * sr) as usize..at - (0.005 * sr) as usize;
+            assert!(peak(&left[before.clone()]) < near * 0.01 && peak(&right[before]) < near * 0.01);
+        }
+    }
+
+    #[test]
+    fn two_loops_double_the_length_without_a_click() {
+        let sr = 8000.0;
+        let arr = arrangement(&[("lead", "tempo: 60\nattack: 0\nsustain: 1.0\nrelease: 0\nnote: A3, 2, 0.8", 0.0)]);
+        let engine = SynthEngine::new_offline(sr);
+        let once = engine.synthesize_arrangement_looped(&arr, 1, 0.01).unwrap();
+        let twice = engine.synthesize_arrangement_looped(&arr, 2, 0.01).unwrap();
+        let overlap = (0.01 * sr) as usize;
+        assert_eq!(twice.len(), 2 * once.len() - overlap);
+
+        // The biggest sample-to-sample step around the seam is no bigger than the tone's own
+        let step = |s: &[f32]| s.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0f32, f32::max);
+        let seam = once.len() - overlap;
+        let tone = step(&once[once.len() / 4..once.len() * 3 / 4]);
+        assert!(step(&twice[seam - 100..seam + overlap + 100]) <= tone * 1.05);
+    }
+
+    #[test]
+    fn arrangement_seed_fixes_the_render() {
+        let mels = HashMap::from([("hiss.mel".to_string(), mel("tempo: 120\nwaveform: noise\nnote: A4, 1, 0.8\n"))]);
+        let seeded = |seed: u64| Arrangement::from_bmi(&format!("seed: {}\ntrack: hiss.mel, 0\n", seed), &mels).unwrap();
+        let engine = SynthEngine::new_offline(8000.0);
+        let first = engine.synthesize_arrangement(&seeded(7)).unwrap();
+        assert_eq!(first, engine.synthesize_arrangement(&seeded(7)).unwrap());
+        assert_ne!(first, engine.synthesize_arrangement(&seeded(8)).unwrap());
+    }
+
+    #[test]
+    fn fade_track_ramps_to_silence_before_disabling() {
+        let sr = 8000.0;
+        let engine = SynthEngine::new_offline(sr);
+        let arr = arrangement(&[("lead", "tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A4, 4, 1.0", 0.0)]);
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+        engine.fade_track("lead", false, 0.1);
+
+        let mut guard = engine.playback_context.lock().unwrap();
+        let context = guard.as_mut().unwrap();
+        let start = sr as usize;
+        let ramp = (0.1 * sr) as usize;
+        let mut last_gain = 1.0;
+        for (n, idx) in (start..start + ramp).enumerate() {
+            context.step_track_fades();
+            let gain = context.dynamic_params.track_fade.get("lead").copied().unwrap_or(0.0);
+            // An even step down every sample, no jump to zero
+            assert!(last_gain - gain <= 1.0 / ramp as f32 + 1e-4 && gain <= last_gain, "sample {}", n);
+            last_gain = gain;
+            if n + 1 < ramp {
+                assert_eq!(context.dynamic_params.track_enabled.get("lead"), Some(&true));
+            }
+            let (l, r) = SynthEngine::synthesize_single_sample(&context.arrangement, idx, sr, &context.dynamic_params, None, &mut context.live, None);
+            assert!(l.abs().max(r.abs()) <= gain + 1e-4);
+        }
+        context.step_track_fades();
+        assert!(context.track_fades.is_empty() && context.dynamic_params.track_fade.get("lead") == Some(&0.0));
+        assert_eq!(context.dynamic_params.track_enabled.get("lead"), Some(&false));
+        assert_eq!(live_level(context, start + ramp, start + ramp + 800, sr), 0.0);
+    }
+
+    #[test]
+    fn current_lyric_follows_the_playhead() {
+        let sr = 8000.0;
+        let mels = HashMap::from([("lead.mel".to_string(), mel("tempo: 60\nnote: A4, 8, 0.8\n"))]);
+        let bmi = "track: lead.mel, 0\nlyric: 4.0, second line\nlyric: 1.5, first line, with a comma\n";
+        let arr = Arrangement::from_bmi(bmi, &mels).unwrap();
+        let engine = SynthEngine::new_offline(sr);
+        assert_eq!(engine.current_lyric(), None);
+        *engine.playback_context.lock().unwrap() = Some(PlaybackContext::new(arr, sr, None, None));
+
+        let at = |seconds: f32| {
+            engine.playback_context.lock().unwrap().as_mut().unwrap().current_sample = (seconds * sr) as usize;
+            engine.current_lyric()
+        };
+        assert_eq!(at(1.0), None);
+        assert_eq!(at(1.5).as_deref(), Some("first line, with a comma"));
+        assert_eq!(at(3.9).as_deref(), Some("first line, with a comma"));
+        assert_eq!(at(6.0).as_deref(), Some("second line"));
+    }
+
+    #[test]
+    fn pan_spread_renders_are_repeatable_per_channel() {
+        let arr = arrangement(&[
+            ("keys", "tempo: 120\npan_spread: 0.8\nchord: C4+E4+G4, 1, 0.6\nchord: D4+F4+A4, 1, 0.6\nnote: C5, 0.5, 0.8\nnote: E5, 0.5, 0.8", 0.0),
+            ("bass", "tempo: 120\nwaveform: sawtooth\npan_spread: 0.5\nnote: C2, 1, 0.8\nnote: G2, 1, 0.8", 0.5),
+        ]);
+        let render = || SynthEngine::new_offline(8000.0).synthesize_arrangement_channels(&arr, &DynamicParameters::default()).unwrap();
+        let (left, right) = render();
+        assert_ne!(left, right);
+        assert_eq!((left, right), render());
+    }
+
+    #[test]
+    fn noise_renders_are_byte_identical() {
+        let arr = arrangement(&[
+            ("white", "waveform: noise\nnote: A4, 1, 0.8", 0.0),
+            ("pink", "waveform: pink\nnote: A4, 1, 0.8", 0.25),
+            ("brown", "waveform: brown\nnote: A4, 1, 0.8", 0.5),
+        ]);
+        let first = SynthEngine::new_offline(8000.0).synthesize_arrangement(&arr).unwrap();
+        // Another engine rendering in between must not disturb the next render
+        let other = SynthEngine::new_offline(8000.0);
+        other.synthesize_arrangement(&arr).unwrap();
+        let second = SynthEngine::new_offline(8000.0).synthesize_arrangement(&arr).unwrap();
+        assert!(peak(&first) > 0.0);
+        assert_eq!(first.iter().map(|s| s.to_bits()).collect::<Vec<_>>(), second.iter().map(|s| s.to_bits()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vibrato_sweeps_the_spectral_peak_around_the_carrier() {
+        let sr = 8000.0;
+        // Strongest bin between 380 and 500 Hz, in 1 Hz steps, of each 40 ms window
+        let peaks = |content: &str| {
+            let out = render(&mel(content), sr);
+            let window = (0.04 * sr) as usize;
+            (0..20)
+                .map(|w| {
+                    let at = (0.25 * sr) as usize + w * window;
+                    (380..=500).max_by(|&a, &b| {
+                        let level = |f: i32| tone_level(&out[at..at + window], f as f32, sr);
+                        level(a).total_cmp(&level(b))
+                    }).unwrap() as f32
+                })
+                .collect::<Vec<f32>>()
+        };
+        let steady = peaks("tempo: 60\nwaveform: sine\nsustain: 1.0\nnote: A4, 2, 1.0");
+        assert!(steady.iter().all(|f| (f - 440.0).abs() <= 2.0), "{:?}", steady);
+
+        // 100 cents either way is roughly 415 to 466 Hz, one full cycle across the 0.8 s measured
+        let wobbly = peaks("tempo: 60\nwaveform: sine\nsustain: 1.0\nvibrato: 1.25, 100\nnote: A4, 2, 1.0");
+        let (low, high) = wobbly.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &f| (lo.min(f), hi.max(f)));
+        assert!(low < 425.0 && high > 455.0, "{:?}", wobbly);
+        assert!((mean(&wobbly) - 440.0).abs() < 8.0, "{:?}", wobbly);
+    }
+
+    #[test]
+    fn exponential_attack_is_further_along_at_its_midpoint() {
+        let source = "attack: 0.2\ndecay: 0\nsustain: 1.0\nrelease: 0.1\nnote: A4, 4, 1.0";
+        let linear = mel(source).instrument;
+        let exp = mel(&format!("env_curve: exp\n{}", source)).instrument;
+        let at_mid = |instr: &Instrument| SynthEngine::calculate_envelope_static(0.1, 2.0, instr, instr.release);
+        assert!((at_mid(&linear) - 0.5).abs() < 1e-6);
+        assert!(at_mid(&exp) > 0.8 && at_mid(&exp) < 1.0, "{}", at_mid(&exp));
+        // Both reach full level at the end of the attack
+        for instr in [&linear, &exp] {
+            assert!((SynthEngine::calculate_envelope_static(0.2, 2.0, instr, instr.release) - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn crossfade_target_starts_from_its_own_beginning() {
+        let sr = 8000.0;
+        let mut engine = SynthEngine::new_offline(sr);
+        let mut outgoing = PlaybackContext::new(SynthEngine::silence(10.0), sr, None, None);
+        outgoing.current_sample = 5 * sr as usize;
+        *engine.playback_context.lock().unwrap() = Some(outgoing);
+
+        // Sound only in the target's first 0.1 s: a target read from the outgoing playhead would stay silent
+        let target = arrangement(&[("intro", "tempo: 60\nwaveform: square\nattack: 0\nnote: A4, 0.1, 1.0\nrest: 9.9", 0.0)]);
+        engine.crossfade_to(target, 0.5).unwrap();
+        let mut guard = engine.playback_context.lock().unwrap();
+        let context = guard.as_mut().unwrap();
+        let out: Vec<f32> = (0..(0.5 * sr) as usize)
+            .map(|_| {
+                let (left, right) = SynthEngine::synthesize_single_sample(&context.arrangement, context.current_sample, sr, &context.dynamic_params, None, &mut context.live, None);
+                let (left, _) = context.crossfade(left, right, sr);
+                context.current_sample += 1;
+                left
+            })
+            .collect();
+        assert!(peak(&out[..(0.1 * sr) as usize]) > 0.01);
+        assert_eq!(peak(&out[(0.15 * sr) as usize..]), 0.0);
+        // Handed over with the playhead where the target had got to, not back at 5 s
+        assert!(context.crossfade_state.is_none());
+        assert_eq!(context.current_sample, (0.5 * sr) as usize);
+    }
+
+    #[test]
+    fn wav_formats_load_into_the_same_range() {
+        let dir = fixture_dir("wav-formats");
+        let write = |name: &str, bits: u16, format: hound::SampleFormat, channels: u16, frames: &[f32]| {
+            let path = dir.join(name);
+            let spec = hound::WavSpec { channels, sample_rate: 22050, bits_per_sample: bits, sample_format: format };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            let full_scale = (1i64 << (bits - 1)) as f32;
+            for &x in frames {
+                match (format, bits) {
+                    (hound::SampleFormat::Float, _) => writer.write_sample(x).unwrap(),
+                    (_, 8) => writer.write_sample((x * full_scale).clamp(-128.0, 127.0) as i8).unwrap(),
+                    _ => writer.write_sample((x * full_scale).clamp(-full_scale, full_scale - 1.0) as i32).unwrap(),
+                }
+            }
+            writer.finalize().unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let mut engine = SynthEngine::new_offline(44100.0);
+        let frames = [0.5, -1.0, 0.25, 0.0];
+        for (name, bits, format) in [
+            ("int8.wav", 8, hound::SampleFormat::Int),
+            ("int16.wav", 16, hound::SampleFormat::Int),
+            ("int24.wav", 24, hound::SampleFormat::Int),
+            ("int32.wav", 32, hound::SampleFormat::Int),
+            ("float32.wav", 32, hound::SampleFormat::Float),
+        ] {
+            engine.load_sample(name, &write(name, bits, format, 1, &frames)).unwrap();
+            let sd = &engine.get_sample_cache()[name];
+            assert_eq!(sd.sample_rate, 22050);
+            for (got, want) in sd.samples.iter().zip(frames) {
+                assert!((got - want).abs() < 1.0 / 64.0, "{}: {} vs {}", name, got, want);
+            }
+        }
+
+        // Interleaved stereo is mixed down to mono, the sides kept as the image
+        engine.load_sample("stereo", &write("stereo.wav", 16, hound::SampleFormat::Int, 2, &[0.5, 0.0, -0.5, -0.25])).unwrap();
+        let sd = &engine.get_sample_cache()["stereo"];
+        assert_eq!(sd.samples.len(), 2);
+        assert!((sd.samples[0] - 0.25).abs() < 1e-3 && (sd.samples[1] + 0.375).abs() < 1e-3);
+        let (left, right) = sd.stereo.as_deref().unwrap();
+        assert!((left[1] + 0.5).abs() < 1e-3 && right[0].abs() < 1e-3);
+
+        let float64 = dir.join("float64.wav");
+        std::fs::write(&float64, [b"RIFF".as_slice(), &36u32.to_le_bytes(), b"WAVEfmt ", &16u32.to_le_bytes(),
+            &3u16.to_le_bytes(), &1u16.to_le_bytes(), &22050u32.to_le_bytes(), &176400u32.to_le_bytes(),
+            &8u16.to_le_bytes(), &64u16.to_le_bytes(), b"data", &0u32.to_le_bytes()].concat()).unwrap();
+        assert!(matches!(engine.load_sample("f64", &float64.to_string_lossy()), Err(SynthError::UnsupportedFormat(_))));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn crossfade_hands_the_cue_a_prebuilt_state() {
+        let sr = 8000.0;
+        let target = arrangement(&[("lead", "note: A4, 1, 1.0", 0.0), ("bass", "note: A2, 1, 1.0", 0.0)]);
+        let silence = SynthEngine::silence(1.0);
+        let cue = CueRender::new(&silence, sr, CueMix::default());
+        let mut context = PlaybackContext::new(silence, sr, Some(cue), Some(CrossfadeState::new(target, 0.01, sr, true)));
+        while context.crossfade_state.is_some() {
+            context.crossfade(0.0, 0.0, sr);
+            context.current_sample += 1;
         }
+        assert_eq!(context.cue.as_ref().unwrap().live.track_fx.len(), 2);
     }
 }
@@ -1,6 +1,14 @@
 use std::fmt;
+use std::sync::Arc;
 
-impl std::error::Error for SynthError {}
+impl std::error::Error for SynthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SynthError::IoError { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum SynthError {
@@ -8,6 +16,15 @@ pub enum SynthError {
     FileError(String),
     AudioError(String),
     InvalidInstrument(String),
+    IoError { path: String, source: Arc<std::io::Error> }, // source.kind() tells a missing file from a permission error
+    UnsupportedFormat(String), // Read fine but can't be decoded (codec, bit depth, feature not enabled)
+    DeviceError(String), // Audio host/device missing or not capable of the requested config
+}
+
+impl SynthError {
+    pub fn io(path: &str, err: std::io::Error) -> Self {
+        SynthError::IoError { path: path.to_string(), source: Arc::new(err) }
+    }
 }
 
 impl fmt::Display for SynthError { // TODO, expand
@@ -17,6 +34,9 @@ impl fmt::Display for SynthError { // TODO, expand
             SynthError::FileError(msg) => write!(f, "File Error: {}", msg),
             SynthError::AudioError(msg) => write!(f, "Audio Error: {}", msg),
             SynthError::InvalidInstrument(msg) => write!(f, "Invalid Instrument Error: {}", msg),
+            SynthError::IoError { path, source } => write!(f, "IO Error: '{}': {}", path, source),
+            SynthError::UnsupportedFormat(msg) => write!(f, "Unsupported Format: {}", msg),
+            SynthError::DeviceError(msg) => write!(f, "Device Error: {}", msg),
         }
     }
-}
\ No newline at end of file
+}
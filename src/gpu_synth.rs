@@ -18,25 +18,24 @@
 //   - so adding a waveform only requires touching waveform.rs.
 //
 // CPU & GPU parity strategy:
-//   - Synthesized instruments (non-Noise waveforms) -> GPU path.
-//   - Sample-based instruments and Noise waveform   -> CPU fallback.
+//   - Synthesized instruments (non-Noise, non-Pulse waveforms) -> GPU path.
+//   - Sample-based instruments, Noise variants, Pulse -> CPU fallback.
 //   - Stateful effects                              -> always CPU-side post-readback.
-//   - Arrangement-level fade & normalisation        -> CPU-side.
+//   - Arrangement-level compressor, fade & normalisation -> CPU-side.
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::error::Error;
 
 use bytemuck::{Pod, Zeroable};
 use futures_channel::mpsc;
 use wgpu::util::DeviceExt;
 
 use crate::arrangement::Arrangement;
-use crate::effects::EffectsProcessor;
-use crate::engine::{DynamicParameters, SynthEngine};
+use crate::engine::{DynamicParameters, SampleDirLoad, SynthEngine};
 use crate::error::SynthError;
-use crate::instrument::{InstrumentSource, SampleData, SequenceElement};
-use crate::track::MelodyTrack;
+use crate::effects::BusCompressor;
+use crate::instrument::{InstrumentSource, SampleData, SequenceElement, EnvelopeCurve};
+use crate::track::{MelodyTrack, MAX_NOTE_SHIFT};
 use crate::waveform::WaveformType;
 
 
@@ -194,7 +193,7 @@ impl GpuSynthEngine {
                 force_fallback_adapter: false,
             })
             .await
-            .map_err(|e| SynthError::AudioError(format!("GPU adapter request failed: {e}")))?;
+            .map_err(|e| SynthError::DeviceError(format!("GPU adapter request failed: {e}")))?;
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
                 label: None,
@@ -205,7 +204,7 @@ impl GpuSynthEngine {
                 trace: wgpu::Trace::Off,
             })
             .await
-            .map_err(|e| SynthError::AudioError(format!("GPU device creation failed: {e}")))?;
+            .map_err(|e| SynthError::DeviceError(format!("GPU device creation failed: {e}")))?;
 
         let sample_rate = 44_100.0f32;
         let cpu = SynthEngine::new_offline(sample_rate);
@@ -214,11 +213,15 @@ impl GpuSynthEngine {
     }
 
     // Can be considered redundant
-    pub fn load_sample(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn load_sample(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
         self.cpu.load_sample(name, path)
     }
 
-    pub fn load_melody(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn load_sample_dir(&mut self, dir: &str) -> Result<SampleDirLoad, SynthError> {
+        self.cpu.load_sample_dir(dir)
+    }
+
+    pub fn load_melody(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
         self.cpu.load_melody(name, path)
     }
 
@@ -228,9 +231,9 @@ impl GpuSynthEngine {
 
 
     // Load a WGSL shader from `path` and cache it under `name`.
-    pub fn load_shader(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn load_shader(&mut self, name: &str, path: &str) -> Result<(), SynthError> {
         let source = std::fs::read_to_string(path)
-            .map_err(|e| SynthError::FileError(e.to_string()))?;
+            .map_err(|e| SynthError::io(path, e))?;
         self.shader_cache.insert(name.to_string(), source);
         Ok(())
     }
@@ -329,10 +332,10 @@ impl GpuSynthEngine {
         arrangement: &Arrangement,
         params: &DynamicParameters,
     ) -> Result<Vec<f32>, SynthError> {
-        let total_samples = (arrangement.total_length * self.sample_rate) as usize;
+        let total_samples = (self.cpu.render_seconds(arrangement) * self.sample_rate) as usize;
         let mut buffer = vec![0.0f32; total_samples];
 
-        for (track, start_time, overrides) in &arrangement.tracks {
+        for (i, (track, start_time, overrides)) in arrangement.tracks.iter().enumerate() {
             let enabled = params.track_enabled.get(&track.name).copied().unwrap_or(true);
             if !enabled { continue; }
             let track_vol = params.track_volumes.get(&track.name).copied().unwrap_or(1.0);
@@ -340,38 +343,34 @@ impl GpuSynthEngine {
 
             let mut t = track.clone();
             if let Some(v) = overrides.volume     { t.instrument.volume = v; }
-            if let Some(p) = overrides.pitch       { t.instrument.pitch  = p * params.master_pitch; }
+            if let Some(p) = overrides.pitch       { t.instrument.pitch  = p; }
+            SynthEngine::apply_master_pitch(&mut t, params.master_pitch);
             if let Some(tm) = overrides.tempo      { t.tempo = tm; }
-            if let Some(r) = &overrides.reverb     { t.instrument.effects.reverb     = Some(r.clone()); }
-            if let Some(d) = &overrides.delay      { t.instrument.effects.delay      = Some(d.clone()); }
-            if let Some(x) = &overrides.distortion { t.instrument.effects.distortion = Some(x.clone()); }
-            if let Some(f) = &overrides.filter     { t.instrument.effects.filter     = Some(f.clone()); }
+            t.instrument.effects = overrides.effects_over(&t.instrument.effects);
             t.instrument.volume *= track_vol;
 
-            let track_total = (t.length * self.sample_rate) as usize;
+            let track_total = self.cpu.track_samples(&t);
             if track_total == 0 { continue; }
 
             let mut track_buf = if Self::gpu_waveform(&t).is_some() {
                 match self.synthesize_track_gpu(&t, track_total).await {
-                    Ok(buf) => buf,
+                    Ok(mut buf) => {
+                        self.cpu.condition_track(&mut buf, &t, 0);
+                        buf
+                    }
                     Err(_) => {
                         let mut buf = vec![0.0f32; track_total];
-                        self.cpu.synthesize_track_into(&mut buf, &t, 0);
+                        self.cpu.synthesize_track_into(&mut buf, &t, 0, &mut self.cpu.track_rng(arrangement, i));
                         buf
                     }
                 }
             } else {
                 let mut buf = vec![0.0f32; track_total];
-                self.cpu.synthesize_track_into(&mut buf, &t, 0);
+                self.cpu.synthesize_track_into(&mut buf, &t, 0, &mut self.cpu.track_rng(arrangement, i));
                 buf
             };
 
-            if t.instrument.effects.has_any() {
-                let mut fx = EffectsProcessor::new(self.sample_rate);
-                for s in track_buf.iter_mut() {
-                    *s = fx.process(*s, &t.instrument.effects);
-                }
-            }
+            self.cpu.apply_track_effects(&mut track_buf, &t);
 
             for (i, &s) in track_buf.iter().enumerate() {
                 if let Some(dst) = buffer.get_mut(start_samp + i) {
@@ -380,14 +379,18 @@ impl GpuSynthEngine {
             }
         }
 
+        if let Some(comp) = &arrangement.compressor {
+            let mut bus = BusCompressor::new(self.sample_rate);
+            buffer.iter_mut().for_each(|s| *s = bus.process(*s, comp));
+        }
         if let Some(fi) = arrangement.fade_in {
             let n = (fi * self.sample_rate) as usize;
-            for i in 0..n.min(buffer.len()) { buffer[i] *= i as f32 / n as f32; }
+            for i in 0..n.min(buffer.len()) { buffer[i] *= arrangement.fade_curve.apply(i as f32 / n as f32); }
         }
         if let Some(fo) = arrangement.fade_out {
             let n = (fo * self.sample_rate) as usize;
             let fs = buffer.len().saturating_sub(n);
-            for i in fs..buffer.len() { buffer[i] *= (buffer.len() - i) as f32 / n as f32; }
+            for i in fs..buffer.len() { buffer[i] *= arrangement.fade_curve.apply((buffer.len() - i) as f32 / n as f32); }
         }
         if let Some(max) = buffer.iter().map(|v| v.abs()).max_by(|a, b| a.partial_cmp(b).unwrap()) {
             if max > 1.0 { buffer.iter_mut().for_each(|s| *s /= max); }
@@ -398,7 +401,18 @@ impl GpuSynthEngine {
 
     fn gpu_waveform(track: &MelodyTrack) -> Option<WaveformType> {
         match &track.instrument.source {
-            InstrumentSource::Sample(_) => None,
+            InstrumentSource::Sample(_) | InstrumentSource::DrumMap(_) => None,
+            // The shader has no sub-oscillator, those tracks stay on the CPU
+            InstrumentSource::Synthesized(_) if track.instrument.sub_osc.is_some() => None,
+            // Nor vibrato, tremolo, detune unison, curved envelopes or a hold stage
+            InstrumentSource::Synthesized(_) if track.instrument.vibrato.depth_cents != 0.0
+                || track.instrument.env_curve != EnvelopeCurve::Linear
+                || track.instrument.hold != 0.0
+                || track.instrument.tremolo.depth != 0.0
+                || track.instrument.detune != 0.0 => None,
+            // Nor per-note waveforms or overlapping release tails
+            InstrumentSource::Synthesized(_) if track.sequence.iter()
+                .any(|e| matches!(e, SequenceElement::Note(note) if note.wave.is_some() || note.overlap)) => None,
             InstrumentSource::Synthesized(wf) => wf.gpu_id().map(|_| *wf),
         }
     }
@@ -411,19 +425,23 @@ impl GpuSynthEngine {
         let wf = Self::gpu_waveform(track).ok_or("Track is not GPU-eligible")?;
         let wf_id = wf.gpu_id().unwrap();
         let sr = self.sample_rate;
-        let beat = 60.0 / track.tempo;
+        let mut beat = track.beat_duration();
 
         let mut gpu_notes: Vec<GpuNoteData> = Vec::new();
-        let mut offset = 0usize;
+        // Onsets from a running time in seconds, same rounding as the CPU render
+        let to_sample = |t: f64| (t * sr as f64).round() as usize;
+        let mut elapsed = 0.0f64;
 
         for element in &track.sequence {
+            let start = to_sample(elapsed);
+            let end = to_sample(elapsed + element.seconds(beat) as f64).min(total_samples);
             match element {
                 SequenceElement::Note(note) => {
-                    let dur_n = (note.duration * beat * sr) as usize;
-                    let start = offset;
-                    let end = (start + dur_n).min(total_samples);
+                    let shift = note.shift.clamp(-MAX_NOTE_SHIFT, MAX_NOTE_SHIFT) as f64;
+                    let start = to_sample((elapsed + shift).max(0.0));
+                    let end = to_sample((elapsed + shift + element.seconds(beat) as f64).max(0.0)).min(total_samples);
                     if start < end {
-                        let (att, dec, rel, rel_st) = adsr_samps(&track.instrument, end, sr);
+                        let (att, dec, rel, rel_st) = adsr_samps(&track.instrument, note.release, end, sr);
                         gpu_notes.push(GpuNoteData {
                             start_sample: start as u32,
                             end_sample: end as u32,
@@ -439,14 +457,10 @@ impl GpuSynthEngine {
                             slide_to_pitch: note.slide_to.unwrap_or(note.pitch),
                         });
                     }
-                    offset += dur_n;
                 }
                 SequenceElement::Chord(chord) => {
-                    let dur_n = (chord.duration * beat * sr) as usize;
-                    let start = offset;
-                    let end = (start + dur_n).min(total_samples);
                     if start < end {
-                        let (att, dec, rel, rel_st) = adsr_samps(&track.instrument, end, sr);
+                        let (att, dec, rel, rel_st) = adsr_samps(&track.instrument, chord.release, end, sr);
                         let vol_per = track.instrument.volume / chord.pitches.len() as f32;
                         for &pitch in &chord.pitches {
                             gpu_notes.push(GpuNoteData {
@@ -465,10 +479,11 @@ impl GpuSynthEngine {
                             });
                         }
                     }
-                    offset += dur_n;
                 }
-                SequenceElement::Rest(d) => { offset += (d * beat * sr) as usize; }
+                SequenceElement::Rest(_) => {}
+                SequenceElement::TempoChange(tempo) => { beat = track.beat_duration_at(*tempo); }
             }
+            elapsed += element.seconds(beat) as f64;
         }
 
         // Nothing to synth, billions must die
@@ -624,6 +639,18 @@ impl GpuSynthEngine {
     pub fn get_sample_cache(&self) -> &HashMap<String, SampleData> {
         self.cpu.get_sample_cache()
     }
+
+    pub fn set_render_seed(&mut self, seed: u64) {
+        self.cpu.set_render_seed(seed);
+    }
+
+    pub fn set_render_tails(&mut self, enabled: bool) {
+        self.cpu.set_render_tails(enabled);
+    }
+
+    pub fn get_melody(&self, name: &str) -> Option<&MelodyTrack> {
+        self.cpu.get_melody(name)
+    }
 }
 
 
@@ -636,10 +663,10 @@ fn bgl_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayout
     }
 }
 
-fn adsr_samps(instr: &crate::instrument::Instrument, end: usize, sr: f32) -> (u32, u32, u32, u32) {
+fn adsr_samps(instr: &crate::instrument::Instrument, release: Option<f32>, end: usize, sr: f32) -> (u32, u32, u32, u32) {
     let att = (instr.attack  * sr) as u32;
     let dec = (instr.decay   * sr) as u32;
-    let rel = (instr.release * sr) as u32;
+    let rel = (release.unwrap_or(instr.release) * sr) as u32;
     let rel_st = end.saturating_sub(rel as usize) as u32;
     (att, dec, rel, rel_st)
 }
@@ -1,17 +1,137 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use crate::waveform::WaveformType;
 use crate::effects::EffectsChain;
+use crate::utils::freq_to_midi;
 
 #[derive(Debug, Clone)]
 pub struct SampleData {
     pub samples: Arc<Vec<f32>>,
     pub sample_rate: u32,
+    pub gain: f32, // Gain already baked into `samples` on load (1.0 unless normalized)
+    pub stereo: Option<Arc<(Vec<f32>, Vec<f32>)>>, // Original left/right image of stereo files, `samples` is their mono mix
+}
+
+impl SampleData {
+    // De-interleaves decoded audio: mono mix in `samples`, the first two channels kept as the stereo image.
+    pub fn from_interleaved(data: &[f32], channels: usize, sample_rate: u32) -> Self {
+        let channels = channels.max(1);
+        let samples = data.chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+            .collect();
+        let stereo = (channels >= 2).then(|| {
+            let left = data.chunks(channels).map(|f| f[0]).collect();
+            let right = data.chunks(channels).map(|f| f.get(1).copied().unwrap_or(f[0])).collect();
+            Arc::new((left, right))
+        });
+        SampleData { samples: Arc::new(samples), sample_rate, gain: 1.0, stereo }
+    }
+
+    // Scales the buffer so its peak sits at 1.0, silent buffers are left alone.
+    pub fn normalize(&mut self) {
+        let peak = self.peak();
+        if peak <= 0.0 { return; }
+        let gain = 1.0 / peak;
+        self.samples = Arc::new(self.samples.iter().map(|s| s * gain).collect());
+        if let Some(stereo) = &mut self.stereo {
+            let (left, right) = stereo.as_ref();
+            *stereo = Arc::new((left.iter().map(|s| s * gain).collect(), right.iter().map(|s| s * gain).collect()));
+        }
+        self.gain *= gain;
+    }
+
+    // Largest absolute value in the (mono) buffer
+    pub fn peak(&self) -> f32 {
+        self.samples.iter().fold(0.0f32, |m, s| m.max(s.abs()))
+    }
+
+    // Root mean square of the (mono) buffer, 0.0 when empty
+    pub fn rms(&self) -> f32 {
+        if self.samples.is_empty() { return 0.0; }
+        (self.samples.iter().map(|s| s * s).sum::<f32>() / self.samples.len() as f32).sqrt()
+    }
+
+    pub fn duration_seconds(&self) -> f32 {
+        self.samples.len() as f32 / self.sample_rate as f32
+    }
+
+    // Seconds the sample lasts when played back at `pitch`
+    pub fn duration(&self, pitch: f32) -> f32 {
+        self.duration_seconds() / pitch
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum InstrumentSource {
     Synthesized(WaveformType),
     Sample(SampleData),
+    DrumMap(HashMap<u8, SampleData>), // One-shot sample per MIDI note number
+}
+
+// Shape of the ADSR ramps
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EnvelopeCurve {
+    #[default]
+    Linear,
+    Exponential, // Fast start, slow finish on every ramp, like a plucked string
+}
+
+const ENV_EXP_K: f32 = 5.0; // Steepness of the exponential ramps
+
+impl EnvelopeCurve {
+    // Ramp from 0 to 1 as `x` goes 0 to 1
+    pub fn rise(&self, x: f32) -> f32 {
+        match self {
+            EnvelopeCurve::Linear => x,
+            EnvelopeCurve::Exponential => (1.0 - (-ENV_EXP_K * x).exp()) / (1.0 - (-ENV_EXP_K).exp()),
+        }
+    }
+
+    // Ramp from 1 to 0 as `x` goes 0 to 1
+    pub fn fall(&self, x: f32) -> f32 {
+        match self {
+            EnvelopeCurve::Linear => 1.0 - x,
+            EnvelopeCurve::Exponential => ((-ENV_EXP_K * x).exp() - (-ENV_EXP_K).exp()) / (1.0 - (-ENV_EXP_K).exp()),
+        }
+    }
+}
+
+// Pitch vibrato, depth_cents 0 = off
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lfo {
+    pub rate_hz: f32,
+    pub depth_cents: f32,
+}
+
+impl Lfo {
+    // Pitch multiplier `t` seconds into a note
+    pub fn pitch_ratio(&self, t: f32) -> f32 {
+        if self.depth_cents == 0.0 { return 1.0; }
+        2f32.powf(self.depth_cents * (std::f32::consts::TAU * self.rate_hz * t).sin() / 1200.0)
+    }
+
+    // Playback time gained `t` seconds into a note, the integral of pitch_ratio - 1 to first order.
+    // Lets readers without running phase (live voices, sample playback) follow the vibrato.
+    pub fn time_offset(&self, t: f32) -> f32 {
+        if self.depth_cents == 0.0 || self.rate_hz <= 0.0 { return 0.0; }
+        let w = std::f32::consts::TAU * self.rate_hz;
+        self.depth_cents * std::f32::consts::LN_2 / 1200.0 * (1.0 - (w * t).cos()) / w
+    }
+}
+
+// Amplitude LFO keyed to note time, depth 0 = off, 1 = dips to silence
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tremolo {
+    pub rate_hz: f32,
+    pub depth: f32,
+}
+
+impl Tremolo {
+    // Envelope multiplier `t` seconds into a note, starts each note at full level
+    pub fn gain(&self, t: f32) -> f32 {
+        if self.depth == 0.0 { return 1.0; }
+        1.0 - self.depth * (0.5 - 0.5 * (std::f32::consts::TAU * self.rate_hz * t).cos())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,13 +139,26 @@ pub struct Instrument {
     pub name: String,
     pub source: InstrumentSource,
     pub attack: f32, // ADSR envelope parameters
+    pub hold: f32, // Seconds at full level between attack and decay
     pub decay: f32,
     pub sustain: f32,
     pub release: f32,
+    pub env_curve: EnvelopeCurve,
     pub volume: f32,
     pub pitch: f32,
     pub pan: f32, // -1.0 left, 1.0 right
-    pub detune: f32, // Pitch offset in cents for detuning 
+    pub detune: f32, // Cents, nonzero plays every voice twice, at +detune and -detune
+    pub pan_spread: f32, // Each note's pan lands randomly within +-spread of its base pan
+    pub filter_keytrack: f32, // How far the filter cutoff follows the note pitch, 0 = fixed, 1 = full
+    pub sub_osc: Option<WaveformType>, // Extra oscillator sub_octave octaves from each synthesized voice
+    pub sub_level: f32,
+    pub sub_octave: i32,
+    pub clip_ceiling: Option<f32>, // Soft-clip ceiling applied to the track before effects, None = off
+    pub hpf: Option<f32>, // One-pole cleanup high-pass cutoff in Hz, ahead of the effects chain
+    pub lpf: Option<f32>, // One-pole cleanup low-pass cutoff in Hz
+    pub osc_dc_block: Option<bool>, // DC removal on each oscillator voice, None = on for Pulse/Sawtooth only (a 50% square has no DC)
+    pub vibrato: Lfo,
+    pub tremolo: Tremolo,
     pub effects: EffectsChain,
 }
 
@@ -35,25 +168,111 @@ impl Default for Instrument {
             name: "Boomie".to_string(),
             source: InstrumentSource::Synthesized(WaveformType::Sine),
             attack: 0.01,
+            hold: 0.0,
             decay: 0.1,
             sustain: 0.8,
             release: 0.2,
+            env_curve: EnvelopeCurve::Linear,
             volume: 0.5,
             pitch: 1.0,
             pan: 0.0,
             detune: 0.0,
+            pan_spread: 0.0,
+            filter_keytrack: 0.0,
+            sub_osc: None,
+            sub_level: 0.5,
+            sub_octave: -1,
+            clip_ceiling: None,
+            hpf: None,
+            lpf: None,
+            osc_dc_block: None,
+            vibrato: Lfo::default(),
+            tremolo: Tremolo::default(),
             effects: EffectsChain::default(),
         }
     }
 }
 
+const KEYTRACK_REFERENCE: f32 = 261.63; // C4, the pitch where keytracking leaves the cutoff alone
+
+impl Instrument {
+    // Filter cutoff multiplier for a note at `pitch` Hz
+    pub fn keytrack_scale(&self, pitch: f32) -> f32 {
+        if self.filter_keytrack == 0.0 || pitch <= 0.0 { return 1.0; }
+        (pitch / KEYTRACK_REFERENCE).powf(self.filter_keytrack)
+    }
+
+    // Pitch multipliers of the copies each voice plays: just 1, or the +-detune unison pair.
+    // The pair renders every voice twice, so a detuned track costs about double the CPU.
+    pub fn unison_ratios(&self) -> impl ExactSizeIterator<Item = f32> + Clone + use<> {
+        let r = 2f32.powf(self.detune / 1200.0);
+        let copies = if self.detune == 0.0 { 1 } else { 2 };
+        [r, 1.0 / r].into_iter().take(copies)
+    }
+
+    // Frequency ratio of the sub-oscillator to the main one
+    pub fn sub_ratio(&self) -> f32 {
+        2f32.powi(self.sub_octave)
+    }
+
+    // Sub-oscillator output at its own phase (0..1), silent without sub_osc
+    pub fn sub_sample(&self, sub_phase: f32) -> f32 {
+        self.sub_osc.map_or(0.0, |wf| wf.generate_sample(sub_phase) * self.sub_level)
+    }
+
+    // Same, with noise drawn from `rng`
+    pub fn sub_sample_with(&self, sub_phase: f32, rng: &mut fastrand::Rng) -> f32 {
+        self.sub_osc.map_or(0.0, |wf| wf.generate_sample_with(sub_phase, rng) * self.sub_level)
+    }
+
+    // Whether voices of waveform `wf` go through the oscillator DC blocker
+    pub fn dc_blocks(&self, wf: WaveformType) -> bool {
+        self.osc_dc_block.unwrap_or(matches!(wf, WaveformType::Pulse(_) | WaveformType::Sawtooth))
+    }
+
+    // Waveform a note is synthesized with: its own wave= override, else the instrument's. None plays the sample.
+    pub fn note_waveform(&self, note_wave: Option<WaveformType>) -> Option<WaveformType> {
+        note_wave.or(match self.source {
+            InstrumentSource::Synthesized(wf) => Some(wf),
+            InstrumentSource::Sample(_) | InstrumentSource::DrumMap(_) => None,
+        })
+    }
+
+    // Drum map sample a note of this pitch triggers, if any
+    pub fn drum_sample(&self, pitch: f32) -> Option<&SampleData> {
+        match &self.source {
+            InstrumentSource::DrumMap(map) => map.get(&freq_to_midi(pitch)),
+            _ => None,
+        }
+    }
+
+    // Pan for one note: its own pan (or the instrument's) nudged by pan_spread, draws from rng only when spreading
+    pub fn note_pan(&self, note_pan: Option<f32>, rng: &mut fastrand::Rng) -> f32 {
+        let base = note_pan.unwrap_or(self.pan);
+        if self.pan_spread <= 0.0 { return base; }
+        (base + (rng.f32() * 2.0 - 1.0) * self.pan_spread).clamp(-1.0, 1.0)
+    }
+}
+
 #[derive(Debug, Clone)] // overrides
 pub struct Note {
     pub pitch: f32,
     pub duration: f32,
+    pub in_seconds: bool, // duration was written as seconds ("0.5s") instead of beats
     pub velocity: f32,
     pub pan: Option<f32>,
     pub slide_to: Option<f32>,
+    pub release: Option<f32>, // Replaces the instrument's release for this note
+    pub shift: f32, // Onset push/pull in seconds, the notes after it keep their grid positions
+    pub wave: Option<WaveformType>, // Synthesize this note with its own waveform, even on a sample instrument
+    pub overlap: bool, // Hold for the full duration and release over the following notes instead of before them
+}
+
+impl Note {
+    // Seconds the voice sounds for, `nd` being the note's length on the timeline
+    pub fn voice_seconds(&self, nd: f32, instr: &Instrument) -> f32 {
+        if self.overlap { nd + self.release.unwrap_or(instr.release) } else { nd }
+    }
 }
 
 // Chord struc for playing multiple notes
@@ -61,7 +280,9 @@ pub struct Note {
 pub struct Chord {
     pub pitches: Vec<f32>,
     pub duration: f32,
+    pub in_seconds: bool,
     pub velocity: f32,
+    pub release: Option<f32>,
 }
 
 // Sequence element enum to support notes, chords, and rests
@@ -70,4 +291,41 @@ pub enum SequenceElement {
     Note(Note),
     Chord(Chord),
     Rest(f32),
-}
\ No newline at end of file
+    TempoChange(f32), // New tempo in BPM for every following element
+}
+
+impl SequenceElement {
+    // Length in beats, beat_dur (seconds per beat at the current tempo) converts seconds-based durations
+    pub fn beats(&self, beat_dur: f32) -> f32 {
+        match self {
+            SequenceElement::Note(Note { duration, in_seconds: true, .. })
+            | SequenceElement::Chord(Chord { duration, in_seconds: true, .. }) => duration / beat_dur,
+            SequenceElement::Note(note) => note.duration,
+            SequenceElement::Chord(chord) => chord.duration,
+            SequenceElement::Rest(d) => *d,
+            SequenceElement::TempoChange(_) => 0.0,
+        }
+    }
+
+    // Length in seconds at the given seconds per beat
+    pub fn seconds(&self, beat_dur: f32) -> f32 {
+        self.beats(beat_dur) * beat_dur
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_stats_on_a_known_buffer() {
+        // Two samples of 0.5, one of -1.0 and one of 0.0: peak 1, rms sqrt(1.5 / 4)
+        let sd = SampleData::from_interleaved(&[0.5, -1.0, 0.5, 0.0], 1, 4);
+        assert_eq!(sd.peak(), 1.0);
+        assert!((sd.rms() - (1.5f32 / 4.0).sqrt()).abs() < 1e-6);
+        assert_eq!(sd.duration_seconds(), 1.0);
+
+        let empty = SampleData::from_interleaved(&[], 1, 44100);
+        assert_eq!((empty.peak(), empty.rms(), empty.duration_seconds()), (0.0, 0.0, 0.0));
+    }
+}
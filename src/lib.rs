@@ -21,11 +21,12 @@ pub mod gpu_synth;
 
 pub use error::SynthError;
 pub use waveform::WaveformType;
-pub use instrument::{Instrument, InstrumentSource, SampleData, Note, Chord, SequenceElement};
-pub use effects::{EffectsChain, ReverbParams, DelayParams, DistortionParams, FilterParams, FilterType, EffectsProcessor};
-pub use track::{MelodyTrack, LoopPoint};
-pub use arrangement::{Arrangement, TrackOverrides};
-pub use engine::{SynthEngine, PlaybackState, DynamicParameters};
+pub use instrument::{Instrument, InstrumentSource, SampleData, Note, Chord, SequenceElement, Lfo, Tremolo, EnvelopeCurve};
+pub use effects::{EffectsChain, ReverbParams, DelayParams, DistortionParams, FilterParams, FilterEnvelope, FilterType, AutoWahParams, ChorusParams, BitcrusherParams, ExpanderParams, CompressorParams, ConvolutionReverbParams, EffectsProcessor, AutoGain, BusCompressor, PeakMeter};
+pub use track::{MelodyTrack, LoopPoint, GatePattern, Groove};
+pub use arrangement::{Arrangement, TrackOverrides, CurveShape, ArpMode};
+pub use engine::{SynthEngine, PlaybackState, DynamicParameters, WavFormat, CueMix, SampleDirLoad};
+pub use cpal::HostId;
 
 #[cfg(feature = "gpu")]
 pub use gpu_synth::{GpuSynthEngine, AudioUniforms};
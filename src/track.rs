@@ -1,9 +1,12 @@
 use std::collections::HashMap;
 use crate::error::SynthError;
-use crate::instrument::{Instrument, InstrumentSource, SampleData, Note, Chord, SequenceElement};
-use crate::waveform::WaveformType;
-use crate::effects::{ReverbParams, DelayParams, DistortionParams, FilterParams, FilterType};
-use crate::utils::parse_note;
+use crate::instrument::{Instrument, InstrumentSource, SampleData, Note, Chord, SequenceElement, Lfo, Tremolo, EnvelopeCurve};
+use crate::effects::{ReverbParams, DelayParams, DistortionParams, FilterParams, FilterEnvelope, FilterType, AutoWahParams, ChorusParams, BitcrusherParams, ExpanderParams, CompressorParams, ConvolutionReverbParams};
+use crate::utils::{parse_note, parse_note_with_tuning, freq_to_midi, TuningConfig};
+
+// Highest instrument volume: accepted from .mel, same headroom as set_track_volume
+const MAX_VOLUME: f32 = 2.0;
+pub(crate) const MAX_NOTE_SHIFT: f32 = 0.1; // Seconds, bounds how far the live scan looks ahead for early notes
 
 #[derive(Debug, Clone)]
 pub struct LoopPoint {
@@ -11,6 +14,43 @@ pub struct LoopPoint {
     pub end: f32,
 }
 
+// Rhythmic on/off chop of a track's output, period in beats at the track tempo
+#[derive(Debug, Clone)]
+pub struct GatePattern {
+    pub period: f32,
+    pub duty: f32, // Open fraction of each period, 0.0-1.0
+}
+
+impl GatePattern {
+    // 1.0 while open, 0.0 while closed, seconds since the track started
+    pub fn gain(&self, seconds: f32, beat_dur: f32) -> f32 {
+        let period = self.period * beat_dur;
+        if period <= 0.0 { return 1.0; }
+        if (seconds / period).fract() < self.duty { 1.0 } else { 0.0 }
+    }
+}
+
+// Per-step (timing offset in beats, velocity multiplier), cycled over the notes, chords and rests
+#[derive(Debug, Clone)]
+pub struct Groove {
+    pub steps: Vec<(f32, f32)>,
+}
+
+impl Groove {
+    // Built-in templates, MPC style swing on a 16th grid in 4/4: every second step lands late
+    pub fn builtin(name: &str) -> Option<Groove> {
+        let swing = |amount: f32| Groove { steps: vec![(0.0, 1.0), ((amount - 0.5) * 0.5, 0.9)] };
+        match name {
+            "mpc-swing-54" => Some(swing(0.54)),
+            "mpc-swing-58" => Some(swing(0.58)),
+            "mpc-swing-62" => Some(swing(0.62)),
+            "mpc-swing-66" => Some(swing(0.66)),
+            "straight" => Some(Groove { steps: vec![(0.0, 1.0)] }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MelodyTrack {
     pub name: String,
@@ -21,9 +61,145 @@ pub struct MelodyTrack {
     pub loop_point: Option<LoopPoint>,
     pub time_signature: (u32, u32), 
     pub swing: f32, // Swing feel: 0.0 = straight, 0.5 = triplet, 1.0 = max
+    pub gate: Option<GatePattern>,
 }
 
 impl MelodyTrack {
+    // Seconds per beat, where the beat unit is the time signature denominator (an eighth in 6/8, 7/8, ...)
+    pub fn beat_duration(&self) -> f32 {
+        self.beat_duration_at(self.tempo)
+    }
+
+    // Every note lands dead centre (no pan, pan_spread or note pan=) and the source has no stereo image of its own,
+    // so both stereo channels come out the same
+    pub fn is_centred(&self) -> bool {
+        self.instrument.pan == 0.0 && self.instrument.pan_spread <= 0.0
+            && !matches!(&self.instrument.source, InstrumentSource::Sample(sd) if sd.stereo.is_some())
+            && self.sequence.iter().all(|e| !matches!(e, SequenceElement::Note(n) if n.pan.is_some_and(|p| p != 0.0)))
+    }
+
+    // Seconds per beat at some other tempo, used after a tempo_change
+    pub fn beat_duration_at(&self, tempo: f32) -> f32 {
+        let denominator = self.time_signature.1.max(1) as f32;
+        60.0 / tempo * (4.0 / denominator)
+    }
+
+    // Seconds per bar, numerator beats long
+    pub fn bar_duration(&self) -> f32 {
+        self.beat_duration() * self.time_signature.0.max(1) as f32
+    }
+
+    // Re-times tempo-synced effects after the tempo changes (parsing, arrangement tempo overrides)
+    pub fn sync_effects_to_tempo(&mut self) {
+        if let Some(delay) = self.instrument.effects.delay.as_mut()
+            && let Some(quarters) = delay.sync {
+            delay.time = quarters * 60.0 / self.tempo;
+        }
+    }
+
+    // Moves each step's onset by its groove offset (later steps keep their grid position) and scales velocities.
+    // Done by trading duration between neighbouring steps, so the track length is unchanged.
+    pub fn apply_groove(&mut self, groove: &Groove) {
+        if groove.steps.is_empty() { return; }
+        let steps: Vec<usize> = self.sequence.iter().enumerate()
+            .filter(|(_, e)| !matches!(e, SequenceElement::TempoChange(_)))
+            .map(|(i, _)| i)
+            .collect();
+        let offset = |k: usize| groove.steps[k % groove.steps.len()].0;
+
+        let mut beat_dur = self.beat_duration();
+        let mut next_step = 0;
+        for i in 0..self.sequence.len() {
+            let element = &mut self.sequence[i];
+            if let SequenceElement::TempoChange(tempo) = element {
+                beat_dur = 60.0 / *tempo * (4.0 / self.time_signature.1.max(1) as f32);
+                continue;
+            }
+            let k = next_step;
+            next_step += 1;
+            // Starts late by its own offset, ends when the next step starts
+            let own = if k == 0 { 0.0 } else { offset(k) };
+            let next = if k + 1 < steps.len() { offset(k + 1) } else { 0.0 };
+            let shift = next - own;
+            let velocity_mult = groove.steps[k % groove.steps.len()].1;
+            match element {
+                SequenceElement::Note(note) => {
+                    note.duration = (note.duration + if note.in_seconds { shift * beat_dur } else { shift }).max(0.0);
+                    note.velocity *= velocity_mult;
+                }
+                SequenceElement::Chord(chord) => {
+                    chord.duration = (chord.duration + if chord.in_seconds { shift * beat_dur } else { shift }).max(0.0);
+                    chord.velocity *= velocity_mult;
+                }
+                SequenceElement::Rest(d) => *d = (*d + shift).max(0.0),
+                SequenceElement::TempoChange(_) => {}
+            }
+        }
+        // A late first step gets a rest in front of it
+        if let Some(&first) = steps.first() && offset(0) > 0.0 {
+            self.sequence.insert(first, SequenceElement::Rest(offset(0)));
+            if let Some(SequenceElement::Note(Note { duration, in_seconds: false, .. }) | SequenceElement::Chord(Chord { duration, in_seconds: false, .. }) | SequenceElement::Rest(duration)) = self.sequence.get_mut(first + 1) {
+                *duration = (*duration - offset(0)).max(0.0);
+            }
+        }
+    }
+
+    // Total length in beats, following tempo changes
+    pub fn total_beats(&self) -> f32 {
+        self.total_length().0
+    }
+
+    // Total length in seconds, following tempo changes
+    pub fn duration_seconds(&self) -> f32 {
+        self.total_length().1
+    }
+
+    // Longest a note can ring past its end: the instrument release or a longer per-note rel=
+    pub fn release_tail(&self) -> f32 {
+        self.sequence.iter()
+            .filter_map(|element| match element {
+                SequenceElement::Note(note) => note.release,
+                SequenceElement::Chord(chord) => chord.release,
+                SequenceElement::Rest(_) | SequenceElement::TempoChange(_) => None,
+            })
+            .fold(self.instrument.release, f32::max)
+    }
+
+    // Each element with its start time in seconds since the track began, following tempo changes
+    pub fn iter_timed(&self) -> impl Iterator<Item = (f32, &SequenceElement)> {
+        let mut beat_dur = self.beat_duration();
+        let mut time = 0.0;
+        self.sequence.iter().map(move |element| {
+            if let SequenceElement::TempoChange(tempo) = element {
+                beat_dur = self.beat_duration_at(*tempo);
+            }
+            let start = time;
+            time += element.seconds(beat_dur);
+            (start, element)
+        })
+    }
+
+    fn total_length(&self) -> (f32, f32) {
+        let mut beat_dur = self.beat_duration();
+        let (mut beats, mut seconds) = (0.0, 0.0);
+        for element in &self.sequence {
+            if let SequenceElement::TempoChange(tempo) = element {
+                beat_dur = self.beat_duration_at(*tempo);
+            }
+            beats += element.beats(beat_dur);
+            seconds += element.seconds(beat_dur);
+        }
+        (beats, seconds)
+    }
+
+    // Zero-based (bar, beat within bar) for a time in seconds since the track started
+    pub fn musical_position(&self, seconds: f32) -> (u32, f32) {
+        let bar_len = self.bar_duration();
+        let bar = (seconds / bar_len).floor().max(0.0);
+        let beat = (seconds - bar * bar_len) / self.beat_duration();
+        (bar as u32, beat)
+    }
+
     pub fn from_mel(content: &str, sample_cache: &HashMap<String, SampleData>) -> Result<Self, SynthError> {
         let mut track = MelodyTrack {
             name: "melody".to_string(),
@@ -34,8 +210,14 @@ impl MelodyTrack {
             loop_point: None,
             time_signature: (4, 4),
             swing: 0.0,
+            gate: None,
         };
 
+        let mut midi_velocity = false;
+        let mut groove = None;
+        let tuning = Self::note_tuning(content)?;
+        let mut filter_env = None;
+
         macro_rules! parse_field {
             ($line:expr, $prefix:expr, $field:expr) => {
                 if let Some(v) = $line.strip_prefix($prefix) {
@@ -57,7 +239,7 @@ impl MelodyTrack {
                 if parts.len() >= 2 {
                     track.loop_point = Some(LoopPoint {
                         start: parts[0].parse().unwrap_or(0.0),
-                        end: parts[1].parse().unwrap_or(track.length),
+                        end: parts[1].parse().unwrap_or_else(|_| track.total_beats()),
                     });
                 }
 
@@ -77,84 +259,116 @@ impl MelodyTrack {
                         .clone()
                 );
                 
+            } else if let Some(v) = line.strip_prefix("drum:") {
+                // Later drum: lines add to the map, a note without a mapping stays silent
+                if !matches!(track.instrument.source, InstrumentSource::DrumMap(_)) {
+                    track.instrument.source = InstrumentSource::DrumMap(HashMap::new());
+                }
+                for entry in v.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                    let (key, name) = entry.split_once('=')
+                        .ok_or_else(|| SynthError::ParseError(format!("Invalid drum mapping '{}', expected NOTE=sample", entry)))?;
+                    let key = key.trim();
+                    let midi = match key.parse::<u8>() {
+                        Ok(n) if n <= 127 => n,
+                        Ok(_) => return Err(SynthError::ParseError(format!("Drum note {} out of MIDI range", key))),
+                        Err(_) => freq_to_midi(parse_note(key)?),
+                    };
+                    let sample = sample_cache.get(name.trim())
+                        .ok_or_else(|| SynthError::InvalidInstrument(format!("Sample not found: {}", name.trim())))?
+                        .clone();
+                    if let InstrumentSource::DrumMap(map) = &mut track.instrument.source {
+                        map.insert(midi, sample);
+                    }
+                }
+
             } else if let Some(v) = line.strip_prefix("waveform:") {
-                track.instrument.source = InstrumentSource::Synthesized(match v.trim().to_lowercase().as_str() {
-                    "sine" => WaveformType::Sine,
-                    "square" => WaveformType::Square,
-                    "triangle" => WaveformType::Triangle,
-                    "sawtooth" => WaveformType::Sawtooth,
-                    "noise" => WaveformType::Noise,
-                    _ => return Err(SynthError::ParseError("Unknown Waveform".to_string())),
-                });
+                track.instrument.source = InstrumentSource::Synthesized(v.parse()?);
+
+            } else if let Some(v) = line.strip_prefix("sub_osc:") {
+                track.instrument.sub_osc = Some(v.parse()?);
 
             } else if let Some(v) = line.strip_prefix("note:") {
                 let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
                 if parts.len() >= 3 {
-                    let pitch = parse_note(parts[0])?;
-                    let duration: f32 = parts[1].parse()
-                        .map_err(|_| SynthError::ParseError("Invalid Duration".to_string()))?;
+                    let pitch = parse_note_with_tuning(parts[0], &tuning)?;
+                    let (duration, in_seconds) = parse_duration(parts[1])?;
                     let velocity: f32 = parts[2].split("//").next().unwrap_or("0").trim().parse()
                         .map_err(|_| SynthError::ParseError("Invalid Velocity".to_string()))?;
                     
-                    let mut note = Note { pitch, duration, velocity, pan: None, slide_to: None };
+                    let mut note = Note { pitch, duration, in_seconds, velocity, pan: None, slide_to: None, release: None, shift: 0.0, wave: None, overlap: false };
                     
                     // Prse optional per-note parameters
                     for param in parts.iter().skip(3) {
                         if let Some((key, val)) = param.split_once('=') {
                             match key.trim() {
                                 "pan" => note.pan = val.trim().parse().ok(),
-                                "slide" => note.slide_to = Some(parse_note(val.trim())?),
+                                "slide" => note.slide_to = Some(parse_note_with_tuning(val.trim(), &tuning)?),
+                                "rel" => note.release = Some(parse_release(val)?),
+                                "wave" => note.wave = Some(val.parse()?),
+                                "overlap" => note.overlap = matches!(val.trim().to_lowercase().as_str(), "true" | "on" | "yes"),
+                                "shift" => {
+                                    let ms: f32 = val.trim().parse()
+                                        .map_err(|_| SynthError::ParseError("Invalid shift".to_string()))?;
+                                    note.shift = (ms / 1000.0).clamp(-MAX_NOTE_SHIFT, MAX_NOTE_SHIFT);
+                                }
                                 _ => {}
                             }
                         }
                     }
                     
                     track.sequence.push(SequenceElement::Note(note));
-                    track.length += duration;
                 }
 
             } else if let Some(v) = line.strip_prefix("chord:") { // Parse chords
                 let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
                 if parts.len() >= 3 {
                     let notes_str = parts[0];
-                    let duration: f32 = parts[1].parse()
-                        .map_err(|_| SynthError::ParseError("Invalid Duration".to_string()))?;
+                    let (duration, in_seconds) = parse_duration(parts[1])?;
                     let velocity: f32 = parts[2].split("//").next().unwrap_or("0").trim().parse()
                         .map_err(|_| SynthError::ParseError("Invalid Velocity".to_string()))?;
                     
                     let pitches: Result<Vec<f32>, _> = notes_str
                         .split('+')
-                        .map(|n| parse_note(n.trim()))
+                        .map(|n| parse_note_with_tuning(n.trim(), &tuning))
                         .collect();
                     
+                    let mut release = None;
+                    for param in parts.iter().skip(3) {
+                        if let Some(("rel", val)) = param.split_once('=').map(|(k, v)| (k.trim(), v)) {
+                            release = Some(parse_release(val)?);
+                        }
+                    }
+
                     track.sequence.push(SequenceElement::Chord(Chord {
                         pitches: pitches?,
                         duration,
+                        in_seconds,
                         velocity,
+                        release,
                     }));
-                    track.length += duration;
                 }
 
             } else if let Some(v) = line.strip_prefix("rest:") { 
                 let duration: f32 = v.trim().parse()
                     .map_err(|_| SynthError::ParseError("Invalid rest duration".to_string()))?;
                 track.sequence.push(SequenceElement::Rest(duration));
-                track.length += duration;
+
+            } else if let Some(v) = line.strip_prefix("tempo_change:") {
+                let tempo: f32 = v.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid tempo_change".to_string()))?;
+                if tempo <= 0.0 {
+                    return Err(SynthError::ParseError("tempo_change must be positive".to_string()));
+                }
+                track.sequence.push(SequenceElement::TempoChange(tempo));
 
             } else if let Some(v) = line.strip_prefix("filter:") { 
-                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
-                if parts.len() >= 3 {
-                    let filter_type = match parts[0].to_lowercase().as_str() {
-                        "lowpass" | "lp" => FilterType::LowPass,
-                        "highpass" | "hp" => FilterType::HighPass,
-                        "bandpass" | "bp" => FilterType::BandPass,
-                        _ => FilterType::LowPass,
-                    };
-                    track.instrument.effects.filter = Some(FilterParams {
-                        filter_type,
-                        cutoff: parts[1].parse().unwrap_or(1000.0),
-                        resonance: parts[2].parse().unwrap_or(0.7),
-                    });
+                if let Some(filter) = parse_filter(v) {
+                    track.instrument.effects.filter = Some(filter);
+                }
+
+            } else if let Some(v) = line.strip_prefix("filter2:") {
+                if let Some(filter) = parse_filter(v) {
+                    track.instrument.effects.filter2 = Some(filter);
                 }
 
             } else if let Some(v) = line.strip_prefix("reverb:") {
@@ -165,6 +379,7 @@ impl MelodyTrack {
                         damping: parts[1].parse().unwrap_or(0.5),
                         wet: parts[2].parse().unwrap_or(0.3),
                         width: parts[3].parse().unwrap_or(1.0),
+                        custom_tunings: None,
                     });
                 }
 
@@ -175,9 +390,26 @@ impl MelodyTrack {
                         time: parts[0].parse().unwrap_or(0.25),
                         feedback: parts[1].parse().unwrap_or(0.4),
                         wet: parts[2].parse().unwrap_or(0.3),
+                        sync: None,
+                        ping_pong: match parts.get(3).map(|p| p.strip_prefix("pingpong=")) {
+                            None => false,
+                            Some(Some(flag)) => flag.parse()
+                                .map_err(|_| SynthError::ParseError("Invalid delay: pingpong=, expected true or false".to_string()))?,
+                            Some(None) => return Err(SynthError::ParseError(format!("Unknown delay: option '{}'", parts[3]))),
+                        },
                     });
                 }
 
+            } else if let Some(v) = line.strip_prefix("delay_synced_pingpong:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                track.instrument.effects.delay = Some(DelayParams {
+                    time: 0.0, // Set from the tempo once the whole file is read
+                    feedback: parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(0.4),
+                    wet: parts.get(2).and_then(|p| p.parse().ok()).unwrap_or(0.3),
+                    sync: Some(parse_subdivision(parts[0])?),
+                    ping_pong: true,
+                });
+
             } else if let Some(v) = line.strip_prefix("distortion:") {
                 let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
                 if parts.len() >= 3 {
@@ -188,20 +420,437 @@ impl MelodyTrack {
                     });
                 }
 
+            } else if let Some(v) = line.strip_prefix("gate_pattern:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                let period: f32 = parts[0].parse()
+                    .map_err(|_| SynthError::ParseError("Invalid gate_pattern period".to_string()))?;
+                let duty: f32 = parts.get(1).map_or(Ok(0.5), |d| d.parse())
+                    .map_err(|_| SynthError::ParseError("Invalid gate_pattern duty".to_string()))?;
+                track.gate = Some(GatePattern { period, duty: duty.clamp(0.0, 1.0) });
+
+            } else if line.starts_with("tuning:") {
+                // Read up front by note_tuning
+
+            } else if let Some(v) = line.strip_prefix("groove:") {
+                let v = v.trim();
+                groove = Some(match Groove::builtin(v) {
+                    Some(builtin) => builtin,
+                    // Inline template: offset:velocity, offset:velocity, ...
+                    None => Groove {
+                        steps: v.split(',').map(|step| {
+                            let (offset, velocity) = step.trim().split_once(':').unwrap_or((step.trim(), "1.0"));
+                            match (offset.trim().parse(), velocity.trim().parse()) {
+                                (Ok(o), Ok(vel)) => Ok((o, vel)),
+                                _ => Err(SynthError::ParseError(format!("Unknown groove '{}'", v))),
+                            }
+                        }).collect::<Result<_, _>>()?,
+                    },
+                });
+
+            } else if let Some(v) = line.strip_prefix("velocity_scale:") {
+                midi_velocity = match v.trim().to_lowercase().as_str() {
+                    "midi" => true,
+                    "unit" => false,
+                    _ => return Err(SynthError::ParseError("Unknown velocity_scale, expected midi or unit".to_string())),
+                };
+
+            } else if let Some(v) = line.strip_prefix("autowah:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 5 {
+                    track.instrument.effects.autowah = Some(AutoWahParams {
+                        sensitivity: parts[0].parse().unwrap_or(1.0),
+                        base_freq: parts[1].parse().unwrap_or(300.0),
+                        range: parts[2].parse().unwrap_or(2000.0),
+                        resonance: parts[3].parse().unwrap_or(2.0),
+                        mix: parts[4].parse().unwrap_or(1.0),
+                    });
+                }
+
+            } else if let Some(v) = line.strip_prefix("bitcrusher:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 3 {
+                    track.instrument.effects.bitcrusher = Some(BitcrusherParams {
+                        bits: parts[0].parse().unwrap_or(8),
+                        downsample: parts[1].parse().unwrap_or(4),
+                        mix: parts[2].parse().unwrap_or(1.0),
+                    });
+                }
+
+            } else if let Some(v) = line.strip_prefix("chorus:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 4 {
+                    track.instrument.effects.chorus = Some(ChorusParams {
+                        rate_hz: parts[0].parse().unwrap_or(0.8),
+                        depth_ms: parts[1].parse().unwrap_or(3.0),
+                        mix: parts[2].parse().unwrap_or(0.4),
+                        voices: parts[3].parse().unwrap_or(3),
+                    });
+                }
+
+            } else if let Some(v) = line.strip_prefix("expander:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 4 {
+                    track.instrument.effects.expander = Some(ExpanderParams {
+                        threshold: parts[0].parse().unwrap_or(-40.0),
+                        ratio: parts[1].parse().unwrap_or(2.0),
+                        attack: parts[2].parse().unwrap_or(0.005),
+                        release: parts[3].parse().unwrap_or(0.1),
+                    });
+                }
+
+            } else if let Some(v) = line.strip_prefix("conv_reverb:") {
+                if !cfg!(feature = "convolution") {
+                    return Err(SynthError::UnsupportedFormat("conv_reverb: requires the `convolution` feature".to_string()));
+                }
+                let (name, wet) = v.split_once(',')
+                    .ok_or_else(|| SynthError::ParseError("Invalid conv_reverb:, expected IR_SAMPLE, WET".to_string()))?;
+                let ir = sample_cache.get(name.trim())
+                    .ok_or_else(|| SynthError::InvalidInstrument(format!("Sample not found: {}", name.trim())))?;
+                track.instrument.effects.conv_reverb = Some(ConvolutionReverbParams {
+                    ir: ir.samples.clone(),
+                    ir_sample_rate: ir.sample_rate,
+                    wet: wet.trim().parse::<f32>()
+                        .map_err(|_| SynthError::ParseError("Invalid conv_reverb: wet".to_string()))?
+                        .clamp(0.0, 1.0),
+                });
+
+            } else if let Some(v) = line.strip_prefix("compressor:") {
+                let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+                if parts.len() >= 5 {
+                    track.instrument.effects.compressor = Some(CompressorParams {
+                        threshold_db: parts[0].parse().unwrap_or(-18.0),
+                        ratio: parts[1].parse().unwrap_or(4.0),
+                        attack_ms: parts[2].parse().unwrap_or(10.0),
+                        release_ms: parts[3].parse().unwrap_or(100.0),
+                        makeup_db: parts[4].parse().unwrap_or(0.0),
+                        knee: parts.get(5).and_then(|k| k.parse().ok()).unwrap_or(0.0),
+                    });
+                }
+
+            } else if let Some(v) = line.strip_prefix("hpf:") {
+                track.instrument.hpf = Some(v.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid hpf:".to_string()))?);
+
+            } else if let Some(v) = line.strip_prefix("lpf:") {
+                track.instrument.lpf = Some(v.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid lpf:".to_string()))?);
+
+            } else if let Some(v) = line.strip_prefix("osc_dc_block:") {
+                track.instrument.osc_dc_block = Some(match v.trim().to_lowercase().as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(SynthError::ParseError("Invalid osc_dc_block:, expected on or off".to_string())),
+                });
+
+            } else if let Some(v) = line.strip_prefix("env_curve:") {
+                track.instrument.env_curve = match v.trim().to_lowercase().as_str() {
+                    "linear" | "lin" => EnvelopeCurve::Linear,
+                    "exponential" | "exp" => EnvelopeCurve::Exponential,
+                    _ => return Err(SynthError::ParseError("Invalid env_curve:, expected linear or exp".to_string())),
+                };
+
+            } else if let Some(v) = line.strip_prefix("adsr:") {
+                // Shorthand for attack:/decay:/sustain:/release:, later single lines still override
+                let vals = v.split(',')
+                    .map(|s| s.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|vals| vals.len() == 4)
+                    .ok_or_else(|| SynthError::ParseError("Invalid adsr:, expected attack, decay, sustain, release".to_string()))?;
+                let instr = &mut track.instrument;
+                (instr.attack, instr.decay, instr.sustain, instr.release) = (vals[0], vals[1], vals[2], vals[3]);
+
+            } else if let Some(v) = line.strip_prefix("vibrato:") {
+                let vals = v.split(',')
+                    .map(|s| s.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|vals| vals.len() == 2)
+                    .ok_or_else(|| SynthError::ParseError("Invalid vibrato:, expected rate (Hz), depth (cents)".to_string()))?;
+                track.instrument.vibrato = Lfo { rate_hz: vals[0], depth_cents: vals[1] };
+
+            } else if let Some(v) = line.strip_prefix("tremolo:") {
+                let vals = v.split(',')
+                    .map(|s| s.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|vals| vals.len() == 2)
+                    .ok_or_else(|| SynthError::ParseError("Invalid tremolo:, expected rate (Hz), depth (0-1)".to_string()))?;
+                track.instrument.tremolo = Tremolo { rate_hz: vals[0], depth: vals[1].clamp(0.0, 1.0) };
+
+            } else if let Some(v) = line.strip_prefix("filter_env:") {
+                let vals = v.split(',')
+                    .map(|s| s.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|vals| vals.len() == 5)
+                    .ok_or_else(|| SynthError::ParseError("Invalid filter_env:, expected amount (Hz), attack, decay, sustain, release".to_string()))?;
+                filter_env = Some(FilterEnvelope {
+                    amount_hz: vals[0],
+                    attack: vals[1].max(0.0),
+                    decay: vals[2].max(0.0),
+                    sustain: vals[3].clamp(0.0, 1.0),
+                    release: vals[4].max(0.0),
+                });
+
+            } else if let Some(v) = line.strip_prefix("clip_ceiling:") {
+                track.instrument.clip_ceiling = Some(v.trim().parse()
+                    .map_err(|_| SynthError::ParseError("Invalid clip_ceiling:".to_string()))?);
+
             } else {
                 parse_field!(line, "tempo:", track.tempo);
                 parse_field!(line, "volume:", track.instrument.volume);
                 parse_field!(line, "attack:", track.instrument.attack);
+                parse_field!(line, "hold:", track.instrument.hold);
                 parse_field!(line, "decay:", track.instrument.decay);
                 parse_field!(line, "sustain:", track.instrument.sustain);
                 parse_field!(line, "release:", track.instrument.release);
                 parse_field!(line, "pitch:", track.instrument.pitch);
                 parse_field!(line, "pan:", track.instrument.pan);
                 parse_field!(line, "detune:", track.instrument.detune);
+                parse_field!(line, "pan_spread:", track.instrument.pan_spread);
+                parse_field!(line, "filter_keytrack:", track.instrument.filter_keytrack);
+                parse_field!(line, "sub_level:", track.instrument.sub_level);
+                parse_field!(line, "sub_octave:", track.instrument.sub_octave);
                 parse_field!(line, "swing:", track.swing);
             }
         }
 
+        // Sweeps the first filter, whichever order filter: and filter_env: come in
+        if let Some(env) = filter_env {
+            match track.instrument.effects.filter.as_mut() {
+                Some(filter) => filter.envelope = Some(env),
+                None => eprintln!("Warning: track '{}' has filter_env: without filter:, ignored", track.name),
+            }
+        }
+
+        // Applied once the final tempo is known, before velocities are clamped
+        if let Some(groove) = &groove {
+            track.apply_groove(groove);
+        }
+
+        // Out of range velocities (typically MIDI values without velocity_scale: midi) would blow up the mix
+        let velocity_div = if midi_velocity { 127.0 } else { 1.0 };
+        let mut clamped = 0;
+        for element in &mut track.sequence {
+            let velocity = match element {
+                SequenceElement::Note(note) => &mut note.velocity,
+                SequenceElement::Chord(chord) => &mut chord.velocity,
+                SequenceElement::Rest(_) | SequenceElement::TempoChange(_) => continue,
+            };
+            *velocity /= velocity_div;
+            if !(0.0..=1.0).contains(velocity) {
+                *velocity = velocity.clamp(0.0, 1.0);
+                clamped += 1;
+            }
+        }
+        if clamped > 0 {
+            eprintln!("Warning: track '{}' has {} velocities outside 0.0-1.0, clamped (velocity_scale: midi for 0-127)", track.name, clamped);
+        }
+        if !(0.0..=MAX_VOLUME).contains(&track.instrument.volume) {
+            eprintln!("Warning: track '{}' volume {} outside 0.0-{}, clamped", track.name, track.instrument.volume, MAX_VOLUME);
+            track.instrument.volume = track.instrument.volume.clamp(0.0, MAX_VOLUME);
+        }
+
+        // Seconds-based durations depend on the final tempo, which may be declared after the notes
+        track.length = track.total_beats();
+        track.sync_effects_to_tempo();
+
         Ok(track)
     }
-}
\ No newline at end of file
+
+    // The tuning notes are parsed with, read before them so tuning: can sit anywhere in the file.
+    // Drum maps are keyed by the untuned note numbers, so a track whose source ends up a drum map keeps A4 = 440.
+    fn note_tuning(content: &str) -> Result<TuningConfig, SynthError> {
+        let mut tuning = TuningConfig::default();
+        let mut drums = false;
+        for line in content.lines().map(str::trim) {
+            if let Some(v) = line.strip_prefix("tuning:") {
+                tuning.a4_hz = v.trim().parse::<f32>().ok()
+                    .filter(|hz| *hz > 0.0)
+                    .ok_or_else(|| SynthError::ParseError("Invalid tuning:, expected the A4 frequency in Hz".to_string()))?;
+            } else if line.starts_with("drum:") {
+                drums = true;
+            } else if line.starts_with("sample:") || line.starts_with("waveform:") {
+                drums = false;
+            }
+        }
+        Ok(if drums { TuningConfig::default() } else { tuning })
+    }
+}
+
+// "TYPE, CUTOFF, RESONANCE[, GAIN]", None if there are fewer than three values
+fn parse_filter(v: &str) -> Option<FilterParams> {
+    let parts: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+    if parts.len() < 3 { return None; }
+    let filter_type = match parts[0].to_lowercase().as_str() {
+        "lowpass" | "lp" => FilterType::LowPass,
+        "highpass" | "hp" => FilterType::HighPass,
+        "bandpass" | "bp" => FilterType::BandPass,
+        "notch" => FilterType::Notch,
+        "peak" => FilterType::Peak,
+        _ => FilterType::LowPass,
+    };
+    Some(FilterParams {
+        filter_type,
+        cutoff: parts[1].parse().unwrap_or(1000.0),
+        resonance: parts[2].parse().unwrap_or(0.7),
+        gain_db: parts.get(3).and_then(|g| g.parse().ok()),
+        envelope: None,
+    })
+}
+
+// Per-note release override in seconds
+fn parse_release(s: &str) -> Result<f32, SynthError> {
+    s.trim().parse::<f32>().ok()
+        .filter(|r| *r >= 0.0)
+        .ok_or_else(|| SynthError::ParseError("Invalid rel".to_string()))
+}
+
+// Note value as quarter notes: "1/8" is 0.5, a "." (or "d") suffix dots it, a "t" suffix makes it a triplet
+fn parse_subdivision(s: &str) -> Result<f32, SynthError> {
+    let s = s.trim();
+    let (fraction, scale) = if let Some(f) = s.strip_suffix('.').or_else(|| s.strip_suffix('d')) {
+        (f, 1.5)
+    } else if let Some(f) = s.strip_suffix('t') {
+        (f, 2.0 / 3.0)
+    } else {
+        (s, 1.0)
+    };
+    let invalid = || SynthError::ParseError(format!("Invalid subdivision '{}', expected e.g. 1/8, 1/8. or 1/8t", s));
+    let (num, den) = fraction.split_once('/').ok_or_else(invalid)?;
+    let (num, den): (f32, f32) = (num.trim().parse().map_err(|_| invalid())?, den.trim().parse().map_err(|_| invalid())?);
+    if num <= 0.0 || den <= 0.0 { return Err(invalid()); }
+    Ok(num / den * 4.0 * scale)
+}
+
+// "0.5s" is seconds, "0.5b" or a bare "0.5" is beats
+fn parse_duration(s: &str) -> Result<(f32, bool), SynthError> {
+    let s = s.trim();
+    let (value, in_seconds) = match s.strip_suffix('s') {
+        Some(v) => (v, true),
+        None => (s.strip_suffix('b').unwrap_or(s), false),
+    };
+    let duration = value.trim().parse()
+        .map_err(|_| SynthError::ParseError("Invalid Duration".to_string()))?;
+    Ok((duration, in_seconds))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mel(content: &str) -> MelodyTrack {
+        MelodyTrack::from_mel(content, &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn compound_and_odd_meters_count_eighths() {
+        // At 120 BPM a quarter is 0.5s, so an eighth-note beat is 0.25s
+        let six_eight = mel("tempo: 120\ntime_sig: 6/8\nnote: C4, 1, 1.0");
+        assert!((six_eight.beat_duration() - 0.25).abs() < 1e-6);
+        assert!((six_eight.bar_duration() - 1.5).abs() < 1e-6);
+        let seven_eight = mel("tempo: 120\ntime_sig: 7/8\nnote: C4, 1, 1.0");
+        assert!((seven_eight.beat_duration() - 0.25).abs() < 1e-6);
+        assert!((seven_eight.bar_duration() - 1.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn seconds_suffix_ignores_tempo() {
+        for tempo in [60, 97, 200] {
+            let track = mel(&format!("tempo: {}\nnote: C4, 0.5s, 1.0\nnote: D4, 0.5b, 1.0", tempo));
+            let second = track.iter_timed().nth(1).unwrap().0;
+            assert!((second - 0.5).abs() < 1e-6, "{} BPM: {}", tempo, second);
+            assert!((track.duration_seconds() - (0.5 + 0.5 * track.beat_duration())).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn tempo_change_respaces_the_notes_after_it() {
+        let track = mel("tempo: 120\nnote: C4, 1, 1.0\nnote: D4, 1, 1.0\ntempo_change: 60\nnote: E4, 1, 1.0\nnote: F4, 1, 1.0");
+        let onsets: Vec<f32> = track.iter_timed()
+            .filter(|(_, e)| matches!(e, SequenceElement::Note(_)))
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(onsets, vec![0.0, 0.5, 1.0, 2.0]);
+        assert!((track.duration_seconds() - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pan_spread_scatters_notes_reproducibly() {
+        let instrument = mel("pan: 0.2\npan_spread: 0.3\nnote: C4, 1, 1.0").instrument;
+        let pans = |seed| {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            (0..8).map(|_| instrument.note_pan(None, &mut rng)).collect::<Vec<f32>>()
+        };
+        let first = pans(7);
+        assert!(first.iter().all(|p| (-0.1..=0.5).contains(p)));
+        assert!(first.windows(2).all(|w| w[0] != w[1]));
+        assert_eq!(first, pans(7));
+        assert_ne!(first, pans(8));
+    }
+
+    #[test]
+    fn keytracking_scales_cutoff_with_pitch() {
+        let full = mel("filter: lowpass, 800, 0.7\nfilter_keytrack: 1\nnote: C4, 1, 1.0").instrument;
+        let (low, high) = (parse_note("A3").unwrap(), parse_note("A5").unwrap());
+        let ratio = full.keytrack_scale(high) / full.keytrack_scale(low);
+        assert!((ratio - 4.0).abs() < 1e-3, "two octaves up should quadruple the cutoff, got {}", ratio);
+
+        let half = mel("filter_keytrack: 0.5\nnote: C4, 1, 1.0").instrument;
+        assert!((half.keytrack_scale(high) / half.keytrack_scale(low) - 2.0).abs() < 1e-3);
+        let none = mel("note: C4, 1, 1.0").instrument;
+        assert_eq!(none.keytrack_scale(high), none.keytrack_scale(low));
+    }
+
+    #[test]
+    fn timed_elements_start_at_cumulative_durations() {
+        // 0.5s per beat: 1 + 0.5 (rest) + 2 + 0.5s (seconds) + 1 beats
+        let track = mel("tempo: 120\nnote: C4, 1, 1.0\nrest: 0.5\nchord: C4+E4, 2, 1.0\nnote: D4, 0.5s, 1.0\nnote: E4, 1, 1.0");
+        let times: Vec<f32> = track.iter_timed().map(|(t, _)| t).collect();
+        assert_eq!(times, vec![0.0, 0.5, 0.75, 1.75, 2.25]);
+        let ends: Vec<f32> = track.iter_timed().map(|(t, e)| t + e.seconds(track.beat_duration())).collect();
+        assert_eq!(&ends[..4], &times[1..]);
+        assert!((ends[4] - track.duration_seconds()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn groove_shifts_each_step_by_its_offset() {
+        let notes = "note: C4, 0.25, 1.0\n".repeat(8);
+        // One beat per second, so offsets in beats read as seconds
+        let track = mel(&format!("tempo: 60\ngroove: 0:1, 0.1:0.5, 0:1, -0.05:1\n{}", notes));
+        let expected = [0.0, 0.35, 0.5, 0.7, 1.0, 1.35, 1.5, 1.7];
+        for ((t, element), want) in track.iter_timed().zip(expected) {
+            assert!((t - want).abs() < 1e-5, "onset {} expected {}", t, want);
+            let SequenceElement::Note(note) = element else { unreachable!() };
+            assert_eq!(note.velocity, if want == 0.35 || want == 1.35 { 0.5 } else { 1.0 });
+        }
+        assert!((track.duration_seconds() - 2.0).abs() < 1e-5);
+
+        // Every second 16th of the MPC swing lands 0.06 beats late
+        let swung = mel(&format!("tempo: 60\ngroove: mpc-swing-62\n{}", notes));
+        let second = swung.iter_timed().nth(1).unwrap().0;
+        assert!((second - 0.31).abs() < 1e-5);
+    }
+
+    #[test]
+    fn adsr_sets_all_four_and_release_overrides() {
+        let instr = mel("adsr: 0.01, 0.1, 0.8, 0.2\nnote: C4, 1, 1.0").instrument;
+        assert_eq!((instr.attack, instr.decay, instr.sustain, instr.release), (0.01, 0.1, 0.8, 0.2));
+        let instr = mel("adsr: 0.01, 0.1, 0.8, 0.2\nrelease: 0.5\nnote: C4, 1, 1.0").instrument;
+        assert_eq!((instr.attack, instr.decay, instr.sustain, instr.release), (0.01, 0.1, 0.8, 0.5));
+    }
+
+    #[test]
+    fn tuning_anywhere_in_the_file_retunes_notes_chords_and_slides() {
+        let track = mel("note: A4, 1, 1.0, slide=A5\nchord: A3+A4, 1, 1.0\ntuning: 415");
+        let pitches: Vec<f32> = track.sequence.iter()
+            .flat_map(|element| match element {
+                SequenceElement::Note(note) => vec![note.pitch, note.slide_to.unwrap()],
+                SequenceElement::Chord(chord) => chord.pitches.clone(),
+                _ => Vec::new(),
+            })
+            .collect();
+        let expected = [415.0, 830.0, 207.5, 415.0];
+        assert!(pitches.iter().zip(expected).all(|(p, e)| (p - e).abs() < 0.5), "{:?}", pitches);
+    }
+}
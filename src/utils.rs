@@ -2,6 +2,30 @@ use crate::error::SynthError;
 
 // TODO: Use standard MIDI formula
 
+// Concert pitch the note names are tuned to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuningConfig {
+    pub a4_hz: f32,
+}
+
+impl Default for TuningConfig {
+    fn default() -> Self {
+        TuningConfig { a4_hz: 440.0 }
+    }
+}
+
+impl TuningConfig {
+    // Factor from the A4 = 440Hz note table to this tuning
+    pub fn scale(&self) -> f32 {
+        self.a4_hz / 440.0
+    }
+}
+
+// parse_note, with every frequency moved to `tuning`'s A4
+pub fn parse_note_with_tuning(note_str: &str, tuning: &TuningConfig) -> Result<f32, SynthError> {
+    Ok(parse_note(note_str)? * tuning.scale())
+}
+
 pub fn parse_note(note_str: &str) -> Result<f32, SynthError> {
     let note_str = note_str.to_uppercase();
     let mut freq = match note_str.chars().next() {
@@ -15,26 +39,102 @@ pub fn parse_note(note_str: &str) -> Result<f32, SynthError> {
         _ => return Err(SynthError::ParseError("Invalid note".to_string())),
     };
 
-    let second_char = note_str.chars().nth(1);
-    let has_accidental = matches!(second_char, Some('#') | Some('S') | Some('B') | Some('F'));
-    
-    if has_accidental {
-        match second_char {
-            Some('#') | Some('S') => freq *= 1.059463,
-            Some('B') | Some('F') => freq *= 0.943874,
-            _ => {}
-        }
-    }
+    // One accidental (# / s / ♯ sharp, b / ♭ flat), a doubled one (## / bb) or an explicit natural (n).
+    // Only those symbols count, so no note letter after the first is ever read as an accidental.
+    let rest = &note_str[1..];
+    let step = |c: char| match c {
+        '#' | 'S' | '♯' => Some(1),
+        'B' | '♭' => Some(-1),
+        _ => None,
+    };
+    let steps: Vec<i32> = rest.chars().map_while(step).take(2).collect();
+    let (semitones, symbols) = match steps.as_slice() {
+        [a, b] if a == b => (a + b, 2),
+        [a, ..] => (*a, 1),
+        [] if rest.starts_with('N') => (0, 1),
+        [] => (0, 0),
+    };
+    freq *= 2.0_f32.powf(semitones as f32 / 12.0);
 
-    let octave_start = if has_accidental { 2 } else { 1 };
-    let octave_str = note_str[octave_start..].trim();
+    let octave_start: usize = rest.chars().take(symbols).map(char::len_utf8).sum();
+    let octave_str = rest[octave_start..].trim();
 
-    if !octave_str.is_empty() {
-        if let Ok(octave) = octave_str.parse::<i32>() {
-            freq *= 2.0_f32.powi(octave);
-        }
+    if !octave_str.is_empty() && let Ok(octave) = octave_str.parse::<i32>() {
+        freq *= 2.0_f32.powi(octave);
     }
 
     Ok(freq)
 
 }
+
+// Nearest MIDI note number for a frequency (A4 = 440Hz = 69), clamped to 0-127
+pub fn freq_to_midi(freq: f32) -> u8 {
+    if freq <= 0.0 { return 0; }
+    (69.0 + 12.0 * (freq / 440.0).log2()).round().clamp(0.0, 127.0) as u8
+}
+
+// tanh saturation that never exceeds `ceiling`, roughly linear well below it.
+#[inline]
+pub fn soft_clip(sample: f32, ceiling: f32) -> f32 {
+    if ceiling <= 0.0 { return 0.0; }
+    ceiling * (sample / ceiling).tanh()
+}
+
+// Mid/side width on a stereo pair: 0 collapses to mono, 1 leaves it alone, >1 exaggerates the sides.
+#[inline]
+pub fn stereo_width(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let mid = (left + right) * 0.5;
+    let side = (left - right) * 0.5 * width;
+    (mid + side, mid - side)
+}
+
+// Equal-power pan law (cos/sin of the pan angle), scaled by sqrt(2) so a centred voice keeps its mono level in
+// both channels and the (left + right) / 2 fold-down of a centred mix is unchanged.
+#[inline]
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    if pan == 0.0 { return (1.0, 1.0); }
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    let (right, left) = angle.sin_cos();
+    (left * std::f32::consts::SQRT_2, right * std::f32::consts::SQRT_2)
+}
+
+// Balance for stereo sources: panning towards one side folds the other channel in (equal power) instead of dropping it.
+#[inline]
+pub fn stereo_balance(left: f32, right: f32, pan: f32) -> (f32, f32) {
+    let amount = pan.clamp(-1.0, 1.0).abs() * std::f32::consts::FRAC_PI_2;
+    let (fold, keep) = amount.sin_cos();
+    if pan < 0.0 {
+        (left + right * fold, right * keep)
+    } else {
+        (left * keep, right + left * fold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The letter table is rounded to the cent, so enharmonic spellings agree to a fraction of a hertz
+    fn same_pitch(a: &str, b: &str) -> bool {
+        (parse_note(a).unwrap() / parse_note(b).unwrap()).log2().abs() * 1200.0 < 1.0
+    }
+
+    #[test]
+    fn double_accidentals_move_two_semitones() {
+        assert!(same_pitch("C##4", "D4"));
+        assert!(same_pitch("Dbb4", "C4"));
+        assert!(same_pitch("Cn4", "C4"));
+        assert!(same_pitch("C#4", "Db4"));
+        assert!(!same_pitch("C##4", "C#4"));
+    }
+
+    #[test]
+    fn letters_after_the_first_are_never_accidentals() {
+        assert!((parse_note("B4").unwrap() - 493.92).abs() < 0.01);
+        assert!((parse_note("F4").unwrap() - 349.28).abs() < 0.01);
+        assert!(same_pitch("Bb4", "A#4"));
+        assert!(same_pitch("Fb4", "E4"));
+        assert!(same_pitch("E#3", "F3"));
+        assert!(parse_note("Bb4").unwrap() < parse_note("B4").unwrap());
+    }
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::error::SynthError;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WaveformType {
     Sine,
@@ -5,6 +9,38 @@ pub enum WaveformType {
     Triangle,
     Sawtooth,
     Noise,
+    Pulse(f32), // Duty cycle in (0, 1), 0.5 sounds the same as Square
+    PinkNoise,  // -3 dB/octave, needs a NoiseState per voice
+    BrownNoise, // -6 dB/octave, needs a NoiseState per voice
+}
+
+// Filter memory for the coloured noises, one per voice
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoiseState {
+    pink: [f32; 7],
+    brown: f32,
+}
+
+impl NoiseState {
+    // Paul Kellet's refined pink filter over white noise in [-1, 1]
+    pub fn pink(&mut self, white: f32) -> f32 {
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let out = b[..6].iter().sum::<f32>() + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        (out * 0.15).clamp(-1.0, 1.0)
+    }
+
+    // Leaky integrator of white noise, the leak keeps it from wandering off to a rail
+    pub fn brown(&mut self, white: f32) -> f32 {
+        self.brown = (self.brown * 0.997 + white * 0.05).clamp(-1.0, 1.0);
+        self.brown
+    }
 }
 
 impl WaveformType {
@@ -12,8 +48,10 @@ impl WaveformType {
         match self {
             WaveformType::Sine => (phase * std::f32::consts::TAU).sin(),
             WaveformType::Square => if (phase * 2.0) % 1.0 < 0.5 { 1.0 } else { -1.0 },
+            WaveformType::Pulse(duty) => if (phase * 2.0) % 1.0 < *duty { 1.0 } else { -1.0 },
             WaveformType::Sawtooth => (phase * 2.0) % 1.0 * 2.0 - 1.0,
-            WaveformType::Noise => fastrand::f32() * 2.0 - 1.0, // WaveformType::Noise => (((phase * 1235.647).sin() * 43758.5453).fract() * 2.0 - 1.0), <- possible replacement
+            // Without a NoiseState the coloured noises come out white
+            WaveformType::Noise | WaveformType::PinkNoise | WaveformType::BrownNoise => fastrand::f32() * 2.0 - 1.0, // WaveformType::Noise => (((phase * 1235.647).sin() * 43758.5453).fract() * 2.0 - 1.0), <- possible replacement
             WaveformType::Triangle => {
                 let p = (phase * 2.0) % 1.0;
                 if p < 0.5 { p * 4.0 - 1.0 } else { 3.0 - p * 4.0 }
@@ -21,6 +59,67 @@ impl WaveformType {
         }
     }
 
+    // Same, drawing noise from `rng` so seeded renders come out the same every time
+    pub fn generate_sample_with(&self, phase: f32, rng: &mut fastrand::Rng) -> f32 {
+        if self.is_noise() { rng.f32() * 2.0 - 1.0 } else { self.generate_sample(phase) }
+    }
+
+    // Band-limited square/pulse/sawtooth: PolyBLEP rounds off each jump over the samples around it, which needs the
+    // per-sample phase increment. The other waveforms have no jumps and come out as generate_sample.
+    pub fn generate_sample_blep(&self, phase: f32, phase_inc: f32) -> f32 {
+        // Square, pulse and sawtooth run at twice the phase rate, see generate_sample
+        let (p, dt) = ((phase * 2.0) % 1.0, (phase_inc * 2.0).clamp(0.0, 0.5));
+        match self {
+            WaveformType::Square => {
+                let naive = if p < 0.5 { 1.0 } else { -1.0 };
+                naive + poly_blep(p, dt) - poly_blep((p + 0.5) % 1.0, dt)
+            }
+            WaveformType::Pulse(duty) => {
+                let naive = if p < *duty { 1.0 } else { -1.0 };
+                naive + poly_blep(p, dt) - poly_blep((p + 1.0 - duty) % 1.0, dt)
+            }
+            WaveformType::Sawtooth => p * 2.0 - 1.0 - poly_blep(p, dt),
+            _ => self.generate_sample(phase),
+        }
+    }
+
+    // generate_sample_blep, drawing noise from `rng` like generate_sample_with
+    pub fn generate_sample_blep_with(&self, phase: f32, phase_inc: f32, rng: &mut fastrand::Rng) -> f32 {
+        if self.is_noise() { rng.f32() * 2.0 - 1.0 } else { self.generate_sample_blep(phase, phase_inc) }
+    }
+
+    // One voice's next sample: generate_sample_blep_with, with pink and brown noise filtered through `state`
+    pub fn generate_sample_stateful(&self, phase: f32, phase_inc: f32, rng: &mut fastrand::Rng, state: &mut NoiseState) -> f32 {
+        match self {
+            WaveformType::PinkNoise => state.pink(rng.f32() * 2.0 - 1.0),
+            WaveformType::BrownNoise => state.brown(rng.f32() * 2.0 - 1.0),
+            _ => self.generate_sample_blep_with(phase, phase_inc, rng),
+        }
+    }
+
+    pub fn is_noise(&self) -> bool {
+        matches!(self, WaveformType::Noise | WaveformType::PinkNoise | WaveformType::BrownNoise)
+    }
+
+    // Pink and brown noise carry filter state from one sample to the next
+    pub fn is_coloured_noise(&self) -> bool {
+        matches!(self, WaveformType::PinkNoise | WaveformType::BrownNoise)
+    }
+
+    // Name as written in .mel files, without the pulse duty cycle
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WaveformType::Sine => "sine",
+            WaveformType::Square => "square",
+            WaveformType::Triangle => "triangle",
+            WaveformType::Sawtooth => "sawtooth",
+            WaveformType::Noise => "noise",
+            WaveformType::Pulse(_) => "pulse",
+            WaveformType::PinkNoise => "pink",
+            WaveformType::BrownNoise => "brown",
+        }
+    }
+
     // Stable ID written into GpuNoteData.waveform_type.
     // Only defined for waveforms that have a GPU path.
     #[cfg(feature = "gpu")]
@@ -31,6 +130,8 @@ impl WaveformType {
             WaveformType::Triangle => Some(2),
             WaveformType::Sawtooth => Some(3),
             WaveformType::Noise    => None,
+            WaveformType::Pulse(_) => None, // Duty cycle would need its own field in GpuNoteData
+            WaveformType::PinkNoise | WaveformType::BrownNoise => None,
         }
     }
 
@@ -51,11 +152,93 @@ impl WaveformType {
                  return select(3.0 - p * 4.0, p * 4.0 - 1.0, p < 0.5);".to_string(),
             WaveformType::Sawtooth =>
                 "return fract(phase * 2.0) * 2.0 - 1.0;".to_string(),
-            WaveformType::Noise => return None,
+            WaveformType::Noise | WaveformType::Pulse(_) | WaveformType::PinkNoise | WaveformType::BrownNoise => return None,
         };
         Some(format!("case {id}u: {{ {body} }}"))
     }
 }
 
+// Correction for a unit downward step at t = 0 of a [0, 1) ramp advancing dt per sample
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if dt <= 0.0 {
+        0.0
+    } else if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Case-insensitive, surrounding whitespace ignored. Pulse takes its duty cycle after the name: `pulse 0.25`
+impl FromStr for WaveformType {
+    type Err = SynthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        if let Some(duty) = lower.strip_prefix("pulse") {
+            let duty = match duty.trim() {
+                "" => 0.5,
+                d => d.parse().map_err(|_| SynthError::ParseError(format!("Invalid pulse duty cycle '{}'", d)))?,
+            };
+            if !(duty > 0.0 && duty < 1.0) {
+                return Err(SynthError::ParseError(format!("Pulse duty cycle must be between 0 and 1, got {}", duty)));
+            }
+            return Ok(WaveformType::Pulse(duty));
+        }
+        match lower.as_str() {
+            "sine" => Ok(WaveformType::Sine),
+            "square" => Ok(WaveformType::Square),
+            "triangle" => Ok(WaveformType::Triangle),
+            "sawtooth" => Ok(WaveformType::Sawtooth),
+            "noise" => Ok(WaveformType::Noise),
+            "pink" => Ok(WaveformType::PinkNoise),
+            "brown" => Ok(WaveformType::BrownNoise),
+            _ => Err(SynthError::ParseError(format!("Unknown Waveform '{}'", s.trim()))),
+        }
+    }
+}
+
+impl fmt::Display for WaveformType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaveformType::Pulse(duty) => write!(f, "pulse {}", duty),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn parses_waveform_names() {
+        assert_eq!(" Sine ".parse::<WaveformType>().unwrap(), WaveformType::Sine);
+        assert_eq!("SAWTOOTH".parse::<WaveformType>().unwrap(), WaveformType::Sawtooth);
+        assert_eq!("pink".parse::<WaveformType>().unwrap(), WaveformType::PinkNoise);
+        assert_eq!("pulse".parse::<WaveformType>().unwrap(), WaveformType::Pulse(0.5));
+        assert_eq!("pulse 0.25".parse::<WaveformType>().unwrap(), WaveformType::Pulse(0.25));
+    }
 
+    #[test]
+    fn rejects_unknown_waveforms() {
+        for bad in ["", "sinewave", "pulse 1.5", "pulse 0", "pulse wide"] {
+            assert!(matches!(bad.parse::<WaveformType>(), Err(SynthError::ParseError(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn display_round_trips_through_from_str() {
+        let all = [
+            WaveformType::Sine, WaveformType::Square, WaveformType::Triangle, WaveformType::Sawtooth,
+            WaveformType::Noise, WaveformType::Pulse(0.1), WaveformType::PinkNoise, WaveformType::BrownNoise,
+        ];
+        for waveform in all {
+            assert_eq!(waveform.to_string().parse::<WaveformType>().unwrap(), waveform);
+        }
+    }
+}